@@ -1,4 +1,6 @@
-use std::str::from_utf8;
+use std::{borrow::Cow, str::from_utf8};
+
+use anyhow::{bail, ensure, Context, Result};
 
 use crate::bytes_reader::BytesReader;
 
@@ -12,102 +14,141 @@ impl<'a> Decoder<'a> {
         Self { reader }
     }
 
-    pub fn is_string(&self) -> bool {
-        self.reader.peek().is_ascii_digit()
+    pub fn is_string(&self) -> Result<bool> {
+        Ok(self.reader.peek()?.is_ascii_digit())
     }
 
-    pub fn is_integer(&self) -> bool {
-        self.reader.peek() == b'i'
+    pub fn is_integer(&self) -> Result<bool> {
+        Ok(self.reader.peek()? == b'i')
     }
 
-    pub fn is_list(&self) -> bool {
-        self.reader.peek() == b'l'
+    pub fn is_list(&self) -> Result<bool> {
+        Ok(self.reader.peek()? == b'l')
     }
 
-    pub fn is_dict(&self) -> bool {
-        self.reader.peek() == b'd'
+    pub fn is_dict(&self) -> Result<bool> {
+        Ok(self.reader.peek()? == b'd')
     }
 
-    pub fn read_string_bytes(&mut self) -> &'a [u8] {
-        let len = from_utf8(self.reader.read_until(b':'))
-            .unwrap()
+    pub fn read_string_bytes(&mut self) -> Result<&'a [u8]> {
+        ensure!(self.is_string()?, "not a string");
+        let len = from_utf8(self.reader.read_until(b':')?)?
             .parse::<usize>()
-            .unwrap();
-        self.reader.skip();
+            .context("invalid string length")?;
+        self.reader.skip()?;
         self.reader.read_n(len)
     }
 
-    pub fn read_string(&mut self) -> &'a str {
-        from_utf8(self.read_string_bytes()).unwrap()
+    pub fn read_string(&mut self) -> Result<&'a str> {
+        from_utf8(self.read_string_bytes()?).context("string isn't UTF-8")
+    }
+
+    // For names and comments, which aren't always UTF-8.
+    pub fn read_string_lossy(&mut self) -> Result<Cow<'a, str>> {
+        Ok(String::from_utf8_lossy(self.read_string_bytes()?))
     }
 
-    pub fn read_integer_bytes(&mut self) -> &'a [u8] {
-        self.reader.skip();
-        let integer = self.reader.read_until(b'e');
-        self.reader.skip();
-        integer
+    pub fn read_integer_bytes(&mut self) -> Result<&'a [u8]> {
+        ensure!(self.is_integer()?, "not an integer");
+        self.reader.skip()?;
+        let integer = self.reader.read_until(b'e')?;
+        self.reader.skip()?;
+        Ok(integer)
     }
 
-    pub fn read_integer(&mut self) -> i64 {
-        from_utf8(self.read_integer_bytes())
-            .unwrap()
+    pub fn read_integer(&mut self) -> Result<i64> {
+        from_utf8(self.read_integer_bytes()?)?
             .parse::<i64>()
-            .unwrap()
+            .context("invalid integer")
+    }
+
+    pub fn start_dict(&mut self) -> Result<usize> {
+        ensure!(self.is_dict()?, "not a dict");
+        self.reader.skip()?;
+        Ok(self.reader.get_pos() - 1)
+    }
+
+    pub fn start_list(&mut self) -> Result<()> {
+        ensure!(self.is_list()?, "not a list");
+        self.reader.skip()
     }
 
-    pub fn start_dict(&mut self) -> usize {
-        if !self.is_dict() {
-            panic!("not a dict")
+    // Whether the current list or dict has no items left.
+    pub fn is_end(&self) -> Result<bool> {
+        Ok(self.reader.peek()? == b'e')
+    }
+
+    pub fn finish_list(&mut self) -> Result<()> {
+        while !self.is_end()? {
+            self.parse()?;
         }
-        self.reader.skip();
-        self.reader.get_pos() - 1
+        self.reader.skip()
     }
 
-    pub fn find_key(&mut self, needle: &str) {
-        while self.reader.peek() != b'e' {
-            let key = self.read_string();
-            if key == needle {
-                return;
+    pub fn find_key(&mut self, needle: &str) -> Result<()> {
+        while !self.is_end()? {
+            let key = self.read_string_bytes()?;
+            if key == needle.as_bytes() {
+                return Ok(());
             }
-            self.parse();
+            self.parse()?;
         }
-        panic!("{} not found", needle);
+        bail!("{} not found", needle);
     }
 
-    pub fn finish_dict(&mut self, start: usize) -> &'a [u8] {
-        while self.reader.peek() != b'e' {
-            self.parse();
-            self.parse();
+    // Keys are sorted, so once a later key shows up, the needle is missing.
+    // That key is left for the next lookup.
+    pub fn find_optional_key(&mut self, needle: &str) -> Result<bool> {
+        while !self.is_end()? {
+            let pos = self.reader.get_pos();
+            let key = self.read_string_bytes()?;
+            if key == needle.as_bytes() {
+                return Ok(true);
+            }
+            if key > needle.as_bytes() {
+                self.reader.set_pos(pos);
+                return Ok(false);
+            }
+            self.parse()?;
         }
-        self.reader.skip();
-        self.reader.get_from(start)
+        Ok(false)
     }
 
-    fn parse(&mut self) {
+    pub fn finish_dict(&mut self, start: usize) -> Result<&'a [u8]> {
+        while !self.is_end()? {
+            self.parse()?;
+            self.parse()?;
+        }
+        self.reader.skip()?;
+        Ok(self.reader.get_from(start))
+    }
+
+    fn parse(&mut self) -> Result<()> {
         if self.reader.is_at_end() {
-            return;
+            return Ok(());
         }
 
-        if self.is_string() {
-            self.read_string_bytes();
-        } else if self.is_integer() {
-            self.read_integer_bytes();
-        } else if self.is_list() {
-            self.reader.skip();
-            while self.reader.peek() != b'e' {
-                self.parse();
+        if self.is_string()? {
+            self.read_string_bytes()?;
+        } else if self.is_integer()? {
+            self.read_integer_bytes()?;
+        } else if self.is_list()? {
+            self.reader.skip()?;
+            while !self.is_end()? {
+                self.parse()?;
             }
-            self.reader.skip();
-        } else if self.is_dict() {
-            self.reader.skip();
-            while self.reader.peek() != b'e' {
-                self.parse();
-                self.parse();
+            self.reader.skip()?;
+        } else if self.is_dict()? {
+            self.reader.skip()?;
+            while !self.is_end()? {
+                self.parse()?;
+                self.parse()?;
             }
-            self.reader.skip();
+            self.reader.skip()?;
         } else {
-            panic!("invalid encoding")
+            bail!("invalid encoding")
         }
+        Ok(())
     }
 }
 
@@ -142,25 +183,25 @@ mod tests {
         let mut decoder = Decoder::new(bytes_reader);
 
         // outer -->
-        let root_start = decoder.start_dict();
-        decoder.find_key("a");
-        assert_eq!(decoder.read_integer(), 1);
-        decoder.find_key("b");
+        let root_start = decoder.start_dict().unwrap();
+        decoder.find_key("a").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 1);
+        decoder.find_key("b").unwrap();
 
         // middle -->
-        let b_start = decoder.start_dict();
-        decoder.find_key("c");
-        assert_eq!(decoder.read_integer(), 2);
-        decoder.find_key("d");
+        let b_start = decoder.start_dict().unwrap();
+        decoder.find_key("c").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 2);
+        decoder.find_key("d").unwrap();
 
         // inner -->
-        let d_start = decoder.start_dict();
-        decoder.find_key("e");
-        assert_eq!(decoder.read_integer(), 3);
-        decoder.find_key("f");
-        assert_eq!(decoder.read_integer(), 4);
+        let d_start = decoder.start_dict().unwrap();
+        decoder.find_key("e").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 3);
+        decoder.find_key("f").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 4);
 
-        let d = decoder.finish_dict(d_start);
+        let d = decoder.finish_dict(d_start).unwrap();
         let d_want = json!({
             "e": 3,
             "f": 4
@@ -168,10 +209,10 @@ mod tests {
         assert_eq!(d, serde_bencode::to_bytes(&d_want).unwrap());
         // <-- inner
 
-        decoder.find_key("g");
-        assert_eq!(decoder.read_integer(), 5);
+        decoder.find_key("g").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 5);
 
-        let b = decoder.finish_dict(b_start);
+        let b = decoder.finish_dict(b_start).unwrap();
         let b_want = json!({
             "c": 2,
             "d": {
@@ -183,14 +224,35 @@ mod tests {
         assert_eq!(b, serde_bencode::to_bytes(&b_want).unwrap());
         // <-- middle
 
-        decoder.find_key("h");
-        assert_eq!(decoder.read_integer(), 6);
+        decoder.find_key("h").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 6);
 
-        let root = decoder.finish_dict(root_start);
+        let root = decoder.finish_dict(root_start).unwrap();
         assert_eq!(root, encoded.as_bytes());
         // <-- outer
     }
 
+    #[test]
+    fn test_decoder_optional_key() {
+        let value = get_test_value();
+        let encoded = serde_bencode::to_string(&value).unwrap();
+
+        let bytes_reader = BytesReader::new(encoded.as_bytes());
+        let mut decoder = Decoder::new(bytes_reader);
+
+        let root_start = decoder.start_dict().unwrap();
+        assert!(!decoder.find_optional_key("0").unwrap());
+        assert!(decoder.find_optional_key("a").unwrap());
+        assert_eq!(decoder.read_integer().unwrap(), 1);
+        assert!(!decoder.find_optional_key("c").unwrap());
+        assert!(decoder.find_optional_key("h").unwrap());
+        assert_eq!(decoder.read_integer().unwrap(), 6);
+        assert!(!decoder.find_optional_key("z").unwrap());
+
+        let root = decoder.finish_dict(root_start).unwrap();
+        assert_eq!(root, encoded.as_bytes());
+    }
+
     #[test]
     fn test_decoder_start_end() {
         let value = get_test_value();
@@ -200,17 +262,17 @@ mod tests {
         let mut decoder = Decoder::new(bytes_reader);
 
         // outer -->
-        let root_start = decoder.start_dict();
-        decoder.find_key("b");
+        let root_start = decoder.start_dict().unwrap();
+        decoder.find_key("b").unwrap();
 
         // middle -->
-        let b_start = decoder.start_dict();
-        decoder.find_key("d");
+        let b_start = decoder.start_dict().unwrap();
+        decoder.find_key("d").unwrap();
 
         // inner -->
-        let d_start = decoder.start_dict();
+        let d_start = decoder.start_dict().unwrap();
 
-        let d = decoder.finish_dict(d_start);
+        let d = decoder.finish_dict(d_start).unwrap();
         let d_want = json!({
             "e": 3,
             "f": 4
@@ -218,7 +280,7 @@ mod tests {
         assert_eq!(d, serde_bencode::to_bytes(&d_want).unwrap());
         // <-- inner
 
-        let b = decoder.finish_dict(b_start);
+        let b = decoder.finish_dict(b_start).unwrap();
         let b_want = json!({
             "c": 2,
             "d": {
@@ -230,7 +292,7 @@ mod tests {
         assert_eq!(b, serde_bencode::to_bytes(&b_want).unwrap());
         // <-- middle
 
-        let root = decoder.finish_dict(root_start);
+        let root = decoder.finish_dict(root_start).unwrap();
         assert_eq!(root, encoded.as_bytes());
         // <-- outer
     }
@@ -244,19 +306,19 @@ mod tests {
         let mut decoder = Decoder::new(bytes_reader);
 
         // outer -->
-        let root_start = decoder.start_dict();
-        decoder.find_key("a");
-        assert_eq!(decoder.read_integer(), 1);
-        decoder.find_key("b");
+        let root_start = decoder.start_dict().unwrap();
+        decoder.find_key("a").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 1);
+        decoder.find_key("b").unwrap();
 
         // middle -->
-        let b_start = decoder.start_dict();
-        decoder.find_key("c");
-        assert_eq!(decoder.read_integer(), 2);
-        decoder.find_key("g");
-        assert_eq!(decoder.read_integer(), 5);
+        let b_start = decoder.start_dict().unwrap();
+        decoder.find_key("c").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 2);
+        decoder.find_key("g").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 5);
 
-        let b = decoder.finish_dict(b_start);
+        let b = decoder.finish_dict(b_start).unwrap();
         let b_want = json!({
             "c": 2,
             "d": {
@@ -268,10 +330,10 @@ mod tests {
         assert_eq!(b, serde_bencode::to_bytes(&b_want).unwrap());
         // <-- middle
 
-        decoder.find_key("h");
-        assert_eq!(decoder.read_integer(), 6);
+        decoder.find_key("h").unwrap();
+        assert_eq!(decoder.read_integer().unwrap(), 6);
 
-        let root = decoder.finish_dict(root_start);
+        let root = decoder.finish_dict(root_start).unwrap();
         assert_eq!(root, encoded.as_bytes());
         // <-- outer
     }
@@ -1,5 +1,7 @@
 mod decoder;
 mod to_json;
+mod value;
 
 pub use decoder::Decoder;
 pub use to_json::to_json;
+pub use value::Value;
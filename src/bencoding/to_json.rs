@@ -1,63 +1,66 @@
 use std::str::from_utf8;
 
+use anyhow::{bail, Result};
+
 use crate::bytes_reader::BytesReader;
 
 use super::Decoder;
 
-pub fn to_json(bencoded_value: &[u8]) -> String {
+pub fn to_json(bencoded_value: &[u8]) -> Result<String> {
     let mut decoder = Decoder::new(BytesReader::new(bencoded_value));
     let mut json = String::with_capacity(decoder.reader.len());
-    decode(&mut decoder, &mut json);
+    decode(&mut decoder, &mut json)?;
     json.shrink_to_fit();
-    json
+    Ok(json)
 }
 
-fn decode(decoder: &mut Decoder, json: &mut String) {
+fn decode(decoder: &mut Decoder, json: &mut String) -> Result<()> {
     if decoder.reader.is_at_end() {
-        return;
+        return Ok(());
     }
 
-    if decoder.is_string() {
-        json.push_str("\"");
-        let bytes = decoder.read_string_bytes();
+    if decoder.is_string()? {
+        json.push('"');
+        let bytes = decoder.read_string_bytes()?;
         match from_utf8(bytes) {
             Ok(string) => json.push_str(string),
             Err(_) => json.push_str(&hex::encode(bytes)),
         }
-        json.push_str("\"");
-    } else if decoder.is_integer() {
-        json.push_str(&decoder.read_integer().to_string());
-    } else if decoder.is_list() {
-        decoder.reader.skip();
-        json.push_str("[");
-        if decoder.reader.peek() != b'e' {
-            decode(decoder, json);
+        json.push('"');
+    } else if decoder.is_integer()? {
+        json.push_str(&decoder.read_integer()?.to_string());
+    } else if decoder.is_list()? {
+        decoder.reader.skip()?;
+        json.push('[');
+        if !decoder.is_end()? {
+            decode(decoder, json)?;
         }
-        while decoder.reader.peek() != b'e' {
-            json.push_str(",");
-            decode(decoder, json);
+        while !decoder.is_end()? {
+            json.push(',');
+            decode(decoder, json)?;
         }
-        decoder.reader.skip();
-        json.push_str("]");
-    } else if decoder.is_dict() {
-        decoder.reader.skip();
-        json.push_str("{");
-        if decoder.reader.peek() != b'e' {
-            decode(decoder, json);
-            json.push_str(":");
-            decode(decoder, json);
+        decoder.reader.skip()?;
+        json.push(']');
+    } else if decoder.is_dict()? {
+        decoder.reader.skip()?;
+        json.push('{');
+        if !decoder.is_end()? {
+            decode(decoder, json)?;
+            json.push(':');
+            decode(decoder, json)?;
         }
-        while decoder.reader.peek() != b'e' {
-            json.push_str(",");
-            decode(decoder, json);
-            json.push_str(":");
-            decode(decoder, json);
+        while !decoder.is_end()? {
+            json.push(',');
+            decode(decoder, json)?;
+            json.push(':');
+            decode(decoder, json)?;
         }
-        decoder.reader.skip();
-        json.push_str("}");
+        decoder.reader.skip()?;
+        json.push('}');
     } else {
-        panic!("invalid encoding")
+        bail!("invalid encoding")
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -70,7 +73,7 @@ mod tests {
 
     fn make_round_trip(json: &Value) -> String {
         let bencoded = serde_bencode::to_string(json).unwrap();
-        to_json(bencoded.as_bytes())
+        to_json(bencoded.as_bytes()).unwrap()
     }
 
     #[test]
@@ -121,6 +124,13 @@ mod tests {
     fn test_to_json_sample_torrent() {
         let metainfo_path = "sample.torrent";
         let bytes = fs::read(metainfo_path).unwrap();
-        to_json(&bytes);
+        to_json(&bytes).unwrap();
+    }
+
+    #[test]
+    fn test_to_json_malformed() {
+        for bencoded in [&b"5:abc"[..], b"i12", b"l1:a", b"d1:ai1e", b"x"] {
+            assert!(to_json(bencoded).is_err());
+        }
     }
 }
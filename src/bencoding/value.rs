@@ -0,0 +1,257 @@
+use std::{collections::BTreeMap, str::from_utf8};
+
+use anyhow::{bail, ensure, Context, Result};
+
+const MAX_DEPTH: usize = 64;
+
+// An owned bencode value, for messages from the wire. `Decoder` instead
+// walks metainfo in place, borrowing its strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    String(Vec<u8>),
+    List(Vec<Value>),
+    // keys stay sorted, which keeps the encoding canonical
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Value)>) -> Self {
+        Self::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let (value, len) = Self::decode_prefix(bytes)?;
+        ensure!(len == bytes.len(), "trailing bytes after bencoded value");
+        Ok(value)
+    }
+
+    // Decodes the value at the start of `bytes` and returns how many bytes
+    // it took, for messages that carry raw data after the bencoded part.
+    pub fn decode_prefix(bytes: &[u8]) -> Result<(Self, usize)> {
+        let mut pos = 0;
+        let value = parse(bytes, &mut pos, 0)?;
+        Ok((value, pos))
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        self.encode_into(&mut bytes);
+        bytes
+    }
+
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        match self {
+            Self::Integer(integer) => bytes.extend(format!("i{}e", integer).as_bytes()),
+            Self::String(string) => encode_string(string, bytes),
+            Self::List(list) => {
+                bytes.push(b'l');
+                for value in list {
+                    value.encode_into(bytes);
+                }
+                bytes.push(b'e');
+            }
+            Self::Dict(dict) => {
+                bytes.push(b'd');
+                for (key, value) in dict {
+                    encode_string(key, bytes);
+                    value.encode_into(bytes);
+                }
+                bytes.push(b'e');
+            }
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Self::Integer(integer) => Some(*integer),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Self::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        from_utf8(self.as_bytes()?).ok()
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Self::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Self::Dict(dict) => Some(dict),
+            _ => None,
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(integer: i64) -> Self {
+        Self::Integer(integer)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(string: &str) -> Self {
+        Self::String(string.as_bytes().to_vec())
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(bytes: &[u8]) -> Self {
+        Self::String(bytes.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self::String(bytes)
+    }
+}
+
+fn encode_string(string: &[u8], bytes: &mut Vec<u8>) {
+    bytes.extend(format!("{}:", string.len()).as_bytes());
+    bytes.extend(string);
+}
+
+fn parse(bytes: &[u8], pos: &mut usize, depth: usize) -> Result<Value> {
+    ensure!(depth < MAX_DEPTH, "bencoded value nested too deeply");
+    let Some(first) = bytes.get(*pos) else {
+        bail!("unexpected end of bencoded value");
+    };
+    let value = match first {
+        b'i' => {
+            *pos += 1;
+            let digits = read_until(bytes, pos, b'e')?;
+            let integer = from_utf8(digits)?
+                .parse()
+                .with_context(|| format!("invalid integer at {}", *pos))?;
+            Value::Integer(integer)
+        }
+        b'0'..=b'9' => Value::String(parse_string(bytes, pos)?),
+        b'l' => {
+            *pos += 1;
+            let mut list = vec![];
+            while !at_end_marker(bytes, *pos)? {
+                list.push(parse(bytes, pos, depth + 1)?);
+            }
+            *pos += 1;
+            Value::List(list)
+        }
+        b'd' => {
+            *pos += 1;
+            let mut dict = BTreeMap::new();
+            while !at_end_marker(bytes, *pos)? {
+                let key = parse_string(bytes, pos)?;
+                let value = parse(bytes, pos, depth + 1)?;
+                dict.insert(key, value);
+            }
+            *pos += 1;
+            Value::Dict(dict)
+        }
+        _ => bail!("invalid bencoded value at {}", *pos),
+    };
+    Ok(value)
+}
+
+fn parse_string(bytes: &[u8], pos: &mut usize) -> Result<Vec<u8>> {
+    let digits = read_until(bytes, pos, b':')?;
+    let len: usize = from_utf8(digits)?
+        .parse()
+        .with_context(|| format!("invalid string length at {}", *pos))?;
+    let end = pos.checked_add(len).context("string too long")?;
+    ensure!(end <= bytes.len(), "unexpected end of bencoded string");
+    let string = bytes[*pos..end].to_vec();
+    *pos = end;
+    Ok(string)
+}
+
+// Reads up to the delimiter and skips it.
+fn read_until<'a>(bytes: &'a [u8], pos: &mut usize, delimiter: u8) -> Result<&'a [u8]> {
+    let start = *pos;
+    let len = bytes[start..]
+        .iter()
+        .position(|byte| *byte == delimiter)
+        .context("unexpected end of bencoded value")?;
+    *pos += len + 1;
+    Ok(&bytes[start..start + len])
+}
+
+fn at_end_marker(bytes: &[u8], pos: usize) -> Result<bool> {
+    match bytes.get(pos) {
+        Some(byte) => Ok(*byte == b'e'),
+        None => bail!("unexpected end of bencoded value"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+
+    #[test]
+    fn test_round_trip() {
+        let encoded = b"d1:ai-3e1:bl3:xyzi0ee1:cd1:d0:ee";
+        let value = Value::decode(encoded).unwrap();
+        assert_eq!(value.get("a").unwrap().as_integer(), Some(-3));
+        assert_eq!(
+            value.get("b").unwrap(),
+            &Value::List(vec!["xyz".into(), 0.into()])
+        );
+        assert_eq!(
+            value.get("c").unwrap().get("d").unwrap().as_bytes(),
+            Some(&b""[..])
+        );
+        assert_eq!(value.encode(), encoded);
+    }
+
+    #[test]
+    fn test_canonical_key_order() {
+        let value = Value::dict([("b", 1.into()), ("a", "x".into())]);
+        assert_eq!(value.encode(), b"d1:a1:x1:bi1ee");
+    }
+
+    #[test]
+    fn test_decode_prefix() {
+        let (value, len) = Value::decode_prefix(b"d1:ai1eeRAW").unwrap();
+        assert_eq!(value, Value::dict([("a", 1.into())]));
+        assert_eq!(len, 8);
+        assert!(Value::decode(b"d1:ai1eeRAW").is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        for bytes in [
+            &b""[..],
+            b"i12",
+            b"ixe",
+            b"5:abc",
+            b"l",
+            b"d1:a",
+            b"di1ei2ee",
+            b"x",
+            b"99999999999999999999999:",
+        ] {
+            assert!(Value::decode(bytes).is_err(), "{:?}", bytes);
+        }
+        assert!(Value::decode(&[b'l'; 1000]).is_err());
+    }
+}
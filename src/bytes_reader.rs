@@ -1,3 +1,5 @@
+use anyhow::{Context, Result};
+
 #[derive(Debug)]
 pub struct BytesReader<'a> {
     bytes: &'a [u8],
@@ -21,32 +23,40 @@ impl<'a> BytesReader<'a> {
         self.pos
     }
 
+    pub fn set_pos(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
     pub fn get_from(&self, start: usize) -> &'a [u8] {
         &self.bytes[start..self.pos]
     }
 
-    pub fn peek(&self) -> u8 {
-        self.bytes[self.pos]
+    pub fn peek(&self) -> Result<u8> {
+        self.bytes.get(self.pos).copied().context("unexpected end")
     }
 
-    pub fn skip(&mut self) {
-        self.read();
+    pub fn skip(&mut self) -> Result<()> {
+        self.read()?;
+        Ok(())
     }
 
-    pub fn read(&mut self) -> u8 {
-        self.read_n(1)[0]
+    pub fn read(&mut self) -> Result<u8> {
+        Ok(self.read_n(1)?[0])
     }
 
-    pub fn read_n(&mut self, len: usize) -> &'a [u8] {
-        let start = self.pos;
+    pub fn read_n(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self.bytes[self.pos..]
+            .get(..len)
+            .context("unexpected end")?;
         self.pos += len;
-        &self.bytes[start..self.pos]
+        Ok(bytes)
     }
 
-    pub fn read_until(&mut self, byte: u8) -> &'a [u8] {
-        let start = self.pos;
-        self.pos += self.bytes[start..].iter().position(|x| *x == byte).unwrap();
-        &self.bytes[start..self.pos]
+    pub fn read_until(&mut self, byte: u8) -> Result<&'a [u8]> {
+        let len = (self.bytes[self.pos..].iter())
+            .position(|x| *x == byte)
+            .context("unexpected end")?;
+        self.read_n(len)
     }
 }
 
@@ -58,41 +68,52 @@ mod tests {
     fn test_peek() {
         let arr = [1, 2, 3];
         let reader = BytesReader::new(&arr);
-        assert_eq!(reader.peek(), 1);
-        assert_eq!(reader.peek(), 1);
+        assert_eq!(reader.peek().unwrap(), 1);
+        assert_eq!(reader.peek().unwrap(), 1);
     }
 
     #[test]
     fn test_skip() {
         let arr = [1, 2, 3];
         let mut reader = BytesReader::new(&arr);
-        reader.skip();
-        assert_eq!(reader.peek(), 2);
+        reader.skip().unwrap();
+        assert_eq!(reader.peek().unwrap(), 2);
     }
 
     #[test]
     fn test_read() {
         let arr = [1, 2, 3];
         let mut reader = BytesReader::new(&arr);
-        assert_eq!(reader.read(), 1);
-        assert_eq!(reader.read(), 2);
-        assert_eq!(reader.read(), 3);
+        assert_eq!(reader.read().unwrap(), 1);
+        assert_eq!(reader.read().unwrap(), 2);
+        assert_eq!(reader.read().unwrap(), 3);
     }
 
     #[test]
     fn test_read_range() {
         let arr = [1, 2, 3];
         let mut reader = BytesReader::new(&arr);
-        assert_eq!(reader.read_n(2), [1, 2]);
-        assert_eq!(reader.peek(), 3);
+        assert_eq!(reader.read_n(2).unwrap(), [1, 2]);
+        assert_eq!(reader.peek().unwrap(), 3);
     }
 
     #[test]
     fn test_read_until() {
         let arr = [1, 2, 3, 4];
         let mut reader = BytesReader::new(&arr);
-        reader.skip();
-        assert_eq!(reader.read_until(4), [2, 3]);
-        assert_eq!(reader.peek(), 4);
+        reader.skip().unwrap();
+        assert_eq!(reader.read_until(4).unwrap(), [2, 3]);
+        assert_eq!(reader.peek().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_past_the_end() {
+        let arr = [1, 2, 3];
+        let mut reader = BytesReader::new(&arr);
+        assert!(reader.read_n(4).is_err());
+        assert!(reader.read_until(4).is_err());
+        assert_eq!(reader.read_n(3).unwrap(), [1, 2, 3]);
+        assert!(reader.peek().is_err());
+        assert!(reader.read().is_err());
     }
 }
@@ -1,4 +1,8 @@
-use clap::{Parser, Subcommand};
+use std::net::IpAddr;
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::downloader::config::Encryption;
 
 #[derive(Parser)]
 pub struct Cli {
@@ -32,5 +36,74 @@ pub enum SCommand {
         #[arg(short)]
         output_file_path: String,
         torrent_file_path: String,
+        #[command(flatten)]
+        args: DownloadArgs,
+    },
+    Verify {
+        torrent_file_path: String,
+        data_path: String,
+        #[arg(long)]
+        json: bool,
+    },
+    Create {
+        #[arg(short)]
+        output_file_path: String,
+        // a file or a directory
+        path: String,
+        #[arg(long)]
+        piece_length: Option<u32>,
+        // one tier per use, its trackers separated by commas
+        #[arg(long = "announce", value_name = "URL[,URL...]")]
+        trackers: Vec<String>,
+        #[arg(long)]
+        comment: Option<String>,
+        #[arg(long, default_value = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION")))]
+        created_by: String,
+        #[arg(long)]
+        no_creation_date: bool,
+        #[arg(long)]
+        private: bool,
+        #[arg(long)]
+        source: Option<String>,
+        #[arg(long = "web-seed", value_name = "URL")]
+        web_seeds: Vec<String>,
     },
+    Magnet {
+        #[arg(short)]
+        output_file_path: String,
+        magnet_link: String,
+        #[command(flatten)]
+        args: DownloadArgs,
+    },
+}
+
+#[derive(Args)]
+pub struct DownloadArgs {
+    #[arg(long)]
+    pub seed: bool,
+    #[arg(long, default_value_t = 6881)]
+    pub port: u16,
+    #[arg(long, default_value = "0.0.0.0")]
+    pub bind: IpAddr,
+    #[arg(long, default_value_t = 50)]
+    pub max_peers: usize,
+    #[arg(long)]
+    pub no_pex: bool,
+    #[arg(long)]
+    pub dht: bool,
+    #[arg(long)]
+    pub lsd: bool,
+    #[arg(long)]
+    pub utp: bool,
+    #[arg(long, value_enum, default_value = "plaintext")]
+    pub encryption: Encryption,
+    // replaces the default bootstrap nodes
+    #[arg(long = "dht-node", value_name = "HOST:PORT", value_parser = parse_node)]
+    pub dht_nodes: Vec<(String, u16)>,
+}
+
+fn parse_node(node: &str) -> Result<(String, u16), String> {
+    let (host, port) = node.rsplit_once(':').ok_or("expected HOST:PORT")?;
+    let port = port.parse().map_err(|_| "invalid port")?;
+    Ok((host.to_string(), port))
 }
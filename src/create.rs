@@ -0,0 +1,336 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread::{self, available_parallelism},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    bencoding::Value,
+    storage::{read_range, DataFile},
+};
+
+const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+// what the automatic piece length aims for
+const MAX_NO_PIECES: u64 = 1500;
+
+// Makes a torrent from a file or a directory.
+pub struct TorrentBuilder {
+    path: PathBuf,
+    piece_length: Option<u32>,
+    // tiers of trackers (BEP 12), the first one is the announce URL
+    trackers: Vec<Vec<String>>,
+    comment: Option<String>,
+    created_by: Option<String>,
+    creation_date: Option<i64>,
+    private: bool,
+    source: Option<String>,
+    web_seeds: Vec<String>,
+}
+
+impl TorrentBuilder {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            piece_length: None,
+            trackers: vec![],
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            private: false,
+            source: None,
+            web_seeds: vec![],
+        }
+    }
+
+    pub fn piece_length(mut self, piece_length: u32) -> Self {
+        self.piece_length = Some(piece_length);
+        self
+    }
+
+    pub fn tracker_tier(mut self, tier: Vec<String>) -> Self {
+        if !tier.is_empty() {
+            self.trackers.push(tier);
+        }
+        self
+    }
+
+    pub fn comment(mut self, comment: &str) -> Self {
+        self.comment = Some(comment.to_string());
+        self
+    }
+
+    pub fn created_by(mut self, created_by: &str) -> Self {
+        self.created_by = Some(created_by.to_string());
+        self
+    }
+
+    // seconds since the epoch
+    pub fn creation_date(mut self, creation_date: i64) -> Self {
+        self.creation_date = Some(creation_date);
+        self
+    }
+
+    pub fn private(mut self, private: bool) -> Self {
+        self.private = private;
+        self
+    }
+
+    pub fn source(mut self, source: &str) -> Self {
+        self.source = Some(source.to_string());
+        self
+    }
+
+    pub fn web_seed(mut self, url: &str) -> Self {
+        self.web_seeds.push(url.to_string());
+        self
+    }
+
+    // The bencoded torrent, with its keys sorted.
+    pub fn build(&self) -> Result<Vec<u8>> {
+        let name = (self.path.file_name())
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("no name for {}", self.path.display()))?;
+        let files = get_files(&self.path)?;
+        let length = files.iter().map(|file| file.length).sum();
+        ensure!(length > 0, "{} is empty", self.path.display());
+        let piece_length = match self.piece_length {
+            Some(piece_length) => {
+                ensure!(
+                    piece_length.is_power_of_two() && piece_length >= MIN_PIECE_LENGTH,
+                    "the piece length must be a power of two of at least {}",
+                    MIN_PIECE_LENGTH
+                );
+                piece_length
+            }
+            None => get_piece_length(length),
+        };
+
+        let mut info = vec![
+            ("name", name.into()),
+            ("piece length", (piece_length as i64).into()),
+            ("pieces", hash_pieces(&files, length, piece_length)?.into()),
+        ];
+        match self.path.is_dir() {
+            true => {
+                let files = (files.iter())
+                    .map(|file| {
+                        let path = file.path.iter().map(|part| part.as_str().into());
+                        Value::dict([
+                            ("length", (file.length as i64).into()),
+                            ("path", Value::List(path.collect())),
+                        ])
+                    })
+                    .collect();
+                info.push(("files", Value::List(files)));
+            }
+            false => info.push(("length", (length as i64).into())),
+        }
+        if self.private {
+            info.push(("private", 1.into()));
+        }
+        if let Some(source) = &self.source {
+            info.push(("source", source.as_str().into()));
+        }
+
+        let mut metainfo = vec![("info", Value::dict(info))];
+        if let Some(announce) = self.trackers.first().and_then(|tier| tier.first()) {
+            metainfo.push(("announce", announce.as_str().into()));
+        }
+        if self.trackers.iter().flatten().count() > 1 {
+            let tiers = (self.trackers.iter())
+                .map(|tier| Value::List(tier.iter().map(|url| url.as_str().into()).collect()))
+                .collect();
+            metainfo.push(("announce-list", Value::List(tiers)));
+        }
+        if let Some(comment) = &self.comment {
+            metainfo.push(("comment", comment.as_str().into()));
+        }
+        if let Some(created_by) = &self.created_by {
+            metainfo.push(("created by", created_by.as_str().into()));
+        }
+        if let Some(creation_date) = self.creation_date {
+            metainfo.push(("creation date", creation_date.into()));
+        }
+        if !self.web_seeds.is_empty() {
+            let urls = self.web_seeds.iter().map(|url| url.as_str().into());
+            metainfo.push(("url-list", Value::List(urls.collect())));
+        }
+        Ok(Value::dict(metainfo).encode())
+    }
+}
+
+// In a fixed order, so the same directory always gives the same torrent.
+fn get_files(path: &Path) -> Result<Vec<DataFile>> {
+    if !path.is_dir() {
+        let length = (fs::metadata(path))
+            .with_context(|| format!("reading {} failed", path.display()))?
+            .len();
+        return Ok(vec![DataFile {
+            full_path: Some(path.to_path_buf()),
+            path: vec![],
+            length,
+        }]);
+    }
+    let mut files = vec![];
+    let mut dirs = vec![(path.to_path_buf(), vec![])];
+    while let Some((dir, prefix)) = dirs.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                bail!("{} is not valid UTF-8", entry.path().display());
+            };
+            let mut path = prefix.clone();
+            path.push(name);
+            let meta = fs::metadata(entry.path())?;
+            match meta.is_dir() {
+                true => dirs.push((entry.path(), path)),
+                false => files.push(DataFile {
+                    full_path: Some(entry.path()),
+                    path,
+                    length: meta.len(),
+                }),
+            }
+        }
+    }
+    ensure!(!files.is_empty(), "{} has no files", path.display());
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(files)
+}
+
+// The smallest power of two that keeps the number of pieces down.
+fn get_piece_length(length: u64) -> u32 {
+    let mut piece_length = MIN_PIECE_LENGTH;
+    while piece_length < MAX_PIECE_LENGTH && length.div_ceil(piece_length as u64) > MAX_NO_PIECES {
+        piece_length *= 2;
+    }
+    piece_length
+}
+
+// Each thread hashes every n-th piece, reading it on its own.
+fn hash_pieces(files: &[DataFile], length: u64, piece_length: u32) -> Result<Vec<u8>> {
+    let no_pieces = length.div_ceil(piece_length as u64) as usize;
+    let parallel = available_parallelism().map_or(1, usize::from);
+    let mut hashes = vec![[0; 20]; no_pieces];
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallel)
+            .map(|first| {
+                scope.spawn(move || {
+                    let mut hashes = vec![];
+                    for idx in (first..no_pieces).step_by(parallel) {
+                        let start = idx as u64 * piece_length as u64;
+                        let len = (length - start).min(piece_length as u64);
+                        let bytes = read_range(files, start, len)?;
+                        hashes.push((idx, <[u8; 20]>::from(Sha1::digest(&bytes))));
+                    }
+                    anyhow::Ok(hashes)
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (idx, hash) in worker.join().unwrap()? {
+                hashes[idx] = hash;
+            }
+        }
+        anyhow::Ok(())
+    })?;
+    Ok(hashes.concat())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use sha1::{Digest, Sha1};
+    use tempfile::tempdir;
+
+    use crate::{bencoding::Value, metainfo::Metainfo};
+
+    use super::{get_piece_length, TorrentBuilder, MAX_PIECE_LENGTH, MIN_PIECE_LENGTH};
+
+    #[test]
+    fn test_create() {
+        let dir = tempdir().unwrap();
+        let root = dir.path().join("my dir");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        let (a, b): (Vec<u8>, Vec<u8>) =
+            ((0..20_000).map(|idx| idx as u8).collect(), vec![7; 30_000]);
+        fs::write(root.join("sub").join("b"), &b).unwrap();
+        fs::write(root.join("a"), &a).unwrap();
+
+        let bytes = TorrentBuilder::new(&root)
+            .piece_length(MIN_PIECE_LENGTH)
+            .tracker_tier(vec!["http://a/announce".to_string()])
+            .tracker_tier(vec![
+                "http://b/announce".to_string(),
+                "udp://c:80".to_string(),
+            ])
+            .comment("hi")
+            .created_by("me")
+            .creation_date(1_700_000_000)
+            .private(true)
+            .source("here")
+            .web_seed("http://d/")
+            .build()
+            .unwrap();
+        let value = Value::decode(&bytes).unwrap();
+        assert_eq!(value.encode(), bytes);
+        assert_eq!(
+            value.get("announce-list").unwrap().as_list().unwrap().len(),
+            2
+        );
+        assert_eq!(value.get("comment").unwrap().as_str(), Some("hi"));
+        assert_eq!(
+            value.get("creation date").unwrap().as_integer(),
+            Some(1_700_000_000)
+        );
+        let info = value.get("info").unwrap();
+        assert_eq!(info.get("source").unwrap().as_str(), Some("here"));
+
+        let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+        let info_hash: [u8; 20] = Sha1::digest(info.encode()).into();
+        assert_eq!(metainfo.get_info_hash(), info_hash);
+        assert_eq!(metainfo.announce, "http://a/announce");
+        assert_eq!(metainfo.url_list, ["http://d/"]);
+        assert_eq!(metainfo.info.name, "my dir");
+        assert!(metainfo.info.private);
+        assert_eq!(metainfo.info.length, 50_000);
+        let files = metainfo.info.files.as_ref().unwrap();
+        assert_eq!(files[0].path, ["a"]);
+        assert_eq!(files[1].path, ["sub", "b"]);
+
+        let data = [a, b].concat();
+        let hashes: Vec<[u8; 20]> = (data.chunks(MIN_PIECE_LENGTH as usize))
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        assert_eq!(metainfo.info.piece_hashes, hashes);
+
+        // a single file, without trackers
+        let bytes = TorrentBuilder::new(root.join("a")).build().unwrap();
+        let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+        assert_eq!(metainfo.announce, "");
+        assert_eq!(metainfo.info.name, "a");
+        assert!(metainfo.info.files.is_none());
+        assert_eq!(metainfo.info.length, 20_000);
+        assert_eq!(metainfo.info.piece_hashes.len(), 2);
+
+        assert!(TorrentBuilder::new(&root)
+            .piece_length(1000)
+            .build()
+            .is_err());
+        assert!(TorrentBuilder::new(dir.path().join("missing"))
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_piece_length() {
+        assert_eq!(get_piece_length(0), MIN_PIECE_LENGTH);
+        assert_eq!(get_piece_length(1500 * 16 * 1024), MIN_PIECE_LENGTH);
+        assert_eq!(get_piece_length(1500 * 16 * 1024 + 1), 2 * MIN_PIECE_LENGTH);
+        assert_eq!(get_piece_length(u64::MAX / 2), MAX_PIECE_LENGTH);
+    }
+}
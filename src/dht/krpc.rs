@@ -0,0 +1,172 @@
+use std::net::SocketAddrV4;
+
+use anyhow::{bail, Context, Result};
+
+use crate::bencoding::Value;
+
+use super::routing::{Node, NodeId};
+
+pub const GENERIC_ERROR: i64 = 201;
+pub const PROTOCOL_ERROR: i64 = 203;
+pub const METHOD_UNKNOWN: i64 = 204;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    Query { method: String, args: Value },
+    Response(Value),
+    Error { code: i64, message: String },
+}
+
+// A KRPC message (BEP 5), one per UDP datagram.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    pub transaction: Vec<u8>,
+    pub body: Body,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let t = ("t", self.transaction.clone().into());
+        let msg = match &self.body {
+            Body::Query { method, args } => Value::dict([
+                t,
+                ("y", "q".into()),
+                ("q", method.as_str().into()),
+                ("a", args.clone()),
+            ]),
+            Body::Response(values) => Value::dict([t, ("y", "r".into()), ("r", values.clone())]),
+            Body::Error { code, message } => {
+                let error = Value::List(vec![(*code).into(), message.as_str().into()]);
+                Value::dict([t, ("y", "e".into()), ("e", error)])
+            }
+        };
+        msg.encode()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        let msg = Value::decode(bytes)?;
+        let transaction = msg
+            .get("t")
+            .and_then(Value::as_bytes)
+            .context("krpc message without transaction id")?
+            .to_vec();
+        let body = match msg.get("y").and_then(Value::as_str) {
+            Some("q") => Body::Query {
+                method: msg
+                    .get("q")
+                    .and_then(Value::as_str)
+                    .context("krpc query without method")?
+                    .to_string(),
+                args: msg
+                    .get("a")
+                    .filter(|args| args.as_dict().is_some())
+                    .context("krpc query without arguments")?
+                    .clone(),
+            },
+            Some("r") => Body::Response(
+                msg.get("r")
+                    .filter(|values| values.as_dict().is_some())
+                    .context("krpc response without values")?
+                    .clone(),
+            ),
+            Some("e") => {
+                let error = msg.get("e").and_then(Value::as_list).unwrap_or_default();
+                Body::Error {
+                    code: error
+                        .first()
+                        .and_then(Value::as_integer)
+                        .unwrap_or(GENERIC_ERROR),
+                    message: error
+                        .get(1)
+                        .and_then(Value::as_str)
+                        .unwrap_or_default()
+                        .to_string(),
+                }
+            }
+            _ => bail!("invalid krpc message type"),
+        };
+        Ok(Self { transaction, body })
+    }
+}
+
+pub fn get_id(values: &Value) -> Option<NodeId> {
+    NodeId::from_bytes(values.get("id")?.as_bytes()?)
+}
+
+// compact node info: id, ip and port
+pub fn encode_nodes(nodes: &[Node]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(26 * nodes.len());
+    for node in nodes {
+        bytes.extend(node.id.0);
+        bytes.extend(encode_peer(node.addr));
+    }
+    bytes
+}
+
+pub fn decode_nodes(bytes: &[u8]) -> Vec<Node> {
+    bytes
+        .chunks_exact(26)
+        .map(|chunk| {
+            let id = NodeId::from_bytes(&chunk[..20]).unwrap();
+            Node::new(id, decode_peer(&chunk[20..]).unwrap())
+        })
+        .collect()
+}
+
+pub fn encode_peer(addr: SocketAddrV4) -> Vec<u8> {
+    let mut bytes = addr.ip().octets().to_vec();
+    bytes.extend(addr.port().to_be_bytes());
+    bytes
+}
+
+pub fn decode_peer(bytes: &[u8]) -> Option<SocketAddrV4> {
+    let bytes: [u8; 6] = bytes.try_into().ok()?;
+    let ip = <[u8; 4]>::try_from(&bytes[..4]).unwrap();
+    Some(SocketAddrV4::new(
+        ip.into(),
+        u16::from_be_bytes([bytes[4], bytes[5]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::bencoding::Value;
+
+    use super::{Body, Message};
+
+    #[test]
+    fn test_messages() {
+        // the examples from BEP 5
+        let encoded = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let msg = Message::decode(encoded).unwrap();
+        assert_eq!(msg.transaction, b"aa");
+        let Body::Query { method, args } = &msg.body else {
+            panic!("expected a query");
+        };
+        assert_eq!(method, "ping");
+        assert_eq!(
+            args.get("id").unwrap().as_bytes(),
+            Some(&b"abcdefghij0123456789"[..])
+        );
+        assert_eq!(msg.encode(), encoded);
+
+        let encoded = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+        let msg = Message::decode(encoded).unwrap();
+        assert!(matches!(msg.body, Body::Response(_)));
+        assert_eq!(msg.encode(), encoded);
+
+        let encoded = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+        let msg = Message::decode(encoded).unwrap();
+        assert_eq!(
+            msg.body,
+            Body::Error {
+                code: 201,
+                message: "A Generic Error Ocurred".to_string()
+            }
+        );
+        assert_eq!(msg.encode(), encoded);
+
+        assert!(Message::decode(b"d1:t2:aa1:y1:xe").is_err());
+        assert!(Message::decode(&Value::dict([("y", "q".into())]).encode()).is_err());
+    }
+}
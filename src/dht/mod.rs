@@ -0,0 +1,473 @@
+// A mainline DHT node (BEP 5), for finding peers without a tracker.
+//
+// Every node keeps a routing table of other nodes, ordered by the XOR
+// distance of their ids to its own. To find the peers of a torrent, we ask
+// the nodes closest to the info hash, which point us to ever closer ones,
+// until those who store the peers are reached. Announcing stores us there.
+
+mod krpc;
+mod routing;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use sha1::{Digest, Sha1};
+use tokio::{
+    net::{lookup_host, UdpSocket},
+    sync::oneshot,
+    task::JoinSet,
+    time::{timeout, Instant},
+};
+
+use crate::{bencoding::Value, random::random_bytes};
+use krpc::{
+    decode_nodes, decode_peer, encode_nodes, encode_peer, get_id, Body, Message, METHOD_UNKNOWN,
+    PROTOCOL_ERROR,
+};
+use routing::{Node, NodeId, RoutingTable, K};
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+// queries in flight at once during a lookup
+const ALPHA: usize = 3;
+// tokens from the previous period stay valid as well
+const TOKEN_PERIOD: Duration = Duration::from_secs(5 * 60);
+const PEER_LIFETIME: Duration = Duration::from_secs(30 * 60);
+// so a response still fits in a datagram
+const MAX_VALUES: usize = 50;
+const MAX_STORED_PEERS: usize = 200;
+const MAX_STORED_TORRENTS: usize = 1000;
+
+// a query is matched to its answer by transaction id and node
+type QueryKey = (Vec<u8>, SocketAddrV4);
+
+struct Secrets {
+    current: [u8; 8],
+    previous: [u8; 8],
+    rotated: Instant,
+}
+
+pub struct Dht {
+    socket: Arc<UdpSocket>,
+    id: NodeId,
+    table: Mutex<RoutingTable>,
+    pending: Mutex<HashMap<QueryKey, oneshot::Sender<Body>>>,
+    next_transaction: AtomicU16,
+    secrets: Mutex<Secrets>,
+    // peers announced to us
+    peers: Mutex<HashMap<[u8; 20], HashMap<SocketAddrV4, Instant>>>,
+}
+
+impl Dht {
+    // The socket may be shared, e.g. with uTP, as long as its packets are
+    // passed to handle.
+    pub fn new(socket: Arc<UdpSocket>) -> Self {
+        let id = NodeId::random();
+        let secret = random_bytes();
+        Self {
+            socket,
+            id,
+            table: Mutex::new(RoutingTable::new(id)),
+            pending: Mutex::new(HashMap::new()),
+            next_transaction: AtomicU16::new(0),
+            secrets: Mutex::new(Secrets {
+                current: secret,
+                previous: secret,
+                rotated: Instant::now(),
+            }),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn get_no_nodes(&self) -> usize {
+        self.table.lock().unwrap().len()
+    }
+
+    // Answers queries and routes responses to the queries waiting for them.
+    pub async fn handle(&self, datagram: &[u8], from: SocketAddr) {
+        let SocketAddr::V4(from) = from else {
+            return;
+        };
+        let Ok(msg) = Message::decode(datagram) else {
+            return;
+        };
+        match msg.body {
+            Body::Query { method, args } => {
+                let body = match self.handle_query(from, &method, &args) {
+                    Ok(values) => Body::Response(values),
+                    Err((code, message)) => Body::Error {
+                        code,
+                        message: message.to_string(),
+                    },
+                };
+                if let Some(id) = get_id(&args) {
+                    self.table.lock().unwrap().insert(id, from);
+                }
+                let reply = Message {
+                    transaction: msg.transaction,
+                    body,
+                };
+                let _ = self.socket.send_to(&reply.encode(), from).await;
+            }
+            body => {
+                let key = (msg.transaction, from);
+                if let Some(sender) = self.pending.lock().unwrap().remove(&key) {
+                    let _ = sender.send(body);
+                }
+            }
+        }
+    }
+
+    fn handle_query(
+        &self,
+        from: SocketAddrV4,
+        method: &str,
+        args: &Value,
+    ) -> Result<Value, (i64, &'static str)> {
+        get_id(args).ok_or((PROTOCOL_ERROR, "invalid id"))?;
+        let get_hash = |key| {
+            let bytes = args.get(key).and_then(Value::as_bytes);
+            bytes
+                .and_then(NodeId::from_bytes)
+                .ok_or((PROTOCOL_ERROR, "invalid argument"))
+        };
+        let id = ("id", Value::from(&self.id.0[..]));
+        match method {
+            "ping" => Ok(Value::dict([id])),
+            "find_node" => {
+                let target = get_hash("target")?;
+                Ok(Value::dict([id, ("nodes", self.get_nodes(&target))]))
+            }
+            "get_peers" => {
+                let info_hash = get_hash("info_hash")?;
+                let token = self.get_token(from.ip(), false);
+                let mut values = vec![id, ("token", token.into())];
+                let peers = self.get_stored_peers(&info_hash.0);
+                if peers.is_empty() {
+                    values.push(("nodes", self.get_nodes(&info_hash)));
+                } else {
+                    let peers = peers.into_iter().map(|addr| encode_peer(addr).into());
+                    values.push(("values", Value::List(peers.collect())));
+                }
+                Ok(Value::dict(values))
+            }
+            "announce_peer" => {
+                let info_hash = get_hash("info_hash")?;
+                let token = args.get("token").and_then(Value::as_bytes);
+                if !token.is_some_and(|token| self.is_valid_token(from.ip(), token)) {
+                    return Err((PROTOCOL_ERROR, "invalid token"));
+                }
+                let port = match args.get("implied_port").and_then(Value::as_integer) {
+                    Some(1) => Some(from.port()),
+                    _ => args
+                        .get("port")
+                        .and_then(Value::as_integer)
+                        .and_then(|port| u16::try_from(port).ok())
+                        .filter(|port| *port != 0),
+                };
+                let port = port.ok_or((PROTOCOL_ERROR, "invalid port"))?;
+                self.store_peer(info_hash.0, SocketAddrV4::new(*from.ip(), port));
+                Ok(Value::dict([id]))
+            }
+            _ => Err((METHOD_UNKNOWN, "method unknown")),
+        }
+    }
+
+    fn get_nodes(&self, target: &NodeId) -> Value {
+        let nodes = self.table.lock().unwrap().closest(target, K);
+        encode_nodes(&nodes).into()
+    }
+
+    fn get_secrets(&self) -> MutexGuard<'_, Secrets> {
+        let mut secrets = self.secrets.lock().unwrap();
+        if secrets.rotated.elapsed() >= TOKEN_PERIOD {
+            secrets.previous = secrets.current;
+            secrets.current = random_bytes();
+            secrets.rotated = Instant::now();
+        }
+        secrets
+    }
+
+    fn get_token(&self, ip: &Ipv4Addr, previous: bool) -> Vec<u8> {
+        let secrets = self.get_secrets();
+        let secret = match previous {
+            true => secrets.previous,
+            false => secrets.current,
+        };
+        let mut hasher = Sha1::new();
+        hasher.update(ip.octets());
+        hasher.update(secret);
+        hasher.finalize()[..8].to_vec()
+    }
+
+    fn is_valid_token(&self, ip: &Ipv4Addr, token: &[u8]) -> bool {
+        self.get_token(ip, false) == token || self.get_token(ip, true) == token
+    }
+
+    fn store_peer(&self, info_hash: [u8; 20], addr: SocketAddrV4) {
+        let mut torrents = self.peers.lock().unwrap();
+        if !torrents.contains_key(&info_hash) && torrents.len() >= MAX_STORED_TORRENTS {
+            return;
+        }
+        let peers = torrents.entry(info_hash).or_default();
+        peers.retain(|_, announced| announced.elapsed() < PEER_LIFETIME);
+        if peers.len() < MAX_STORED_PEERS || peers.contains_key(&addr) {
+            peers.insert(addr, Instant::now());
+        }
+    }
+
+    fn get_stored_peers(&self, info_hash: &[u8; 20]) -> Vec<SocketAddrV4> {
+        let torrents = self.peers.lock().unwrap();
+        let Some(peers) = torrents.get(info_hash) else {
+            return vec![];
+        };
+        peers
+            .iter()
+            .filter(|(_, announced)| announced.elapsed() < PEER_LIFETIME)
+            .map(|(addr, _)| *addr)
+            .take(MAX_VALUES)
+            .collect()
+    }
+
+    async fn query(
+        &self,
+        addr: SocketAddrV4,
+        method: &str,
+        mut args: Vec<(&str, Value)>,
+    ) -> Result<Value> {
+        let transaction = self.next_transaction.fetch_add(1, Ordering::Relaxed);
+        let transaction = transaction.to_be_bytes().to_vec();
+        args.push(("id", self.id.0[..].into()));
+        let msg = Message {
+            transaction: transaction.clone(),
+            body: Body::Query {
+                method: method.to_string(),
+                args: Value::dict(args),
+            },
+        };
+
+        let key = (transaction, addr);
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().insert(key.clone(), sender);
+        let result = async {
+            self.socket.send_to(&msg.encode(), addr).await?;
+            let body = timeout(QUERY_TIMEOUT, receiver)
+                .await
+                .context("query timed out")??;
+            anyhow::Ok(body)
+        }
+        .await;
+        self.pending.lock().unwrap().remove(&key);
+
+        let body = match result {
+            Ok(body) => body,
+            Err(err) => {
+                self.table.lock().unwrap().failed(addr);
+                return Err(err);
+            }
+        };
+        match body {
+            Body::Response(values) => {
+                let id = get_id(&values).context("response without id")?;
+                self.table.lock().unwrap().insert(id, addr);
+                Ok(values)
+            }
+            Body::Error { code, message } => bail!("error {}: {}", code, message),
+            Body::Query { .. } => bail!("query instead of a response"),
+        }
+    }
+
+    // Joins the network through some known nodes, e.g. from the torrent.
+    pub async fn bootstrap(self: &Arc<Self>, nodes: &[(String, u16)]) {
+        let mut tasks = JoinSet::new();
+        for (host, port) in nodes.iter().cloned() {
+            let dht = self.clone();
+            tasks.spawn(async move {
+                for addr in lookup_host((host.as_str(), port)).await? {
+                    let SocketAddr::V4(addr) = addr else {
+                        continue;
+                    };
+                    let args = vec![("target", dht.id.0[..].into())];
+                    let values = dht.query(addr, "find_node", args).await?;
+                    let nodes = values.get("nodes").and_then(Value::as_bytes);
+                    let mut table = dht.table.lock().unwrap();
+                    for node in decode_nodes(nodes.unwrap_or_default()) {
+                        table.insert(node.id, node.addr);
+                    }
+                    break;
+                }
+                anyhow::Ok(())
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+
+        // fills the buckets close to us
+        self.lookup(self.id, "find_node").await;
+    }
+
+    // Finds peers of the torrent. With a port, we are announced to the
+    // nodes closest to the info hash as well.
+    pub async fn get_peers(
+        self: &Arc<Self>,
+        info_hash: [u8; 20],
+        port: Option<u16>,
+    ) -> Vec<SocketAddr> {
+        let (peers, closest) = self.lookup(NodeId(info_hash), "get_peers").await;
+        if let Some(port) = port {
+            let mut tasks = JoinSet::new();
+            for (node, token) in closest {
+                let Some(token) = token else {
+                    continue;
+                };
+                let args = vec![
+                    ("info_hash", info_hash[..].into()),
+                    ("port", (port as i64).into()),
+                    ("token", token.into()),
+                ];
+                let dht = self.clone();
+                tasks.spawn(async move { dht.query(node.addr, "announce_peer", args).await });
+            }
+            while tasks.join_next().await.is_some() {}
+        }
+        peers.into_iter().map(SocketAddr::V4).collect()
+    }
+
+    // Iterative lookup: keeps asking the closest nodes not asked yet, until
+    // the K closest ones have all answered or failed. Returns the peers
+    // found and the closest nodes that answered, with their tokens.
+    async fn lookup(
+        self: &Arc<Self>,
+        target: NodeId,
+        method: &'static str,
+    ) -> (HashSet<SocketAddrV4>, Vec<(Node, Option<Vec<u8>>)>) {
+        let key = match method {
+            "get_peers" => "info_hash",
+            _ => "target",
+        };
+        let mut shortlist: BTreeMap<_, _> = self
+            .table
+            .lock()
+            .unwrap()
+            .closest(&target, K)
+            .into_iter()
+            .map(|node| (node.id.distance(&target), node))
+            .collect();
+        let mut queried = HashSet::new();
+        let mut responded = BTreeMap::new();
+        let mut peers = HashSet::new();
+        loop {
+            let batch: Vec<_> = shortlist
+                .values()
+                .take(K)
+                .filter(|node| !queried.contains(&node.addr))
+                .take(ALPHA)
+                .copied()
+                .collect();
+            if batch.is_empty() {
+                break;
+            }
+
+            let mut tasks = JoinSet::new();
+            for node in batch {
+                queried.insert(node.addr);
+                let dht = self.clone();
+                let args = vec![(key, target.0[..].into())];
+                tasks.spawn(async move { (node, dht.query(node.addr, method, args).await) });
+            }
+            while let Some(joined) = tasks.join_next().await {
+                let (node, result) = joined.unwrap();
+                let distance = node.id.distance(&target);
+                let Ok(values) = result else {
+                    shortlist.remove(&distance);
+                    continue;
+                };
+                let nodes = values.get("nodes").and_then(Value::as_bytes);
+                for found in decode_nodes(nodes.unwrap_or_default()) {
+                    if found.id != self.id {
+                        shortlist.entry(found.id.distance(&target)).or_insert(found);
+                    }
+                }
+                let values_list = values.get("values").and_then(Value::as_list);
+                for value in values_list.unwrap_or_default() {
+                    peers.extend(value.as_bytes().and_then(decode_peer));
+                }
+                let token = values.get("token").and_then(Value::as_bytes);
+                responded.insert(distance, (node, token.map(<[u8]>::to_vec)));
+            }
+        }
+        (peers, responded.into_values().take(K).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio::{net::UdpSocket, spawn};
+
+    use super::Dht;
+
+    async fn start_node() -> Arc<Dht> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let dht = Arc::new(Dht::new(socket.clone()));
+        let handler = dht.clone();
+        spawn(async move {
+            let mut buf = vec![0; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                handler.handle(&buf[..len], from).await;
+            }
+        });
+        dht
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() {
+        let mut nodes = vec![];
+        for _ in 0..6 {
+            nodes.push(start_node().await);
+        }
+        let bootstrap = [(
+            "127.0.0.1".to_string(),
+            nodes[0].socket.local_addr().unwrap().port(),
+        )];
+        for dht in &nodes[1..] {
+            dht.bootstrap(&bootstrap).await;
+            assert!(dht.get_no_nodes() > 0);
+        }
+
+        let info_hash = [7; 20];
+        assert!(nodes[1].get_peers(info_hash, Some(1234)).await.is_empty());
+        let peers = nodes[5].get_peers(info_hash, None).await;
+        assert_eq!(peers, ["127.0.0.1:1234".parse().unwrap()]);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_token() {
+        let (a, b) = (start_node().await, start_node().await);
+        let super::SocketAddr::V4(addr) = b.socket.local_addr().unwrap() else {
+            unreachable!();
+        };
+        let args = vec![
+            ("info_hash", [7; 20][..].into()),
+            ("port", 1234.into()),
+            ("token", "nope".into()),
+        ];
+        let err = a.query(addr, "announce_peer", args).await.unwrap_err();
+        assert_eq!(err.to_string(), "error 203: invalid token");
+
+        let err = a
+            .query(addr, "sample_infohashes", vec![])
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "error 204: method unknown");
+        assert!(a.query(addr, "ping", vec![]).await.is_ok());
+    }
+}
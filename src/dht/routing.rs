@@ -0,0 +1,164 @@
+use std::net::SocketAddrV4;
+
+use crate::random::random_bytes;
+
+pub const K: usize = 8;
+// a node that missed this many queries in a row may be replaced
+const MAX_FAILURES: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(pub [u8; 20]);
+
+impl NodeId {
+    pub fn random() -> Self {
+        Self(random_bytes())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        Some(Self(bytes.try_into().ok()?))
+    }
+
+    // XOR metric, compared as a big-endian number
+    pub fn distance(&self, other: &NodeId) -> [u8; 20] {
+        let mut distance = [0; 20];
+        for (idx, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[idx] ^ other.0[idx];
+        }
+        distance
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Node {
+    pub id: NodeId,
+    pub addr: SocketAddrV4,
+    failures: u32,
+}
+
+impl Node {
+    pub fn new(id: NodeId, addr: SocketAddrV4) -> Self {
+        Self {
+            id,
+            addr,
+            failures: 0,
+        }
+    }
+}
+
+// Bucket i holds the nodes whose id shares exactly i leading bits with
+// ours, so we know many nodes close to us and a few far away.
+pub struct RoutingTable {
+    own_id: NodeId,
+    buckets: Vec<Vec<Node>>,
+}
+
+impl RoutingTable {
+    pub fn new(own_id: NodeId) -> Self {
+        Self {
+            own_id,
+            buckets: vec![vec![]; 160],
+        }
+    }
+
+    fn get_bucket_idx(&self, id: &NodeId) -> Option<usize> {
+        let distance = self.own_id.distance(id);
+        let idx = distance.iter().position(|byte| *byte != 0)?;
+        Some(idx * 8 + distance[idx].leading_zeros() as usize)
+    }
+
+    // Known nodes move to the back, as the most recently seen. A full
+    // bucket only takes a new node in place of one that stopped answering.
+    pub fn insert(&mut self, id: NodeId, addr: SocketAddrV4) {
+        let Some(idx) = self.get_bucket_idx(&id) else {
+            return;
+        };
+        let bucket = &mut self.buckets[idx];
+        if let Some(pos) = bucket.iter().position(|node| node.id == id) {
+            bucket.remove(pos);
+        } else if bucket.len() >= K {
+            let Some(pos) = bucket.iter().position(|node| node.failures >= MAX_FAILURES) else {
+                return;
+            };
+            bucket.remove(pos);
+        }
+        bucket.push(Node::new(id, addr));
+    }
+
+    pub fn failed(&mut self, addr: SocketAddrV4) {
+        let mut nodes = self.buckets.iter_mut().flatten();
+        if let Some(node) = nodes.find(|node| node.addr == addr) {
+            node.failures += 1;
+        }
+    }
+
+    pub fn closest(&self, target: &NodeId, n: usize) -> Vec<Node> {
+        let mut nodes: Vec<_> = self.buckets.iter().flatten().copied().collect();
+        nodes.sort_by_key(|node| node.id.distance(target));
+        nodes.truncate(n);
+        nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddrV4;
+
+    use super::{NodeId, RoutingTable, K};
+
+    fn id(first: u8, last: u8) -> NodeId {
+        let mut id = [0; 20];
+        id[0] = first;
+        id[19] = last;
+        NodeId(id)
+    }
+
+    fn addr(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([127, 0, 0, 1].into(), port)
+    }
+
+    #[test]
+    fn test_buckets() {
+        let mut table = RoutingTable::new(id(0, 0));
+        assert_eq!(table.get_bucket_idx(&id(0x80, 0)), Some(0));
+        assert_eq!(table.get_bucket_idx(&id(0x01, 0)), Some(7));
+        assert_eq!(table.get_bucket_idx(&id(0, 1)), Some(159));
+        assert_eq!(table.get_bucket_idx(&id(0, 0)), None);
+
+        // the far half of the id space only gets one bucket
+        for last in 0..=K as u8 {
+            table.insert(id(0x80, last), addr(last as u16));
+        }
+        assert_eq!(table.len(), K);
+        table.insert(id(0, 1), addr(100));
+        assert_eq!(table.len(), K + 1);
+
+        // until one of them stops answering
+        for _ in 0..2 {
+            table.failed(addr(3));
+        }
+        table.insert(id(0x80, K as u8), addr(200));
+        assert_eq!(table.len(), K + 1);
+        assert!(table
+            .closest(&id(0x80, 3), K)
+            .iter()
+            .all(|node| node.addr != addr(3)));
+    }
+
+    #[test]
+    fn test_closest() {
+        let mut table = RoutingTable::new(id(0, 0));
+        for first in [0x80, 0x40, 0x20, 0x10] {
+            table.insert(id(first, 0), addr(first as u16));
+        }
+        let closest: Vec<_> = table
+            .closest(&id(0x30, 0), 3)
+            .into_iter()
+            .map(|node| node.id)
+            .collect();
+        assert_eq!(closest, [id(0x20, 0), id(0x10, 0), id(0x40, 0)]);
+    }
+}
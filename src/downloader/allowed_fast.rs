@@ -0,0 +1,57 @@
+use std::net::Ipv4Addr;
+
+use sha1::{Digest, Sha1};
+
+// The canonical allowed fast set from BEP 6, so both sides of a connection
+// agree on it without further negotiation.
+pub fn get_allowed_fast_set(
+    ip: Ipv4Addr,
+    info_hash: &[u8; 20],
+    no_pieces: u32,
+    k: usize,
+) -> Vec<u32> {
+    let mut set = vec![];
+    if no_pieces as usize <= k {
+        return set;
+    }
+
+    let masked = u32::from(ip) & 0xffffff00;
+    let mut x = masked.to_be_bytes().to_vec();
+    x.extend_from_slice(info_hash);
+    while set.len() < k {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks(4) {
+            if set.len() == k {
+                break;
+            }
+            let y = u32::from_be_bytes(chunk.try_into().unwrap());
+            let idx = y % no_pieces;
+            if !set.contains(&idx) {
+                set.push(idx);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::get_allowed_fast_set;
+
+    #[test]
+    fn test_allowed_fast_set() {
+        let ip = Ipv4Addr::new(80, 4, 4, 200);
+        let info_hash = [0xaa; 20];
+        assert_eq!(
+            get_allowed_fast_set(ip, &info_hash, 1313, 7),
+            [1059, 431, 808, 1217, 287, 376, 1188]
+        );
+        assert_eq!(
+            get_allowed_fast_set(ip, &info_hash, 1313, 9),
+            [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+        );
+        assert!(get_allowed_fast_set(ip, &info_hash, 5, 7).is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; len.div_ceil(8)],
+            len,
+        }
+    }
+
+    pub fn from_bytes(mut bytes: Vec<u8>, len: usize) -> Self {
+        bytes.resize(len.div_ceil(8), 0);
+        let mut bitfield = Self { bytes, len };
+        bitfield.clear_spare_bits();
+        bitfield
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn has_none(&self) -> bool {
+        self.bytes.iter().all(|byte| *byte == 0)
+    }
+
+    pub fn has_all(&self) -> bool {
+        (0..self.len as u32).all(|idx| self.has(idx))
+    }
+
+    pub fn has(&self, idx: u32) -> bool {
+        let idx = idx as usize;
+        if idx >= self.len {
+            return false;
+        }
+        self.bytes[idx / 8] & (0x80 >> (idx % 8)) != 0
+    }
+
+    pub fn set(&mut self, idx: u32) {
+        let idx = idx as usize;
+        if idx >= self.len {
+            return;
+        }
+        self.bytes[idx / 8] |= 0x80 >> (idx % 8);
+    }
+
+    fn clear_spare_bits(&mut self) {
+        let spare = self.bytes.len() * 8 - self.len;
+        if let Some(last) = self.bytes.last_mut() {
+            *last &= 0xff << spare;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bitfield;
+
+    #[test]
+    fn test_has() {
+        let bitfield = Bitfield::from_bytes(vec![0b1010_0000, 0b0100_0000], 10);
+        let got: Vec<_> = (0..10).filter(|idx| bitfield.has(*idx)).collect();
+        assert_eq!(got, [0, 2, 9]);
+        assert!(!bitfield.has(10));
+    }
+
+    #[test]
+    fn test_set() {
+        let mut bitfield = Bitfield::new(10);
+        bitfield.set(1);
+        bitfield.set(8);
+        bitfield.set(10);
+        assert_eq!(
+            bitfield,
+            Bitfield::from_bytes(vec![0b0100_0000, 0b1000_0000], 10)
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_spare_bits() {
+        let bitfield = Bitfield::from_bytes(vec![0xff, 0xff, 0xff], 10);
+        assert_eq!(bitfield, Bitfield::from_bytes(vec![0xff, 0b1100_0000], 10));
+
+        let bitfield = Bitfield::from_bytes(vec![0xff], 10);
+        assert_eq!(bitfield, Bitfield::from_bytes(vec![0xff, 0], 10));
+    }
+}
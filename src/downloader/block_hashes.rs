@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+
+use crate::{
+    merkle::{BlockVerifier, BLOCK_LEN},
+    metainfo::Info,
+};
+
+use super::{parts::HashReq, swarm::PeerKey};
+
+// the most hashes a request may ask for (BEP 52)
+const MAX_HASHES: usize = 512;
+
+struct File {
+    start: u64,
+    length: u64,
+    verifier: BlockVerifier,
+}
+
+// The v2 files of a torrent, so each block can be checked as it arrives
+// and a bad one blamed on the peer that sent it. The piece hashes still
+// decide what is kept, unless there are none but these.
+#[derive(Default)]
+pub struct BlockHashes {
+    piece_length: u64,
+    // by start, without the empty ones
+    files: Vec<File>,
+    // asked for and not answered yet, with who was asked
+    pending: HashMap<HashReq, PeerKey>,
+}
+
+impl BlockHashes {
+    pub fn new(info: &Info) -> Self {
+        let files = (info.file_tree.iter())
+            .filter_map(|file| {
+                let no_blocks = file.length.div_ceil(BLOCK_LEN as u64) as usize;
+                Some(File {
+                    start: file.start,
+                    length: file.length,
+                    verifier: BlockVerifier::new(file.pieces_root?, no_blocks),
+                })
+            })
+            .collect();
+        Self {
+            piece_length: info.piece_length as u64,
+            files,
+            pending: HashMap::new(),
+        }
+    }
+
+    // The file a block starts in, and its index there.
+    fn locate(&self, piece_idx: u32, begin: u32) -> Option<(&File, usize)> {
+        let offset = piece_idx as u64 * self.piece_length + begin as u64;
+        let pos = self.files.partition_point(|file| file.start <= offset);
+        let file = self.files.get(pos.checked_sub(1)?)?;
+        let block_idx = (offset - file.start) / BLOCK_LEN as u64;
+        (offset < file.start + file.length).then_some((file, block_idx as usize))
+    }
+
+    // What to ask `key` for, so the block can be checked. None if its hash
+    // is known, or already asked for.
+    pub fn get_hash_req(&mut self, key: PeerKey, piece_idx: u32, begin: u32) -> Option<HashReq> {
+        let (file, block_idx) = self.locate(piece_idx, begin)?;
+        if file.verifier.has_hash(block_idx) {
+            return None;
+        }
+        // the hashes of the piece's blocks
+        let length = (self.piece_length as usize / BLOCK_LEN).clamp(2, MAX_HASHES);
+        let req = file.verifier.get_hash_req(block_idx, length);
+        if self.pending.contains_key(&req) {
+            return None;
+        }
+        self.pending.insert(req, key);
+        Some(req)
+    }
+
+    // False if the hashes don't prove out. Ones we didn't ask `key` for
+    // are ignored.
+    pub fn add_hashes(&mut self, key: PeerKey, req: &HashReq, hashes: &[[u8; 32]]) -> bool {
+        if self.pending.get(req) != Some(&key) {
+            return true;
+        }
+        self.pending.remove(req);
+        // files with the same data share a root
+        (self.files.iter_mut()).fold(false, |added, file| {
+            file.verifier.add_hashes(req, hashes) | added
+        })
+    }
+
+    pub fn reject(&mut self, key: PeerKey, req: &HashReq) {
+        if self.pending.get(req) == Some(&key) {
+            self.pending.remove(req);
+        }
+    }
+
+    // Forgets what `key` was asked, so another peer is asked instead.
+    pub fn remove_peer(&mut self, key: PeerKey) {
+        self.pending.retain(|_, asked| *asked != key);
+    }
+
+    // None if the hash isn't known yet, or the block isn't in a v2 file.
+    pub fn check_block(&self, piece_idx: u32, begin: u32, bytes: &[u8]) -> Option<bool> {
+        let (file, block_idx) = self.locate(piece_idx, begin)?;
+        // the rest of the piece is padding
+        let left = file.length - (block_idx * BLOCK_LEN) as u64;
+        let len = left.min(bytes.len() as u64) as usize;
+        file.verifier.check_block(block_idx, &bytes[..len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        bencoding::Value,
+        merkle::{get_block_hashes, get_file_root, hash_pair, BLOCK_LEN},
+        metainfo::Metainfo,
+    };
+
+    use super::BlockHashes;
+
+    #[test]
+    fn test_block_hashes() {
+        // a file of a block and a bit, then one of four blocks
+        let a: Vec<u8> = (0..BLOCK_LEN + 5).map(|idx| idx as u8).collect();
+        let b: Vec<u8> = (0..4 * BLOCK_LEN).map(|idx| (idx / 3) as u8).collect();
+        let (a_blocks, b_blocks) = (get_block_hashes(&a), get_block_hashes(&b));
+        let file = |length: usize, root: [u8; 32]| {
+            let file = Value::dict([
+                ("length", (length as i64).into()),
+                ("pieces root", root.as_slice().into()),
+            ]);
+            Value::dict([("", file)])
+        };
+        let tree = Value::dict([
+            ("a", file(a.len(), get_file_root(&a_blocks))),
+            ("b", file(b.len(), get_file_root(&b_blocks))),
+        ]);
+        let info = Value::dict([
+            ("file tree", tree),
+            ("meta version", 2.into()),
+            ("name", "dir".into()),
+            ("piece length", (2 * BLOCK_LEN as i64).into()),
+        ])
+        .encode();
+        let metainfo = Metainfo::from_info("", &info).unwrap();
+        let mut block_hashes = BlockHashes::new(&metainfo.info);
+        let begin = BLOCK_LEN as u32;
+
+        // b starts on piece 1, so piece 2 holds its last two blocks
+        let req = block_hashes.get_hash_req(0, 2, begin).unwrap();
+        assert_eq!((req.index, req.length, req.proof_layers), (2, 2, 1));
+        assert_eq!(block_hashes.get_hash_req(1, 2, 0), None);
+        assert_eq!(
+            block_hashes.check_block(2, begin, &b[3 * BLOCK_LEN..]),
+            None
+        );
+
+        // only from who was asked
+        let uncle = hash_pair(&b_blocks[0], &b_blocks[1]);
+        let hashes = [b_blocks[2], b_blocks[3], uncle];
+        assert!(block_hashes.add_hashes(1, &req, &[[0; 32]; 3]));
+        assert!(!block_hashes.add_hashes(0, &req, &[b_blocks[3], b_blocks[2], uncle]));
+        assert_eq!(block_hashes.get_hash_req(1, 2, 0), Some(req));
+        assert!(block_hashes.add_hashes(1, &req, &hashes));
+        assert_eq!(block_hashes.get_hash_req(1, 2, 0), None);
+        let block = |idx: usize| &b[idx * BLOCK_LEN..(idx + 1) * BLOCK_LEN];
+        assert_eq!(block_hashes.check_block(2, begin, block(3)), Some(true));
+        assert_eq!(block_hashes.check_block(2, begin, block(2)), Some(false));
+
+        // the last block of a is padded to a whole one
+        let req = block_hashes.get_hash_req(0, 0, begin).unwrap();
+        assert!(block_hashes.add_hashes(0, &req, &a_blocks));
+        let mut padded = a[BLOCK_LEN..].to_vec();
+        padded.resize(BLOCK_LEN, 0);
+        assert_eq!(block_hashes.check_block(0, begin, &padded), Some(true));
+
+        // given up on once the peer is gone
+        let req = block_hashes.get_hash_req(2, 1, 0).unwrap();
+        block_hashes.remove_peer(2);
+        assert_eq!(block_hashes.get_hash_req(3, 1, 0), Some(req));
+        block_hashes.reject(3, &req);
+        assert_eq!(block_hashes.get_hash_req(4, 1, 0), Some(req));
+    }
+}
@@ -0,0 +1,82 @@
+use super::peer_msg::PeerMsg;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChokeState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for ChokeState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl ChokeState {
+    pub fn received(&mut self, msg: &PeerMsg) {
+        match msg {
+            PeerMsg::Choke => self.peer_choking = true,
+            PeerMsg::Unchoke => self.peer_choking = false,
+            PeerMsg::Interested => self.peer_interested = true,
+            PeerMsg::NotInterested => self.peer_interested = false,
+            _ => {}
+        }
+    }
+
+    pub fn sent(&mut self, msg: &PeerMsg) {
+        match msg {
+            PeerMsg::Choke => self.am_choking = true,
+            PeerMsg::Unchoke => self.am_choking = false,
+            PeerMsg::Interested => self.am_interested = true,
+            PeerMsg::NotInterested => self.am_interested = false,
+            _ => {}
+        }
+    }
+
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::downloader::peer_msg::PeerMsg;
+
+    use super::ChokeState;
+
+    #[test]
+    fn test_choke_state() {
+        let mut state = ChokeState::default();
+        assert!(!state.can_request());
+
+        state.sent(&PeerMsg::Interested);
+        assert!(!state.can_request());
+        state.received(&PeerMsg::Unchoke);
+        assert!(state.can_request());
+
+        state.received(&PeerMsg::Choke);
+        assert!(!state.can_request());
+        state.received(&PeerMsg::Unchoke);
+        state.sent(&PeerMsg::NotInterested);
+        assert!(!state.can_request());
+
+        state.received(&PeerMsg::Interested);
+        state.sent(&PeerMsg::Unchoke);
+        assert_eq!(
+            state,
+            ChokeState {
+                am_choking: false,
+                am_interested: false,
+                peer_choking: false,
+                peer_interested: true,
+            }
+        );
+    }
+}
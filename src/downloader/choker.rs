@@ -0,0 +1,225 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use tokio::time::{interval, Instant};
+
+use crate::random::Rng;
+
+use super::{
+    peer::PeerCmd,
+    swarm::{PeerKey, Swarm},
+};
+
+const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+// every third rechoke, i.e. every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PeerStats {
+    pub interested: bool,
+    pub snubbed: bool,
+    // bytes per second
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+pub struct Choker {
+    regular_slots: usize,
+    optimistic: Option<PeerKey>,
+    round: u32,
+    rng: Rng,
+}
+
+impl Choker {
+    // One of the slots is kept for the optimistic unchoke.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            regular_slots: slots.saturating_sub(1),
+            optimistic: None,
+            round: 0,
+            rng: Rng::default(),
+        }
+    }
+
+    // Returns the peers to unchoke, everyone else gets choked.
+    pub fn rechoke(
+        &mut self,
+        peers: &HashMap<PeerKey, PeerStats>,
+        seeding: bool,
+    ) -> HashSet<PeerKey> {
+        let rate = |stats: &PeerStats| match seeding {
+            true => stats.upload_rate,
+            false => stats.download_rate,
+        };
+        let mut candidates: Vec<_> = peers
+            .iter()
+            .filter(|(_, stats)| stats.interested && !stats.snubbed)
+            .collect();
+        candidates.sort_by_key(|(key, stats)| (Reverse(rate(stats)), **key));
+        let mut unchoked: HashSet<_> = candidates
+            .into_iter()
+            .take(self.regular_slots)
+            .map(|(key, _)| *key)
+            .collect();
+
+        let rotate = self.round.is_multiple_of(OPTIMISTIC_ROUNDS);
+        self.round += 1;
+        let current = self.optimistic;
+        let keep = current.filter(|key| {
+            !rotate
+                && !unchoked.contains(key)
+                && peers.get(key).is_some_and(|stats| stats.interested)
+        });
+        self.optimistic = keep.or_else(|| {
+            let choked: Vec<_> = peers
+                .iter()
+                .filter(|(key, stats)| stats.interested && !unchoked.contains(*key))
+                .map(|(key, _)| *key)
+                .collect();
+            // prefer someone new when rotating
+            let others: Vec<_> = (choked.iter().copied())
+                .filter(|key| Some(*key) != current)
+                .collect();
+            (self.rng.choose(&others))
+                .or_else(|| self.rng.choose(&choked))
+                .copied()
+        });
+        unchoked.extend(self.optimistic);
+        unchoked
+    }
+}
+
+pub async fn run_choker(swarm: Arc<Swarm>) {
+    let snub = swarm.config().timeouts.snub;
+    let mut choker = Choker::new(swarm.config().upload_slots);
+    let mut last = HashMap::new();
+    let mut interval = interval(RECHOKE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        let transfers = swarm.get_transfers();
+        let stats = transfers
+            .iter()
+            .map(|(key, transfer)| {
+                let (downloaded, uploaded) = last.get(key).copied().unwrap_or_default();
+                let stats = PeerStats {
+                    interested: transfer.peer_interested,
+                    snubbed: transfer.am_interested && now >= transfer.last_block + snub,
+                    download_rate: (transfer.downloaded - downloaded) / RECHOKE_INTERVAL.as_secs(),
+                    upload_rate: (transfer.uploaded - uploaded) / RECHOKE_INTERVAL.as_secs(),
+                };
+                (*key, stats)
+            })
+            .collect();
+        last = transfers
+            .into_iter()
+            .map(|(key, transfer)| (key, (transfer.downloaded, transfer.uploaded)))
+            .collect();
+
+        let seeding = swarm.picker().is_finished();
+        let unchoked = choker.rechoke(&stats, seeding);
+        for key in stats.keys() {
+            let cmd = match unchoked.contains(key) {
+                true => PeerCmd::Unchoke,
+                false => PeerCmd::Choke,
+            };
+            swarm.send(*key, cmd);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use super::{Choker, PeerStats};
+
+    fn peer(download_rate: u64, upload_rate: u64) -> PeerStats {
+        PeerStats {
+            interested: true,
+            snubbed: false,
+            download_rate,
+            upload_rate,
+        }
+    }
+
+    fn regular(unchoked: HashSet<usize>, optimistic: Option<usize>) -> Vec<usize> {
+        let mut keys: Vec<_> = unchoked
+            .into_iter()
+            .filter(|key| Some(*key) != optimistic)
+            .collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn test_unchoke_fastest() {
+        let peers = HashMap::from([
+            (0, peer(10, 50)),
+            (1, peer(40, 10)),
+            (2, peer(30, 20)),
+            (3, peer(20, 40)),
+            (4, peer(50, 30)),
+        ]);
+        let mut choker = Choker::new(3);
+
+        let unchoked = choker.rechoke(&peers, false);
+        assert_eq!(unchoked.len(), 3);
+        let optimistic = choker.optimistic.unwrap();
+        assert!([0, 2, 3].contains(&optimistic));
+        assert_eq!(regular(unchoked, Some(optimistic)), [1, 4]);
+
+        // seeding ranks by what we upload to them
+        let unchoked = choker.rechoke(&peers, true);
+        assert_eq!(regular(unchoked, choker.optimistic), [0, 3]);
+    }
+
+    #[test]
+    fn test_not_interested_and_snubbed() {
+        let mut peers = HashMap::from([(0, peer(50, 0)), (1, peer(40, 0)), (2, peer(30, 0))]);
+        peers.get_mut(&0).unwrap().snubbed = true;
+        peers.get_mut(&1).unwrap().interested = false;
+        let mut choker = Choker::new(2);
+
+        // the snubbed peer only qualifies for the optimistic slot
+        let unchoked = choker.rechoke(&peers, false);
+        assert_eq!(choker.optimistic, Some(0));
+        assert_eq!(unchoked, HashSet::from([0, 2]));
+    }
+
+    #[test]
+    fn test_optimistic_rotation() {
+        let peers = HashMap::from([(0, peer(50, 0)), (1, peer(0, 0)), (2, peer(0, 0))]);
+        let mut choker = Choker::new(2);
+
+        choker.rechoke(&peers, false);
+        let first = choker.optimistic.unwrap();
+        assert_ne!(first, 0);
+        for _ in 0..2 {
+            let unchoked = choker.rechoke(&peers, false);
+            assert_eq!(unchoked, HashSet::from([0, first]));
+        }
+        let unchoked = choker.rechoke(&peers, false);
+        let second = choker.optimistic.unwrap();
+        assert_ne!(second, first);
+        assert_eq!(unchoked, HashSet::from([0, second]));
+    }
+
+    #[test]
+    fn test_optimistic_replaced_when_gone() {
+        let mut peers = HashMap::from([(0, peer(50, 0)), (1, peer(0, 0))]);
+        let mut choker = Choker::new(2);
+
+        choker.rechoke(&peers, false);
+        assert_eq!(choker.optimistic, Some(1));
+
+        peers.remove(&1);
+        peers.insert(2, peer(0, 0));
+        let unchoked = choker.rechoke(&peers, false);
+        assert_eq!(unchoked, HashSet::from([0, 2]));
+    }
+}
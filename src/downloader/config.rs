@@ -0,0 +1,95 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use crate::random::random_u64;
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // random per client, so two of them don't mistake each other for
+    // themselves
+    pub peer_id: String,
+    pub timeouts: Timeouts,
+    // keep serving peers once the download is complete
+    pub seed: bool,
+    pub listen_addr: SocketAddr,
+    // connected to besides what the tracker returns
+    pub peers: Vec<SocketAddr>,
+    pub max_peers: usize,
+    pub upload_slots: usize,
+    // size of the allowed fast set we grant (BEP 6)
+    pub allowed_fast: usize,
+    // peer exchange (BEP 11), always off for private torrents
+    pub pex: bool,
+    // Mainline DHT (BEP 5), always off for private torrents. Like LSD, uTP
+    // and encryption it is opt-in, so plain downloads only talk to the
+    // tracker's peers over TCP.
+    pub dht: bool,
+    // bootstrap nodes, besides those in the torrent
+    pub dht_nodes: Vec<(String, u16)>,
+    // local service discovery (BEP 14), always off for private torrents
+    pub lsd: bool,
+    // uTP (BEP 29), accepted on the listen port and tried when TCP fails
+    pub utp: bool,
+    // message stream encryption, for outgoing and incoming connections
+    pub encryption: Encryption,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            peer_id: format!("-CR0001-{:012}", random_u64() % 1_000_000_000_000),
+            timeouts: Timeouts::default(),
+            seed: false,
+            listen_addr: (Ipv4Addr::UNSPECIFIED, 6881).into(),
+            peers: vec![],
+            max_peers: 50,
+            upload_slots: 4,
+            allowed_fast: 10,
+            pex: true,
+            dht: false,
+            dht_nodes: vec![
+                ("router.bittorrent.com".to_string(), 6881),
+                ("dht.transmissionbt.com".to_string(), 6881),
+            ],
+            lsd: false,
+            utp: false,
+            encryption: Encryption::Plaintext,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encryption {
+    Plaintext,
+    // encrypted if the peer can, plaintext otherwise
+    Prefer,
+    Require,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    pub unchoke: Duration,
+    pub request: Duration,
+    pub keep_alive: Duration,
+    pub idle: Duration,
+    // no block for this long while we are interested counts as a snub
+    pub snub: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(10),
+            handshake: Duration::from_secs(10),
+            unchoke: Duration::from_secs(60),
+            request: Duration::from_secs(60),
+            keep_alive: Duration::from_secs(120),
+            idle: Duration::from_secs(180),
+            snub: Duration::from_secs(30),
+        }
+    }
+}
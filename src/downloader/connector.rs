@@ -0,0 +1,87 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+// spreads out connection attempts to peers we hear about
+const CONNECT_INTERVAL: Duration = Duration::from_millis(200);
+const MAX_PENDING: usize = 500;
+
+// Decides when to connect to the peers learned while downloading, so a
+// burst of addresses doesn't open lots of sockets at once. An address is
+// only ever tried once.
+pub struct Connector {
+    known: HashSet<SocketAddr>,
+    pending: VecDeque<SocketAddr>,
+    next_connect: Instant,
+}
+
+impl Connector {
+    pub fn new() -> Self {
+        Self {
+            known: HashSet::new(),
+            pending: VecDeque::new(),
+            next_connect: Instant::now(),
+        }
+    }
+
+    // For peers connected to right away, e.g. the tracker's.
+    pub fn add_connected(&mut self, addr: SocketAddr) {
+        self.known.insert(addr);
+    }
+
+    pub fn add(&mut self, addr: SocketAddr) {
+        if self.pending.len() < MAX_PENDING && self.known.insert(addr) {
+            self.pending.push_back(addr);
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    pub fn next_deadline(&self) -> Instant {
+        self.next_connect
+    }
+
+    pub fn pop(&mut self) -> Option<SocketAddr> {
+        let now = Instant::now();
+        if now < self.next_connect {
+            return None;
+        }
+        let addr = self.pending.pop_front()?;
+        self.next_connect = now + CONNECT_INTERVAL;
+        Some(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::time::{sleep_until, Instant};
+
+    use super::{Connector, CONNECT_INTERVAL};
+
+    #[tokio::test]
+    async fn test_connector() {
+        let addr = |port| SocketAddr::from(([127, 0, 0, 1], port));
+        let mut connector = Connector::new();
+        connector.add_connected(addr(1));
+        for port in [1, 2, 3, 2] {
+            connector.add(addr(port));
+        }
+
+        let start = Instant::now();
+        assert_eq!(connector.pop(), Some(addr(2)));
+        assert_eq!(connector.pop(), None);
+        assert!(connector.has_pending());
+        sleep_until(connector.next_deadline()).await;
+        assert!(start.elapsed() >= CONNECT_INTERVAL);
+        assert_eq!(connector.pop(), Some(addr(3)));
+        assert!(!connector.has_pending());
+    }
+}
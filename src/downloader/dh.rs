@@ -0,0 +1,133 @@
+use std::{cmp::Ordering, sync::LazyLock};
+
+use crate::random::random_bytes;
+
+// the 768 bit prime MSE uses, with 2 as generator
+const PRIME: &str = "FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F14374FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+pub const KEY_LEN: usize = 96;
+const LIMBS: usize = KEY_LEN / 8;
+
+// little-endian 64 bit limbs
+type Num = [u64; LIMBS];
+
+static P: LazyLock<Num> = LazyLock::new(|| {
+    let bytes: [u8; KEY_LEN] = hex::decode(PRIME).unwrap().try_into().unwrap();
+    from_bytes(&bytes)
+});
+
+// Diffie-Hellman for message stream encryption: 160 bit private keys are
+// enough, the spec says.
+pub fn generate() -> ([u8; 20], [u8; KEY_LEN]) {
+    let private: [u8; 20] = random_bytes();
+    let mut generator = [0; LIMBS];
+    generator[0] = 2;
+    (private, to_bytes(&pow_mod(&generator, &private)))
+}
+
+pub fn get_secret(private: &[u8; 20], public: &[u8; KEY_LEN]) -> [u8; KEY_LEN] {
+    let mut public = from_bytes(public);
+    if compare(&public, &P) != Ordering::Less {
+        public = sub(&public, &P).0;
+    }
+    to_bytes(&pow_mod(&public, private))
+}
+
+fn from_bytes(bytes: &[u8; KEY_LEN]) -> Num {
+    let mut num = [0; LIMBS];
+    for (idx, chunk) in bytes.rchunks(8).enumerate() {
+        num[idx] = u64::from_be_bytes(chunk.try_into().unwrap());
+    }
+    num
+}
+
+fn to_bytes(num: &Num) -> [u8; KEY_LEN] {
+    let mut bytes = [0; KEY_LEN];
+    for (idx, chunk) in bytes.rchunks_mut(8).enumerate() {
+        chunk.copy_from_slice(&num[idx].to_be_bytes());
+    }
+    bytes
+}
+
+fn compare(a: &Num, b: &Num) -> Ordering {
+    a.iter().rev().cmp(b.iter().rev())
+}
+
+fn add(a: &Num, b: &Num) -> (Num, bool) {
+    let mut sum = [0; LIMBS];
+    let mut carry = false;
+    for idx in 0..LIMBS {
+        let (limb, c1) = a[idx].overflowing_add(b[idx]);
+        let (limb, c2) = limb.overflowing_add(carry as u64);
+        sum[idx] = limb;
+        carry = c1 || c2;
+    }
+    (sum, carry)
+}
+
+fn sub(a: &Num, b: &Num) -> (Num, bool) {
+    let mut diff = [0; LIMBS];
+    let mut borrow = false;
+    for idx in 0..LIMBS {
+        let (limb, b1) = a[idx].overflowing_sub(b[idx]);
+        let (limb, b2) = limb.overflowing_sub(borrow as u64);
+        diff[idx] = limb;
+        borrow = b1 || b2;
+    }
+    (diff, borrow)
+}
+
+// both below P
+fn add_mod(a: &Num, b: &Num) -> Num {
+    let (sum, carry) = add(a, b);
+    match carry || compare(&sum, &P) != Ordering::Less {
+        true => sub(&sum, &P).0,
+        false => sum,
+    }
+}
+
+// double and add, one bit of a at a time
+fn mul_mod(a: &Num, b: &Num) -> Num {
+    let mut product = [0; LIMBS];
+    for bit in (0..LIMBS * 64).rev() {
+        product = add_mod(&product, &product);
+        if a[bit / 64] >> (bit % 64) & 1 == 1 {
+            product = add_mod(&product, b);
+        }
+    }
+    product
+}
+
+fn pow_mod(base: &Num, exponent: &[u8]) -> Num {
+    let mut result = [0; LIMBS];
+    result[0] = 1;
+    for byte in exponent {
+        for bit in (0..8).rev() {
+            result = mul_mod(&result, &result);
+            if byte >> bit & 1 == 1 {
+                result = mul_mod(&result, base);
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, get_secret, pow_mod, to_bytes, LIMBS};
+
+    #[test]
+    fn test_dh() {
+        let mut generator = [0; LIMBS];
+        generator[0] = 2;
+        let private: Vec<u8> = (1..=20).collect();
+        let public = to_bytes(&pow_mod(&generator, &private));
+        assert_eq!(hex::encode(public), "96e112dab29e8c5272accb9b17b26887ce54a144a4e3b697c7d159b7a817e556b0918db2b4c658e02a87f7e5fb14b18a553e084cbf3dad2d30f16596ccb982d406258c61b30c5c1dae2ddc60bdbd48d79896312aad63238c39e1a633821eb693");
+
+        let (a_private, a_public) = generate();
+        let (b_private, b_public) = generate();
+        assert_eq!(
+            get_secret(&a_private, &b_public),
+            get_secret(&b_private, &a_public)
+        );
+    }
+}
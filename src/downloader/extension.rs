@@ -0,0 +1,263 @@
+use std::{collections::BTreeMap, net::IpAddr};
+
+use anyhow::{bail, Context, Result};
+
+use crate::bencoding::Value;
+
+pub const HANDSHAKE_ID: u8 = 0;
+pub const CLIENT_VERSION: &str = concat!("bittorrent-starter-rust ", env!("CARGO_PKG_VERSION"));
+
+// The extension handshake (BEP 10). Ids in `m` are what the sender wants to
+// receive its messages with, 0 means the extension is disabled.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtensionHandshake {
+    pub m: BTreeMap<String, u8>,
+    pub v: Option<String>,
+    pub p: Option<u16>,
+    pub reqq: Option<u32>,
+    pub yourip: Option<IpAddr>,
+    // ut_metadata (BEP 9)
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtensionHandshake {
+    pub fn encode(&self) -> Vec<u8> {
+        let m = self
+            .m
+            .iter()
+            .map(|(name, id)| (name.as_str(), Value::from(*id as i64)));
+        let mut entries = vec![("m", Value::dict(m))];
+        if let Some(v) = &self.v {
+            entries.push(("v", v.as_str().into()));
+        }
+        if let Some(p) = self.p {
+            entries.push(("p", (p as i64).into()));
+        }
+        if let Some(reqq) = self.reqq {
+            entries.push(("reqq", (reqq as i64).into()));
+        }
+        if let Some(yourip) = self.yourip {
+            let bytes = match yourip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            entries.push(("yourip", bytes.into()));
+        }
+        if let Some(metadata_size) = self.metadata_size {
+            entries.push(("metadata_size", (metadata_size as i64).into()));
+        }
+        Value::dict(entries).encode()
+    }
+
+    // Unknown keys and values of the wrong type are ignored, as the BEP asks.
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let value = Value::decode(payload)?;
+        let m = value
+            .get("m")
+            .and_then(Value::as_dict)
+            .context("extension handshake without m")?
+            .iter()
+            .filter_map(|(name, id)| {
+                let name = String::from_utf8(name.clone()).ok()?;
+                let id = u8::try_from(id.as_integer()?).ok()?;
+                Some((name, id))
+            })
+            .collect();
+        let integer = |key| value.get(key).and_then(Value::as_integer);
+        let yourip = value
+            .get("yourip")
+            .and_then(Value::as_bytes)
+            .and_then(|bytes| match bytes.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(bytes).ok()?)),
+                _ => None,
+            });
+        Ok(Self {
+            m,
+            v: value.get("v").and_then(Value::as_str).map(String::from),
+            p: integer("p").and_then(|p| u16::try_from(p).ok()),
+            reqq: integer("reqq").and_then(|reqq| u32::try_from(reqq).ok()),
+            yourip,
+            metadata_size: integer("metadata_size").and_then(|size| usize::try_from(size).ok()),
+        })
+    }
+}
+
+// An extension speaking over message id 20. There is one instance per
+// connection; whatever it returns is sent to the peer under its name.
+pub trait Extension: Send {
+    fn name(&self) -> &'static str;
+
+    fn prepare_handshake(&self, _handshake: &mut ExtensionHandshake) {}
+
+    fn on_handshake(&mut self, _handshake: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>>;
+
+    // Called about once a minute, for extensions that send on their own.
+    fn tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        Ok(vec![])
+    }
+}
+
+// Routes extension messages of one connection to the registered modules.
+// Our ids are the module's position in the registry, starting at 1.
+pub struct Extensions {
+    modules: Vec<Box<dyn Extension>>,
+    peer_ids: BTreeMap<String, u8>,
+}
+
+impl Extensions {
+    pub fn new(modules: Vec<Box<dyn Extension>>) -> Self {
+        Self {
+            modules,
+            peer_ids: BTreeMap::new(),
+        }
+    }
+
+    pub fn get_handshake(&self) -> ExtensionHandshake {
+        let mut handshake = ExtensionHandshake {
+            m: self
+                .modules
+                .iter()
+                .enumerate()
+                .map(|(idx, module)| (module.name().to_string(), idx as u8 + 1))
+                .collect(),
+            v: Some(CLIENT_VERSION.to_string()),
+            ..Default::default()
+        };
+        for module in &self.modules {
+            module.prepare_handshake(&mut handshake);
+        }
+        handshake
+    }
+
+    // Returns the messages to send, as (extended message id, payload).
+    pub fn on_handshake(&mut self, handshake: &ExtensionHandshake) -> Result<Vec<(u8, Vec<u8>)>> {
+        // a later handshake may update individual ids
+        for (name, id) in &handshake.m {
+            self.peer_ids.insert(name.clone(), *id);
+        }
+        let mut out = vec![];
+        for idx in 0..self.modules.len() {
+            let payloads = self.modules[idx].on_handshake(handshake)?;
+            out.extend(self.address(idx, payloads));
+        }
+        Ok(out)
+    }
+
+    pub fn tick(&mut self) -> Result<Vec<(u8, Vec<u8>)>> {
+        let mut out = vec![];
+        for idx in 0..self.modules.len() {
+            let payloads = self.modules[idx].tick()?;
+            out.extend(self.address(idx, payloads));
+        }
+        Ok(out)
+    }
+
+    pub fn on_message(&mut self, id: u8, payload: &[u8]) -> Result<Vec<(u8, Vec<u8>)>> {
+        let idx = (id as usize).wrapping_sub(1);
+        let Some(module) = self.modules.get_mut(idx) else {
+            // not something we announced, so the peer is confused
+            bail!("unknown extended message id {}", id);
+        };
+        let payloads = module.on_message(payload).with_context(|| module.name())?;
+        Ok(self.address(idx, payloads))
+    }
+
+    fn address(&self, idx: usize, payloads: Vec<Vec<u8>>) -> Vec<(u8, Vec<u8>)> {
+        let name = self.modules[idx].name();
+        match self.peer_ids.get(name) {
+            Some(id) if *id != 0 => payloads.into_iter().map(|payload| (*id, payload)).collect(),
+            // the peer doesn't speak this extension
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::{IpAddr, Ipv4Addr},
+    };
+
+    use anyhow::Result;
+
+    use super::{Extension, ExtensionHandshake, Extensions};
+
+    struct Echo;
+
+    impl Extension for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+
+        fn on_handshake(&mut self, _handshake: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![b"hello".to_vec()])
+        }
+
+        fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+            Ok(vec![payload.to_vec()])
+        }
+    }
+
+    #[test]
+    fn test_handshake_round_trip() {
+        let handshake = ExtensionHandshake {
+            m: BTreeMap::from([("ut_pex".to_string(), 1), ("ut_metadata".to_string(), 2)]),
+            v: Some("test 1.0".to_string()),
+            p: Some(6881),
+            reqq: Some(250),
+            yourip: Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))),
+            metadata_size: Some(1234),
+        };
+        let encoded = handshake.encode();
+        assert!(encoded
+            .starts_with(b"d1:md11:ut_metadatai2e6:ut_pexi1ee13:metadata_sizei1234e1:pi6881e"));
+        assert_eq!(ExtensionHandshake::decode(&encoded).unwrap(), handshake);
+    }
+
+    #[test]
+    fn test_decode_lenient() {
+        let handshake =
+            ExtensionHandshake::decode(b"d1:md1:ai300e1:bi3ee1:pi-1e6:youripi1e1:x1:ye").unwrap();
+        assert_eq!(handshake.m, BTreeMap::from([("b".to_string(), 3)]));
+        assert_eq!(handshake.p, None);
+        assert_eq!(handshake.yourip, None);
+
+        assert!(ExtensionHandshake::decode(b"d1:pi1ee").is_err());
+    }
+
+    #[test]
+    fn test_routing() {
+        let mut extensions = Extensions::new(vec![Box::new(Echo)]);
+        assert_eq!(extensions.get_handshake().m["echo"], 1);
+
+        // the peer doesn't support it yet, so nothing goes out
+        assert!(extensions.on_message(1, b"x").unwrap().is_empty());
+
+        let theirs = ExtensionHandshake {
+            m: BTreeMap::from([("echo".to_string(), 7)]),
+            ..Default::default()
+        };
+        let out = extensions.on_handshake(&theirs).unwrap();
+        assert_eq!(out, [(7, b"hello".to_vec())]);
+        assert_eq!(
+            extensions.on_message(1, b"x").unwrap(),
+            [(7, b"x".to_vec())]
+        );
+        assert!(extensions.on_message(2, b"x").is_err());
+        assert!(extensions.on_message(0, b"x").is_err());
+
+        // disabled later on
+        let theirs = ExtensionHandshake {
+            m: BTreeMap::from([("echo".to_string(), 0)]),
+            ..Default::default()
+        };
+        extensions.on_handshake(&theirs).unwrap();
+        assert!(extensions.on_message(1, b"x").unwrap().is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+use anyhow::{Context, Result};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    spawn,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+
+use crate::utp::UtpSocket;
+
+use super::{config::Config, mse, peer::Peer, swarm::Swarm};
+
+pub type Torrents = HashMap<[u8; 20], Arc<Swarm>>;
+
+pub async fn listen(listener: TcpListener, torrents: Arc<Torrents>, config: Config) {
+    // once the limit is reached, new connections wait in the backlog
+    let limit = Arc::new(Semaphore::new(config.max_peers));
+    loop {
+        let permit = limit.clone().acquire_owned().await.unwrap();
+        let (stream, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                eprintln!("accept failed: {}", err);
+                continue;
+            }
+        };
+        spawn(serve(
+            stream,
+            addr,
+            torrents.clone(),
+            config.clone(),
+            permit,
+        ));
+    }
+}
+
+pub async fn listen_utp(utp: Arc<UtpSocket>, torrents: Arc<Torrents>, config: Config) {
+    // once the limit is reached, new connections wait in the backlog
+    let limit = Arc::new(Semaphore::new(config.max_peers));
+    loop {
+        let permit = limit.clone().acquire_owned().await.unwrap();
+        let Ok((stream, addr)) = utp.accept().await else {
+            return;
+        };
+        spawn(serve(
+            stream,
+            addr,
+            torrents.clone(),
+            config.clone(),
+            permit,
+        ));
+    }
+}
+
+async fn serve<S>(
+    stream: S,
+    addr: SocketAddr,
+    torrents: Arc<Torrents>,
+    config: Config,
+    permit: OwnedSemaphorePermit,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    if let Err(err) = accept(stream, addr, &torrents, &config).await {
+        eprintln!("dropped incoming peer {}: {:#}", addr, err);
+    }
+    drop(permit);
+}
+
+async fn accept<S>(stream: S, addr: SocketAddr, torrents: &Torrents, config: &Config) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let info_hashes: Vec<_> = torrents.keys().copied().collect();
+    let stream = timeout(
+        config.timeouts.handshake,
+        mse::accept(stream, &info_hashes, config.encryption),
+    )
+    .await
+    .context("encryption handshake timed out")??;
+    let mut peer = Peer::from_stream(addr, stream, config.timeouts);
+    let handshake = peer
+        .accept_handshake(&config.peer_id, |info_hash| {
+            torrents.contains_key(info_hash)
+        })
+        .await?;
+    let swarm = torrents[&handshake.info_hash].clone();
+    peer.download(&handshake, swarm).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+
+    use tempfile::tempdir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        spawn,
+        sync::mpsc::channel,
+        task::yield_now,
+    };
+
+    use crate::{
+        downloader::{
+            block_hashes::BlockHashes, config::Config, parts::Piece, piece_picker::PiecePicker,
+            swarm::Swarm,
+        },
+        storage::Storage,
+    };
+
+    use super::listen;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    async fn connect(addr: SocketAddr, config: &Config, peer_id: &[u8]) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut msg = [0; 68];
+        msg[..20].copy_from_slice(b"\x13BitTorrent protocol");
+        msg[28..48].copy_from_slice(&INFO_HASH);
+        msg[48..].copy_from_slice(peer_id);
+        stream.write_all(&msg).await.unwrap();
+        stream.read_exact(&mut msg).await.unwrap();
+        assert_eq!(&msg[48..], config.peer_id.as_bytes());
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_listen() {
+        let dir = tempdir().unwrap();
+        let storage = Storage::open(dir.path().join("file"), 4, 4).await.unwrap();
+        let picker = PiecePicker::new(&[Piece::new(0, 4, [0; 20])], 4);
+        let (block_resp_sender, _) = channel(1);
+        let config = Config::default();
+        let swarm = Swarm::new(
+            picker,
+            BlockHashes::default(),
+            storage,
+            vec![block_resp_sender],
+            config.clone(),
+            vec![],
+        );
+        let swarm = Arc::new(swarm);
+        let torrents = Arc::new(HashMap::from([(INFO_HASH, swarm.clone())]));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(listen(listener, torrents, config.clone()));
+
+        let _first = connect(addr, &config, b"-RM0001-000000000000").await;
+        while swarm.get_no_peers() < 1 {
+            yield_now().await;
+        }
+        let mut second = connect(addr, &config, b"-RM0001-000000000000").await;
+        let mut buf = [0; 1];
+        assert_eq!(second.read(&mut buf).await.unwrap(), 0);
+        assert_eq!(swarm.get_no_peers(), 1);
+
+        let _third = connect(addr, &config, b"-RM0001-000000000001").await;
+        while swarm.get_no_peers() < 2 {
+            yield_now().await;
+        }
+    }
+}
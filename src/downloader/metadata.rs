@@ -0,0 +1,270 @@
+use std::{
+    mem::take,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use sha1::{Digest, Sha1};
+use tokio::time::timeout;
+
+use crate::bencoding::Value;
+
+use super::{
+    config::Timeouts,
+    extension::{Extension, ExtensionHandshake, Extensions, HANDSHAKE_ID},
+    peer::{Handshake, PeerReader, PeerWriter},
+    peer_msg::PeerMsg,
+};
+
+pub const UT_METADATA: &str = "ut_metadata";
+const PIECE_LEN: usize = 16 * 1024;
+const MAX_METADATA_SIZE: usize = 16 * 1024 * 1024;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+// Sends the info dict to peers that ask for it (BEP 9) and, as long as it
+// is unknown, fetches it one piece after the other.
+pub struct UtMetadata {
+    info_hash: [u8; 20],
+    metadata: Arc<OnceLock<Vec<u8>>>,
+    // the size the peer announced, 0 while we aren't fetching
+    size: usize,
+    buf: Vec<u8>,
+}
+
+impl UtMetadata {
+    pub fn new(info_hash: [u8; 20], metadata: Arc<OnceLock<Vec<u8>>>) -> Self {
+        Self {
+            info_hash,
+            metadata,
+            size: 0,
+            buf: vec![],
+        }
+    }
+
+    fn on_data(&mut self, piece: usize, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        if self.size == 0 || self.metadata.get().is_some() {
+            return Ok(vec![]);
+        }
+        ensure!(
+            piece == self.buf.len() / PIECE_LEN,
+            "unexpected metadata piece {}",
+            piece
+        );
+        ensure!(
+            data.len() == PIECE_LEN.min(self.size - self.buf.len()),
+            "invalid metadata piece length"
+        );
+        self.buf.extend_from_slice(data);
+        if self.buf.len() < self.size {
+            return Ok(vec![message(REQUEST, piece + 1, &[])]);
+        }
+
+        let hash: [u8; 20] = Sha1::digest(&self.buf).into();
+        ensure!(hash == self.info_hash, "metadata hash mismatch");
+        // another connection may have been faster
+        let _ = self.metadata.set(take(&mut self.buf));
+        Ok(vec![])
+    }
+
+    fn on_request(&self, piece: usize) -> Vec<Vec<u8>> {
+        let Some(metadata) = self.metadata.get() else {
+            return vec![message(REJECT, piece, &[])];
+        };
+        let start = piece.saturating_mul(PIECE_LEN);
+        if start >= metadata.len() {
+            return vec![message(REJECT, piece, &[])];
+        }
+        let end = metadata.len().min(start + PIECE_LEN);
+        let header = Value::dict([
+            ("msg_type", DATA.into()),
+            ("piece", (piece as i64).into()),
+            ("total_size", (metadata.len() as i64).into()),
+        ]);
+        let mut payload = header.encode();
+        payload.extend_from_slice(&metadata[start..end]);
+        vec![payload]
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &'static str {
+        UT_METADATA
+    }
+
+    fn prepare_handshake(&self, handshake: &mut ExtensionHandshake) {
+        handshake.metadata_size = self.metadata.get().map(Vec::len);
+    }
+
+    fn on_handshake(&mut self, handshake: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+        if self.metadata.get().is_some() || self.size != 0 {
+            return Ok(vec![]);
+        }
+        let Some(size) = handshake.metadata_size else {
+            return Ok(vec![]);
+        };
+        ensure!(
+            size > 0 && size <= MAX_METADATA_SIZE,
+            "invalid metadata size: {}",
+            size
+        );
+        self.size = size;
+        Ok(vec![message(REQUEST, 0, &[])])
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (header, len) = Value::decode_prefix(payload)?;
+        let integer = |key| header.get(key).and_then(Value::as_integer);
+        let msg_type = integer("msg_type").context("ut_metadata message without msg_type")?;
+        let piece = integer("piece")
+            .and_then(|piece| usize::try_from(piece).ok())
+            .context("ut_metadata message without piece")?;
+        match msg_type {
+            REQUEST => Ok(self.on_request(piece)),
+            DATA => self.on_data(piece, &payload[len..]),
+            REJECT if self.size != 0 && self.metadata.get().is_none() => {
+                bail!("metadata request rejected")
+            }
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+fn message(msg_type: i64, piece: usize, data: &[u8]) -> Vec<u8> {
+    let header = Value::dict([
+        ("msg_type", msg_type.into()),
+        ("piece", (piece as i64).into()),
+    ]);
+    let mut payload = header.encode();
+    payload.extend_from_slice(data);
+    payload
+}
+
+// Speaks just enough of the protocol to get the info dict from one peer.
+// Returns once anyone has stored it in `metadata`.
+pub async fn fetch(
+    mut reader: PeerReader,
+    mut writer: PeerWriter,
+    handshake: &Handshake,
+    metadata: Arc<OnceLock<Vec<u8>>>,
+    timeouts: Timeouts,
+) -> Result<()> {
+    ensure!(
+        handshake.supports_extensions(),
+        "peer doesn't support extensions"
+    );
+    let module = UtMetadata::new(handshake.info_hash, metadata.clone());
+    let mut extensions = Extensions::new(vec![Box::new(module)]);
+    if handshake.supports_fast() {
+        PeerMsg::HaveNone.write(&mut writer).await?;
+    }
+    let payload = extensions.get_handshake().encode();
+    PeerMsg::Extended {
+        id: HANDSHAKE_ID,
+        payload,
+    }
+    .write(&mut writer)
+    .await?;
+
+    while metadata.get().is_none() {
+        let msg = timeout(timeouts.request, PeerMsg::read(&mut reader))
+            .await
+            .context("metadata request timed out")??;
+        let PeerMsg::Extended { id, payload } = msg else {
+            continue;
+        };
+        let out = if id == HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::decode(&payload)?;
+            ensure!(
+                handshake.m.get(UT_METADATA).is_some_and(|id| *id != 0)
+                    && handshake.metadata_size.is_some(),
+                "peer can't send metadata"
+            );
+            extensions.on_handshake(&handshake)?
+        } else {
+            extensions.on_message(id, &payload)?
+        };
+        for (id, payload) in out {
+            PeerMsg::Extended { id, payload }.write(&mut writer).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, OnceLock};
+
+    use sha1::{Digest, Sha1};
+
+    use crate::{bencoding::Value, downloader::extension::ExtensionHandshake};
+
+    use super::{Extension, UtMetadata, PIECE_LEN};
+
+    fn new_metadata() -> Vec<u8> {
+        (0..PIECE_LEN + 100).map(|idx| idx as u8).collect()
+    }
+
+    #[test]
+    fn test_fetch_and_serve() {
+        let metadata = new_metadata();
+        let info_hash = Sha1::digest(&metadata).into();
+        let mut seeder = UtMetadata::new(info_hash, Arc::new(OnceLock::from(metadata.clone())));
+        let fetched = Arc::new(OnceLock::new());
+        let mut leecher = UtMetadata::new(info_hash, fetched.clone());
+
+        let mut handshake = ExtensionHandshake::default();
+        seeder.prepare_handshake(&mut handshake);
+        assert_eq!(handshake.metadata_size, Some(metadata.len()));
+        assert!(seeder.on_handshake(&handshake).unwrap().is_empty());
+
+        let mut msgs = leecher.on_handshake(&handshake).unwrap();
+        for _ in 0..2 {
+            assert_eq!(msgs.len(), 1);
+            let data = seeder.on_message(&msgs[0]).unwrap();
+            msgs = leecher.on_message(&data[0]).unwrap();
+        }
+        assert!(msgs.is_empty());
+        assert_eq!(fetched.get(), Some(&metadata));
+
+        let request = Value::dict([("msg_type", 0.into()), ("piece", 2.into())]);
+        let reject = seeder.on_message(&request.encode()).unwrap();
+        assert_eq!(reject[0], b"d8:msg_typei2e5:piecei2ee");
+    }
+
+    #[test]
+    fn test_hash_mismatch() {
+        let metadata = new_metadata();
+        let mut seeder = UtMetadata::new([0; 20], Arc::new(OnceLock::from(metadata.clone())));
+        let fetched = Arc::new(OnceLock::new());
+        let mut leecher = UtMetadata::new([0; 20], fetched.clone());
+
+        let handshake = ExtensionHandshake {
+            metadata_size: Some(metadata.len()),
+            ..Default::default()
+        };
+        let request = leecher.on_handshake(&handshake).unwrap();
+        let data = seeder.on_message(&request[0]).unwrap();
+        let request = leecher.on_message(&data[0]).unwrap();
+        let data = seeder.on_message(&request[0]).unwrap();
+        let err = leecher.on_message(&data[0]).unwrap_err();
+        assert_eq!(err.to_string(), "metadata hash mismatch");
+        assert!(fetched.get().is_none());
+    }
+
+    #[test]
+    fn test_reject() {
+        let mut leecher = UtMetadata::new([0; 20], Arc::new(OnceLock::new()));
+        let handshake = ExtensionHandshake {
+            metadata_size: Some(10),
+            ..Default::default()
+        };
+        leecher.on_handshake(&handshake).unwrap();
+        let err = leecher
+            .on_message(b"d8:msg_typei2e5:piecei0ee")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "metadata request rejected");
+    }
+}
@@ -1,16 +1,16 @@
 //  Concurrent tasks:
 //
-//  -------------piece-req-------------
+//  -------------piece-picker----------
 //  |                                 |
 //  |                    ---------------------------
-//  |                    |                         |
+//  |                    |   (rarest first, blocks)|
 //  |                    V                         V
 //  |           |-----------------|       |-----------------|
 //  |           |      Peer       |       |      Peer       |
 //  |           |-----------------|       |-----------------|
-//  |           | request_writer  |       | request_writer  |
+//  |           |     session     |       |     session     |
+//  |           |        ^        |       |        ^        |
 //  |           |        |        |       |        |        |
-//  |           |        V        |       |        V        |
 //  |           | response_reader |       | response_reader |
 //  |           |-----------------|       |-----------------|
 //  |               a    b    c               a    b    c
@@ -41,99 +41,665 @@
 //                              | combiner |
 //                              |----------|
 
+mod allowed_fast;
+mod bitfield;
+mod block_hashes;
+mod choke_state;
+mod choker;
+pub mod config;
+mod connector;
+mod dh;
+mod extension;
+mod listener;
+mod metadata;
+mod mse;
 pub mod parts;
 pub mod peer;
 mod peer_msg;
+mod pex;
 mod piece_combiner;
+mod piece_picker;
 mod piece_validator;
+mod rc4;
+mod resume;
+mod session;
+mod swarm;
+pub mod verify;
+mod web_seed;
 
-use async_channel::unbounded;
+use std::{
+    collections::{HashMap, HashSet},
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
 use tokio::{
+    fs,
+    net::{TcpListener, UdpSocket},
     runtime::Runtime,
+    select,
+    signal::ctrl_c,
     spawn,
     sync::mpsc::{channel, unbounded_channel},
+    task::JoinSet,
+    time::{sleep_until, Instant},
 };
 
 use crate::{
+    dht::Dht,
+    lsd::{Lsd, GROUP_V4, GROUP_V6},
+    magnet::Magnet,
     metainfo::Metainfo,
+    storage::Storage,
     tracker::{get_peers, QueryParams},
+    utp::UtpSocket,
 };
+use bitfield::Bitfield;
+use block_hashes::BlockHashes;
+use choker::run_choker;
+use config::Config;
+use connector::Connector;
+use listener::{listen, listen_utp, Torrents};
 use parts::Piece;
 use peer::Peer;
-use piece_combiner::piece_combiner;
+use piece_combiner::{piece_combiner, save};
+use piece_picker::PiecePicker;
 use piece_validator::piece_validator;
+use resume::{check_pieces, Resume};
+use swarm::Swarm;
+use web_seed::{run_web_seed, WebSeed};
 
-pub fn download(output_file_path: &str, metainfo: &Metainfo, pieces: Vec<Piece>) {
-    let query_params = QueryParams {
-        info_hash: &metainfo.get_info_hash(),
-        peer_id: "00112233445566778899",
-        port: 6881,
-        uploaded: 0,
-        downloaded: 0,
-        left: metainfo.info.length,
-        compact: 1,
+// how often the DHT is asked for peers again, which announces us as well
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+const LSD_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// for clients on the network to connect once they heard of us
+const LSD_WAIT: Duration = Duration::from_secs(15);
+
+pub fn download(
+    output_file_path: &str,
+    metainfo: &Metainfo,
+    pieces: Vec<Piece>,
+    config: &Config,
+) -> Result<()> {
+    let config = &Config {
+        pex: config.pex && !metainfo.info.private,
+        dht: config.dht && !metainfo.info.private,
+        lsd: config.lsd && !metainfo.info.private,
+        ..config.clone()
     };
-    let peer_addrs = get_peers(metainfo.announce, query_params);
+    let info_hash = metainfo.get_info_hash();
+    let peer_addrs = find_peers(
+        &[metainfo.announce],
+        &info_hash,
+        metainfo.info.length,
+        config,
+    );
 
-    let rt = Runtime::new().unwrap();
+    let rt = Runtime::new()?;
     rt.block_on(async {
-        let (piece_req_sender, piece_req_receiver) = unbounded();
-        for piece in &pieces {
-            piece_req_sender.send(piece.into()).await.unwrap();
-        }
+        let block_size = 16 * 1024;
+        let picker = PiecePicker::new(&pieces, block_size);
+        let existing = (fs::metadata(output_file_path).await).is_ok_and(|meta| meta.len() > 0);
+        let storage = Storage::open(
+            output_file_path,
+            metainfo.info.length,
+            metainfo.info.piece_length,
+        )
+        .await?;
+        // only hash what is there if the resume file can't tell
+        let resume = Resume::new(output_file_path, info_hash);
+        let have = match resume.load(&storage).await {
+            Some(have) => have,
+            None if existing => {
+                let have = check_pieces(&storage, &pieces).await;
+                resume.save(&storage, &have).await?;
+                have
+            }
+            None => Bitfield::new(pieces.len()),
+        };
 
         let (block_resp_senders, block_resp_receivers): (Vec<_>, Vec<_>) =
             (0..pieces.len()).map(|_| channel(1)).unzip();
 
+        let swarm = Swarm::new(
+            picker,
+            BlockHashes::new(&metainfo.info),
+            storage,
+            block_resp_senders,
+            config.clone(),
+            metainfo.info.encoded.to_vec(),
+        );
+        let swarm = Arc::new(swarm);
+        let resumed: Vec<_> = (0..pieces.len() as u32)
+            .filter(|idx| have.has(*idx))
+            .collect();
+        for &idx in &resumed {
+            swarm.picker().piece_done(idx);
+            swarm.add_have(idx);
+        }
+        if !resumed.is_empty() {
+            eprintln!("resuming with {} of {} pieces", resumed.len(), pieces.len());
+        }
+
+        let torrents = Arc::new(HashMap::from([(info_hash, swarm.clone())]));
+        let listener_task = match TcpListener::bind(config.listen_addr).await {
+            Ok(listener) => Some(spawn(listen(listener, torrents.clone(), config.clone()))),
+            Err(err) => {
+                eprintln!("not accepting peers on {}: {}", config.listen_addr, err);
+                None
+            }
+        };
+
+        let choker_task = spawn(run_choker(swarm.clone()));
+
+        // the DHT and uTP share the UDP port
+        let udp = match config.dht || config.utp {
+            true => match UdpSocket::bind(config.listen_addr).await {
+                Ok(socket) => Some(Arc::new(socket)),
+                Err(err) => {
+                    eprintln!("no DHT or uTP on {}: {}", config.listen_addr, err);
+                    None
+                }
+            },
+            false => None,
+        };
+        let dht = (udp.as_ref())
+            .filter(|_| config.dht)
+            .map(|socket| Arc::new(Dht::new(socket.clone())));
+        let utp = (udp.as_ref())
+            .filter(|_| config.utp)
+            .map(|socket| Arc::new(UtpSocket::new(socket.clone())));
+        let udp_task = udp.map(|socket| spawn(route_datagrams(socket, dht.clone(), utp.clone())));
+        let utp_listener_task =
+            (utp.clone()).map(|utp| spawn(listen_utp(utp, torrents.clone(), config.clone())));
+        let port = config.listen_addr.port();
+        let mut lsd_tasks = vec![];
+        if config.lsd {
+            for group in [GROUP_V4, GROUP_V6] {
+                match Lsd::bind(group, port).await {
+                    Ok(lsd) => lsd_tasks.push(spawn(run_lsd(lsd, torrents.clone()))),
+                    Err(err) => eprintln!("no LSD on {}: {:#}", group, err),
+                }
+            }
+        }
+        let lsd_wait = Instant::now() + LSD_WAIT;
+        let mut lookups = JoinSet::new();
+        if let Some(dht) = dht.clone() {
+            let mut nodes: Vec<_> = (metainfo.nodes.iter())
+                .map(|(host, port)| (host.to_string(), *port))
+                .collect();
+            nodes.extend(config.dht_nodes.iter().cloned());
+            lookups.spawn(async move {
+                dht.bootstrap(&nodes).await;
+                if dht.get_no_nodes() == 0 {
+                    eprintln!("DHT bootstrap found no nodes");
+                }
+                dht.get_peers(info_hash, Some(port)).await
+            });
+        }
+        let mut next_lookup = Instant::now() + DHT_INTERVAL;
+
         let (piece_resp_sender, piece_resp_receiver) = unbounded_channel();
 
-        let mut request_writer_tasks = vec![];
-        let mut response_reader_tasks = vec![];
+        let mut web_seed_tasks = JoinSet::new();
+        let timeout = config.timeouts.request;
+        let url_list = (metainfo.url_list.iter())
+            .map(|url| WebSeed::new(url, &metainfo.info, timeout));
+        let httpseeds = (metainfo.httpseeds.iter())
+            .map(|url| WebSeed::new_http_seed(url, info_hash, timeout));
+        for web_seed in url_list.chain(httpseeds) {
+            match web_seed {
+                Ok(web_seed) => {
+                    web_seed_tasks.spawn(run_web_seed(web_seed, swarm.clone()));
+                }
+                Err(err) => eprintln!("{:#}", err),
+            }
+        }
 
-        let peer_id = "00112233445566778899";
-        let block_size = 16 * 1024;
-        for addr in &peer_addrs {
-            let mut peer = Peer::create(addr).await;
-            peer.do_handshake(&metainfo.get_info_hash(), peer_id).await;
-            peer.init_download().await;
-            let (request_writer_task, response_reader_task) = peer.start_download_tasks(
-                piece_req_receiver.clone(),
-                block_resp_senders.clone(),
-                block_size,
-            );
-            request_writer_tasks.push(request_writer_task);
-            response_reader_tasks.push(response_reader_task);
+        let mut connector = Connector::new();
+        let mut peer_tasks = JoinSet::new();
+        for addr in peer_addrs {
+            connector.add_connected(addr);
+            peer_tasks.spawn(run_peer(addr, info_hash, swarm.clone(), utp.clone()));
         }
 
         let mut validator_tasks = vec![];
         for (block_receiver, piece) in block_resp_receivers.into_iter().zip(pieces) {
+            if have.has(piece.idx) {
+                continue;
+            }
             let task = spawn(piece_validator(
                 block_receiver,
-                piece_req_sender.clone(),
+                swarm.clone(),
                 piece_resp_sender.clone(),
                 piece,
             ));
             validator_tasks.push(task);
         }
 
-        let combiner_task = spawn(piece_combiner(
+        let mut combiner_task = spawn(piece_combiner(
             piece_resp_receiver,
-            metainfo.info.piece_length,
-            String::from(output_file_path),
+            swarm.clone(),
+            resume.clone(),
         ));
+        let mut combined = false;
 
-        drop(piece_req_sender);
         drop(piece_resp_sender);
 
-        for task in request_writer_tasks {
-            task.await.unwrap();
-        }
-        for task in response_reader_tasks {
-            task.await.unwrap();
+        // Incoming peers count as well, so only give up once nobody is left.
+        // A seed carries on, connecting to the peers it learns of.
+        loop {
+            let changed = swarm.changed();
+            let finished = swarm.picker().is_finished();
+            if finished && !config.seed {
+                break;
+            }
+            for addr in swarm.take_candidates() {
+                connector.add(addr);
+            }
+            let can_connect = connector.has_pending() && peer_tasks.len() < config.max_peers;
+            if can_connect {
+                if let Some(addr) = connector.pop() {
+                    peer_tasks.spawn(run_peer(addr, info_hash, swarm.clone(), utp.clone()));
+                }
+            }
+            if !finished
+                && peer_tasks.is_empty()
+                && swarm.get_no_peers() == 0
+                && !connector.has_pending()
+                && lookups.is_empty()
+                && web_seed_tasks.is_empty()
+                && (lsd_tasks.is_empty() || Instant::now() >= lsd_wait)
+            {
+                bail!("no peers left");
+            }
+            select! {
+                Some(result) = peer_tasks.join_next() => log_dropped(result.unwrap()),
+                // a failed write ends the download
+                result = &mut combiner_task, if !combined => {
+                    result.unwrap()?;
+                    combined = true;
+                }
+                Some(result) = lookups.join_next() => {
+                    for addr in result.unwrap() {
+                        connector.add(addr);
+                    }
+                    next_lookup = Instant::now() + DHT_INTERVAL;
+                }
+                _ = changed => {}
+                // the combiner saves now and then, this keeps the pieces since
+                _ = ctrl_c() => {
+                    save(&swarm, &resume).await;
+                    if finished {
+                        return Ok(());
+                    }
+                    bail!("interrupted");
+                }
+                _ = sleep_until(connector.next_deadline()), if can_connect => {}
+                _ = sleep_until(lsd_wait), if !lsd_tasks.is_empty() && Instant::now() < lsd_wait => {}
+                _ = sleep_until(next_lookup), if dht.is_some() && lookups.is_empty() => {
+                    let dht = dht.clone().unwrap();
+                    lookups.spawn(async move { dht.get_peers(info_hash, Some(port)).await });
+                }
+            }
         }
+
         for validator in validator_tasks {
             validator.await.unwrap();
         }
-        combiner_task.await.unwrap();
-    });
+        if !combined {
+            combiner_task.await.unwrap()?;
+        }
+
+        while let Some(result) = peer_tasks.join_next().await {
+            log_dropped(result.unwrap());
+        }
+        choker_task.abort();
+        let tasks = [listener_task, udp_task, utp_listener_task].into_iter().flatten();
+        for task in tasks.chain(lsd_tasks) {
+            task.abort();
+        }
+        Ok(())
+    })
+}
+
+// Gets the info dict from the peers of a magnet link (BEP 9). Trackerless
+// ones only have the DHT to go by.
+pub fn fetch_metadata(magnet: &Magnet, config: &Config) -> Result<Vec<u8>> {
+    let trackers: Vec<_> = magnet.trackers.iter().map(String::as_str).collect();
+    let peer_addrs = find_peers(&trackers, &magnet.info_hash, 0, config);
+
+    let rt = Runtime::new()?;
+    rt.block_on(async {
+        let dht = match config.dht {
+            true => match UdpSocket::bind(config.listen_addr).await {
+                Ok(socket) => {
+                    let socket = Arc::new(socket);
+                    let dht = Arc::new(Dht::new(socket.clone()));
+                    spawn(route_datagrams(socket, Some(dht.clone()), None));
+                    Some(dht)
+                }
+                Err(err) => {
+                    eprintln!("no DHT on {}: {}", config.listen_addr, err);
+                    None
+                }
+            },
+            false => None,
+        };
+        let mut lookups = JoinSet::new();
+        if let Some(dht) = dht {
+            let (nodes, info_hash) = (config.dht_nodes.clone(), magnet.info_hash);
+            lookups.spawn(async move {
+                dht.bootstrap(&nodes).await;
+                if dht.get_no_nodes() == 0 {
+                    eprintln!("DHT bootstrap found no nodes");
+                }
+                // we don't take connections, so nothing to announce
+                dht.get_peers(info_hash, None).await
+            });
+        }
+
+        let metadata = Arc::new(OnceLock::new());
+        let mut tasks = JoinSet::new();
+        let mut seen = HashSet::new();
+        let mut fetch = |addrs: Vec<SocketAddr>, tasks: &mut JoinSet<_>| {
+            for addr in addrs {
+                if seen.len() < config.max_peers && seen.insert(addr) {
+                    let task = fetch_from(addr, magnet.info_hash, metadata.clone(), config.clone());
+                    tasks.spawn(task);
+                }
+            }
+        };
+        fetch(peer_addrs, &mut tasks);
+        loop {
+            select! {
+                Some(result) = tasks.join_next() => {
+                    log_dropped(result.unwrap());
+                    if let Some(metadata) = metadata.get() {
+                        return Ok(metadata.clone());
+                    }
+                }
+                Some(result) = lookups.join_next() => fetch(result.unwrap(), &mut tasks),
+                else => bail!("no peer sent the metadata"),
+            }
+        }
+    })
+}
+
+// A tracker that fails is skipped, the others may still know peers.
+fn find_peers(
+    trackers: &[&str],
+    info_hash: &[u8; 20],
+    left: u64,
+    config: &Config,
+) -> Vec<SocketAddr> {
+    let mut peer_addrs = config.peers.clone();
+    for tracker in trackers.iter().filter(|tracker| !tracker.is_empty()) {
+        let query_params = QueryParams {
+            info_hash,
+            peer_id: &config.peer_id,
+            port: config.listen_addr.port() as i64,
+            uploaded: 0,
+            downloaded: 0,
+            left,
+            compact: 1,
+        };
+        match get_peers(tracker, query_params) {
+            Ok(peers) => peer_addrs.extend(peers.into_iter().map(SocketAddr::from)),
+            Err(err) => eprintln!("tracker {} failed: {:#}", tracker, err),
+        }
+    }
+    let mut seen = HashSet::new();
+    peer_addrs.retain(|addr| seen.insert(*addr));
+    peer_addrs
+}
+
+async fn fetch_from(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    metadata: Arc<OnceLock<Vec<u8>>>,
+    config: Config,
+) -> Result<()> {
+    let result = async {
+        let mut peer =
+            Peer::connect(&addr, &info_hash, config.encryption, None, config.timeouts).await?;
+        let handshake = peer.do_handshake(&info_hash, &config.peer_id).await?;
+        peer.fetch_metadata(&handshake, metadata).await
+    }
+    .await;
+    result.with_context(|| addr.to_string())
+}
+
+async fn run_peer(
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    swarm: Arc<Swarm>,
+    utp: Option<Arc<UtpSocket>>,
+) -> Result<()> {
+    let result = async {
+        let config = swarm.config();
+        let mut peer = Peer::connect(
+            &addr,
+            &info_hash,
+            config.encryption,
+            utp.as_ref(),
+            config.timeouts,
+        )
+        .await?;
+        let handshake = peer.do_handshake(&info_hash, &config.peer_id).await?;
+        peer.download(&handshake, swarm).await
+    }
+    .await;
+    result.with_context(|| addr.to_string())
+}
+
+// DHT messages are bencoded dicts, anything else may be uTP.
+async fn route_datagrams(
+    socket: Arc<UdpSocket>,
+    dht: Option<Arc<Dht>>,
+    utp: Option<Arc<UtpSocket>>,
+) {
+    let mut buf = vec![0; 2048];
+    loop {
+        let (len, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            // an ICMP error for something we sent earlier, or a signal
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    ErrorKind::ConnectionRefused
+                        | ErrorKind::ConnectionReset
+                        | ErrorKind::Interrupted
+                ) =>
+            {
+                continue
+            }
+            Err(err) => {
+                eprintln!("no more DHT or uTP, receiving failed: {}", err);
+                return;
+            }
+        };
+        let datagram = &buf[..len];
+        match (datagram.first(), &dht, &utp) {
+            (Some(b'd'), Some(dht), _) => dht.handle(datagram, from).await,
+            (_, _, Some(utp)) => utp.handle(datagram, from).await,
+            _ => {}
+        }
+    }
+}
+
+// Announces our torrents now and then, and passes on the clients on the
+// network that are on them too.
+async fn run_lsd(lsd: Lsd, torrents: Arc<Torrents>) {
+    let info_hashes: Vec<_> = torrents.keys().copied().collect();
+    let mut next_announce = Instant::now();
+    loop {
+        select! {
+            _ = sleep_until(next_announce) => {
+                if let Err(err) = lsd.announce(&info_hashes).await {
+                    eprintln!("LSD announce failed: {:#}", err);
+                }
+                next_announce = Instant::now() + LSD_INTERVAL;
+            }
+            Ok((addr, announced)) = lsd.recv() => {
+                for swarm in announced.iter().filter_map(|info_hash| torrents.get(info_hash)) {
+                    swarm.add_candidates([addr]);
+                }
+            }
+        }
+    }
+}
+
+fn log_dropped(result: Result<()>) {
+    if let Err(err) = result {
+        eprintln!("dropped peer: {:#}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs, net::TcpStream, sync::Arc, thread, time::Duration};
+
+    use tempfile::tempdir;
+    use tokio::{net::UdpSocket, runtime::Runtime, spawn};
+
+    use crate::{
+        bencoding::Value,
+        create::TorrentBuilder,
+        dht::Dht,
+        magnet::Magnet,
+        merkle::{get_block_hashes, get_file_root, get_piece_root},
+        metainfo::Metainfo,
+        random::random_u64,
+    };
+
+    use super::{download, fetch_metadata, route_datagrams, Config};
+
+    // A port of its own for each client, TCP and UDP alike.
+    fn get_addr() -> std::net::SocketAddr {
+        ([127, 0, 0, 1], 40000 + (random_u64() % 20000) as u16).into()
+    }
+
+    // A DHT node that only routes, for the others to bootstrap from.
+    fn start_dht_node(rt: &Runtime) -> (String, u16) {
+        rt.block_on(async {
+            let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+            let port = socket.local_addr().unwrap().port();
+            let dht = Arc::new(Dht::new(socket.clone()));
+            spawn(route_datagrams(socket, Some(dht), None));
+            ("127.0.0.1".to_string(), port)
+        })
+    }
+
+    // Seeds `data` for as long as the tests run.
+    fn seed(torrent: Vec<u8>, data: Vec<u8>, config: Config) {
+        thread::spawn(move || {
+            let dir = tempdir().unwrap();
+            let path = dir.path().join("data");
+            fs::write(&path, data).unwrap();
+            let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+            let pieces = metainfo.get_pieces().unwrap();
+            let config = Config {
+                seed: true,
+                ..config
+            };
+            download(path.to_str().unwrap(), &metainfo, pieces, &config).unwrap();
+        });
+    }
+
+    #[test]
+    fn test_trackerless_magnet() {
+        let rt = Runtime::new().unwrap();
+        let nodes = vec![start_dht_node(&rt)];
+        let dir = tempdir().unwrap();
+        let data: Vec<u8> = (0..100_000).map(|idx| (idx / 7) as u8).collect();
+        fs::write(dir.path().join("file"), &data).unwrap();
+        let torrent = TorrentBuilder::new(dir.path().join("file"))
+            .build()
+            .unwrap();
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        assert!(metainfo.announce.is_empty());
+
+        let config = Config {
+            listen_addr: get_addr(),
+            dht: true,
+            dht_nodes: nodes.clone(),
+            ..Default::default()
+        };
+        seed(torrent.clone(), data, config);
+
+        let info_hash = hex::encode(metainfo.get_info_hash());
+        let magnet = Magnet::parse(&format!("magnet:?xt=urn:btih:{}", info_hash)).unwrap();
+        let config = Config {
+            listen_addr: get_addr(),
+            dht: true,
+            dht_nodes: nodes,
+            ..Default::default()
+        };
+        // the seed announces itself once it has checked its data
+        let info = (0..20)
+            .find_map(|_| {
+                thread::sleep(Duration::from_millis(250));
+                fetch_metadata(&magnet, &config).ok()
+            })
+            .unwrap();
+        assert_eq!(info, metainfo.info.encoded);
+    }
+
+    #[test]
+    fn test_v2_download() {
+        // a file of one piece, then one of four, each starting on a piece
+        let piece_length = 32 * 1024;
+        let a: Vec<u8> = (0..20_000).map(|idx| (idx % 251) as u8).collect();
+        let b: Vec<u8> = (0..100_000).map(|idx| (idx / 7) as u8).collect();
+        let file = |data: &[u8]| {
+            let root = get_file_root(&get_block_hashes(data));
+            let file = Value::dict([
+                ("length", (data.len() as i64).into()),
+                ("pieces root", root.as_slice().into()),
+            ]);
+            (Value::dict([("", file)]), root)
+        };
+        let ((a_file, _), (b_file, b_root)) = (file(&a), file(&b));
+        let layer: Vec<_> = (b.chunks(piece_length))
+            .flat_map(|piece| get_piece_root(&get_block_hashes(piece), piece_length as u32))
+            .collect();
+        let info = Value::dict([
+            ("file tree", Value::dict([("a", a_file), ("b", b_file)])),
+            ("meta version", 2.into()),
+            ("name", "dir".into()),
+            ("piece length", (piece_length as i64).into()),
+        ]);
+        let layers = Value::Dict(BTreeMap::from([(b_root.to_vec(), layer.into())]));
+        let torrent = Value::dict([("info", info), ("piece layers", layers)]).encode();
+        let mut data = a.clone();
+        data.resize(piece_length, 0);
+        data.extend(&b);
+
+        let seed_addr = get_addr();
+        let config = Config {
+            listen_addr: seed_addr,
+            ..Default::default()
+        };
+        seed(torrent.clone(), data.clone(), config);
+        while TcpStream::connect(seed_addr).is_err() {
+            thread::sleep(Duration::from_millis(50));
+        }
+
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        assert!(metainfo.info.piece_hashes.is_empty());
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+        let config = Config {
+            listen_addr: get_addr(),
+            peers: vec![seed_addr],
+            ..Default::default()
+        };
+        let pieces = metainfo.get_pieces().unwrap();
+        download(path.to_str().unwrap(), &metainfo, pieces, &config).unwrap();
+        assert_eq!(fs::read(path).unwrap(), data);
+    }
 }
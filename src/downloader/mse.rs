@@ -0,0 +1,371 @@
+// Message stream encryption: a Diffie-Hellman key exchange, after which
+// both sides agree on RC4 or plaintext for the rest of the connection. It
+// only obfuscates, anyone knowing the info hash can follow along.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context as TaskContext, Poll},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+use crate::random::Rng;
+
+use super::{
+    config::Encryption,
+    dh::{self, KEY_LEN},
+    rc4::Rc4,
+};
+
+const PROTOCOL: &[u8; 20] = b"\x13BitTorrent protocol";
+// verification constant
+const VC: [u8; 8] = [0; 8];
+const CRYPTO_PLAINTEXT: u32 = 1;
+const CRYPTO_RC4: u32 = 2;
+const MAX_PAD: usize = 512;
+// the start of the RC4 output is weak
+const DISCARD: usize = 1024;
+
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+fn cipher(name: &[u8], secret: &[u8], info_hash: &[u8; 20]) -> Rc4 {
+    let mut rc4 = Rc4::new(&hash(&[name, secret, info_hash]));
+    rc4.apply(&mut [0; DISCARD]);
+    rc4
+}
+
+fn get_pad() -> Vec<u8> {
+    let mut rng = Rng::default();
+    let len = rng.below(MAX_PAD as u64 + 1);
+    (0..len).map(|_| rng.next_u64() as u8).collect()
+}
+
+fn get_provide(encryption: Encryption) -> u32 {
+    match encryption {
+        Encryption::Require => CRYPTO_RC4,
+        _ => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+    }
+}
+
+// Reads until the stream ends with the pattern, which has to come within
+// max_skip bytes.
+async fn sync<S: AsyncRead + Unpin>(stream: &mut S, pattern: &[u8], max_skip: usize) -> Result<()> {
+    let mut buf = Vec::with_capacity(max_skip + pattern.len());
+    while !buf.ends_with(pattern) {
+        ensure!(
+            buf.len() < max_skip + pattern.len(),
+            "encryption sync failed"
+        );
+        buf.push(stream.read_u8().await?);
+    }
+    Ok(())
+}
+
+// The outgoing side, the remote learns the info hash from us.
+pub async fn initiate<S>(
+    mut stream: S,
+    info_hash: &[u8; 20],
+    encryption: Encryption,
+) -> Result<CryptoStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (private, public) = dh::generate();
+    stream
+        .write_all(&[&public[..], &get_pad()].concat())
+        .await?;
+
+    let mut their_public = [0; KEY_LEN];
+    stream.read_exact(&mut their_public).await?;
+    ensure!(
+        &their_public[..20] != PROTOCOL,
+        "peer answered in plaintext"
+    );
+    let secret = dh::get_secret(&private, &their_public);
+    let mut encrypt = cipher(b"keyA", &secret, info_hash);
+    let mut decrypt = cipher(b"keyB", &secret, info_hash);
+
+    let mut msg = hash(&[b"req1", &secret]).to_vec();
+    let req2 = hash(&[b"req2", info_hash]);
+    let req3 = hash(&[b"req3", &secret]);
+    msg.extend(req2.iter().zip(req3).map(|(a, b)| a ^ b));
+    // no padding and no initial payload, the handshake follows later
+    let mut tail = [&VC[..], &get_provide(encryption).to_be_bytes(), &[0; 4]].concat();
+    encrypt.apply(&mut tail);
+    msg.extend(tail);
+    stream.write_all(&msg).await?;
+
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(&mut stream, &vc, MAX_PAD).await?;
+    let mut msg = [0; 6];
+    stream.read_exact(&mut msg).await?;
+    decrypt.apply(&mut msg);
+    let select = u32::from_be_bytes(msg[..4].try_into().unwrap());
+    let mut pad = vec![0; u16::from_be_bytes([msg[4], msg[5]]) as usize];
+    ensure!(pad.len() <= MAX_PAD, "padding too long");
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+
+    let ciphers = match select {
+        CRYPTO_RC4 => Some((encrypt, decrypt)),
+        CRYPTO_PLAINTEXT if encryption != Encryption::Require => None,
+        _ => bail!("invalid crypto method {}", select),
+    };
+    Ok(CryptoStream::new(stream, vec![], ciphers))
+}
+
+// The incoming side: plaintext and encrypted connections are told apart by
+// their first bytes, encrypted ones must be for one of our torrents.
+pub async fn accept<S>(
+    mut stream: S,
+    info_hashes: &[[u8; 20]],
+    encryption: Encryption,
+) -> Result<CryptoStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut their_public = [0; KEY_LEN];
+    stream.read_exact(&mut their_public[..20]).await?;
+    if &their_public[..20] == PROTOCOL {
+        ensure!(
+            encryption != Encryption::Require,
+            "plaintext connection refused"
+        );
+        return Ok(CryptoStream::new(stream, PROTOCOL.to_vec(), None));
+    }
+    ensure!(
+        encryption != Encryption::Plaintext,
+        "encrypted connection refused"
+    );
+    stream.read_exact(&mut their_public[20..]).await?;
+
+    let (private, public) = dh::generate();
+    stream
+        .write_all(&[&public[..], &get_pad()].concat())
+        .await?;
+    let secret = dh::get_secret(&private, &their_public);
+
+    sync(&mut stream, &hash(&[b"req1", &secret]), MAX_PAD).await?;
+    let mut msg = [0; 20];
+    stream.read_exact(&mut msg).await?;
+    let req3 = hash(&[b"req3", &secret]);
+    let info_hash = (info_hashes.iter())
+        .find(|info_hash| {
+            let req2 = hash(&[b"req2", &info_hash[..]]);
+            (req2.iter().zip(req3)).map(|(a, b)| a ^ b).eq(msg)
+        })
+        .context("unknown info hash")?;
+    let mut decrypt = cipher(b"keyA", &secret, info_hash);
+    let mut encrypt = cipher(b"keyB", &secret, info_hash);
+
+    let mut msg = [0; 14];
+    stream.read_exact(&mut msg).await?;
+    decrypt.apply(&mut msg);
+    ensure!(msg[..8] == VC, "invalid verification constant");
+    let provide = u32::from_be_bytes(msg[8..12].try_into().unwrap());
+    let mut pad = vec![0; u16::from_be_bytes([msg[12], msg[13]]) as usize];
+    ensure!(pad.len() <= MAX_PAD, "padding too long");
+    stream.read_exact(&mut pad).await?;
+    decrypt.apply(&mut pad);
+    let mut len = [0; 2];
+    stream.read_exact(&mut len).await?;
+    decrypt.apply(&mut len);
+    let mut initial = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut initial).await?;
+    decrypt.apply(&mut initial);
+
+    let select = match provide {
+        _ if provide & CRYPTO_RC4 != 0 => CRYPTO_RC4,
+        _ if provide & CRYPTO_PLAINTEXT != 0 && encryption == Encryption::Prefer => {
+            CRYPTO_PLAINTEXT
+        }
+        _ => bail!("no common crypto method"),
+    };
+    let mut msg = [&VC[..], &select.to_be_bytes(), &[0; 2]].concat();
+    encrypt.apply(&mut msg);
+    stream.write_all(&msg).await?;
+
+    let ciphers = (select == CRYPTO_RC4).then_some((encrypt, decrypt));
+    Ok(CryptoStream::new(stream, initial, ciphers))
+}
+
+pub struct CryptoStream<S> {
+    inner: S,
+    // read already, but not passed on yet
+    prefix: Vec<u8>,
+    // to encrypt and decrypt with, None for plaintext
+    ciphers: Option<(Rc4, Rc4)>,
+    // encrypted, but not written yet
+    pending: Vec<u8>,
+}
+
+impl<S: AsyncWrite + Unpin> CryptoStream<S> {
+    fn new(inner: S, prefix: Vec<u8>, ciphers: Option<(Rc4, Rc4)>) -> Self {
+        Self {
+            inner,
+            prefix,
+            ciphers,
+            pending: vec![],
+        }
+    }
+
+    fn poll_pending(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let len = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if len == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..len);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CryptoStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if !this.prefix.is_empty() {
+            let len = this.prefix.len().min(buf.remaining());
+            buf.put_slice(&this.prefix[..len]);
+            this.prefix.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        if let Some((_, decrypt)) = &mut this.ciphers {
+            decrypt.apply(&mut buf.filled_mut()[start..]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CryptoStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.ciphers.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // buf is only encrypted once we take it, so what is pending goes first
+        ready!(this.poll_pending(cx))?;
+        this.pending.extend_from_slice(buf);
+        if let Some((encrypt, _)) = &mut this.ciphers {
+            encrypt.apply(&mut this.pending);
+        }
+        match this.poll_pending(cx) {
+            Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+            _ => Poll::Ready(Ok(buf.len())),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_pending(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        spawn,
+    };
+
+    use crate::downloader::config::Encryption;
+
+    use super::{accept, initiate};
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+
+    // Runs both sides and checks data gets across, returns whether it was
+    // encrypted on the wire.
+    async fn connect(outgoing: Encryption, incoming: Encryption) -> Option<bool> {
+        let (local, remote) = duplex(64 * 1024);
+        let server = spawn(async move {
+            let mut stream = accept(remote, &[[1; 20], INFO_HASH], incoming).await.ok()?;
+            let mut msg = [0; 5];
+            stream.read_exact(&mut msg).await.unwrap();
+            assert_eq!(&msg, b"hello");
+            stream.write_all(b"world").await.unwrap();
+            Some(stream.ciphers.is_some())
+        });
+        let Ok(mut stream) = initiate(local, &INFO_HASH, outgoing).await else {
+            assert!(server.await.unwrap().is_none());
+            return None;
+        };
+        stream.write_all(b"hello").await.unwrap();
+        let mut msg = [0; 5];
+        stream.read_exact(&mut msg).await.unwrap();
+        assert_eq!(&msg, b"world");
+        let encrypted = server.await.unwrap()?;
+        assert_eq!(stream.ciphers.is_some(), encrypted);
+        Some(encrypted)
+    }
+
+    #[tokio::test]
+    async fn test_mse() {
+        assert_eq!(
+            connect(Encryption::Prefer, Encryption::Prefer).await,
+            Some(true)
+        );
+        assert_eq!(
+            connect(Encryption::Require, Encryption::Prefer).await,
+            Some(true)
+        );
+        assert_eq!(
+            connect(Encryption::Require, Encryption::Plaintext).await,
+            None
+        );
+
+        // plaintext handshakes still get through
+        let (mut local, remote) = duplex(1024);
+        let server = spawn(async move {
+            let mut stream = accept(remote, &[INFO_HASH], Encryption::Prefer)
+                .await
+                .unwrap();
+            let mut msg = [0; 21];
+            stream.read_exact(&mut msg).await.unwrap();
+            msg
+        });
+        local.write_all(b"\x13BitTorrent protocol!").await.unwrap();
+        assert_eq!(&server.await.unwrap(), b"\x13BitTorrent protocol!");
+
+        let (mut local, remote) = duplex(1024);
+        let server = spawn(accept(remote, &[INFO_HASH], Encryption::Require));
+        local.write_all(b"\x13BitTorrent protocol").await.unwrap();
+        let Err(err) = server.await.unwrap() else {
+            panic!("accepted");
+        };
+        assert_eq!(err.to_string(), "plaintext connection refused");
+
+        let (local, remote) = duplex(64 * 1024);
+        let server = spawn(accept(remote, &[[1; 20]], Encryption::Prefer));
+        spawn(initiate(local, &INFO_HASH, Encryption::Prefer));
+        let Err(err) = server.await.unwrap() else {
+            panic!("accepted");
+        };
+        assert_eq!(err.to_string(), "unknown info hash");
+    }
+}
@@ -1,10 +1,14 @@
+use sha1::{Digest, Sha1};
+
+use crate::merkle::{get_block_hashes, get_root};
+
 use super::peer_msg::PeerMsg;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct BlockReq {
-    piece_idx: u32,
-    begin: u32,
-    len: u32,
+    pub piece_idx: u32,
+    pub begin: u32,
+    pub len: u32,
 }
 
 impl BlockReq {
@@ -17,6 +21,24 @@ impl BlockReq {
     }
 }
 
+impl BlockReq {
+    pub fn to_cancel(self) -> PeerMsg {
+        PeerMsg::Cancel {
+            idx: self.piece_idx,
+            begin: self.begin,
+            length: self.len,
+        }
+    }
+
+    pub fn to_reject(self) -> PeerMsg {
+        PeerMsg::Reject {
+            idx: self.piece_idx,
+            begin: self.begin,
+            length: self.len,
+        }
+    }
+}
+
 impl From<BlockReq> for PeerMsg {
     fn from(block_req: BlockReq) -> Self {
         Self::Request {
@@ -27,6 +49,38 @@ impl From<BlockReq> for PeerMsg {
     }
 }
 
+// Asks for `length` hashes of a layer of a file's merkle tree, counted from
+// the leaves, plus the uncles up `proof_layers` layers (BEP 52).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HashReq {
+    pub pieces_root: [u8; 32],
+    pub base_layer: u32,
+    pub index: u32,
+    pub length: u32,
+    pub proof_layers: u32,
+}
+
+impl HashReq {
+    pub fn from_bytes(bytes: &[u8; 48]) -> Self {
+        let get_u32 = |pos: usize| u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        Self {
+            pieces_root: bytes[..32].try_into().unwrap(),
+            base_layer: get_u32(32),
+            index: get_u32(36),
+            length: get_u32(40),
+            proof_layers: get_u32(44),
+        }
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = self.pieces_root.to_vec();
+        for val in [self.base_layer, self.index, self.length, self.proof_layers] {
+            bytes.extend(val.to_be_bytes());
+        }
+        bytes
+    }
+}
+
 pub struct BlockResp {
     pub begin: u32,
     pub bytes: Vec<u8>,
@@ -38,43 +92,43 @@ impl BlockResp {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceHash {
+    V1([u8; 20]),
+    // a piece layer entry, or the root of a file of one piece, over a tree
+    // `width` blocks wide
+    V2 { root: [u8; 32], width: usize },
+    // No piece layer, as with magnets, so each block is checked against
+    // the file's merkle tree with hashes the peers send.
+    Blocks,
+}
+
 #[derive(Debug)]
 pub struct Piece {
     pub idx: u32,
+    // without the zeros up to the next v2 file
     pub len: u32,
-    pub hash: [u8; 20],
+    pub hash: PieceHash,
 }
 
 impl Piece {
     pub fn new(idx: u32, len: u32, hash: [u8; 20]) -> Self {
-        Self { idx, len, hash }
+        Self::new_v2(idx, len, PieceHash::V1(hash))
     }
-}
 
-impl From<&Piece> for PieceReq {
-    fn from(piece: &Piece) -> Self {
-        Self {
-            idx: piece.idx,
-            len: piece.len,
-        }
+    pub fn new_v2(idx: u32, len: u32, hash: PieceHash) -> Self {
+        Self { idx, len, hash }
     }
-}
 
-#[derive(Debug)]
-pub struct PieceReq {
-    pub idx: u32,
-    pub len: u32,
-}
-
-impl PieceReq {
-    pub fn into_block_reqs(&self, block_size: u32) -> Vec<BlockReq> {
-        let mut blocks: Vec<_> = (0..self.len)
-            .step_by(block_size as usize)
-            .map(|begin| BlockReq::new(self.idx, begin, block_size))
-            .collect();
-        let no_blocks = blocks.len();
-        blocks[no_blocks - 1].len = self.len - (no_blocks - 1) as u32 * block_size;
-        blocks
+    // None if only the blocks can tell.
+    pub fn check(&self, bytes: &[u8]) -> Option<bool> {
+        match self.hash {
+            PieceHash::V1(hash) => Some(<[u8; 20]>::from(Sha1::digest(bytes)) == hash),
+            PieceHash::V2 { root, width } => {
+                Some(get_root(&get_block_hashes(bytes), width, [0; 32]) == root)
+            }
+            PieceHash::Blocks => None,
+        }
     }
 }
 
@@ -1,126 +1,331 @@
-use std::net::SocketAddrV4;
+use std::{
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+};
 
-use async_channel::Receiver;
+use anyhow::{anyhow, ensure, Context, Result};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::{
-        tcp::{OwnedReadHalf, OwnedWriteHalf},
-        TcpStream,
-    },
-    spawn,
-    sync::mpsc::{channel, Sender},
-    task::JoinHandle,
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
+    net::TcpStream,
+    time::timeout,
+};
+
+use crate::utp::UtpSocket;
+
+use super::{
+    config::{Encryption, Timeouts},
+    metadata, mse,
+    parts::BlockReq,
+    session::Session,
+    swarm::Swarm,
 };
 
-use crate::downloader::peer_msg::PeerMsg;
+// the extension protocol, the fast extension and v2
+const RESERVED: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0x14];
+
+pub type PeerReader = BufReader<Box<dyn AsyncRead + Send + Unpin>>;
+pub type PeerWriter = BufWriter<Box<dyn AsyncWrite + Send + Unpin>>;
+
+trait PeerStream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> PeerStream for S {}
+
+#[derive(Debug)]
+pub struct Handshake {
+    pub info_hash: [u8; 20],
+    pub peer_id: Vec<u8>,
+    pub reserved: [u8; 8],
+}
+
+impl Handshake {
+    // BEP 6
+    pub fn supports_fast(&self) -> bool {
+        self.reserved[7] & 0x04 != 0
+    }
+
+    // BEP 10
+    pub fn supports_extensions(&self) -> bool {
+        self.reserved[5] & 0x10 != 0
+    }
+
+    // BEP 52, so it can send the hashes of v2 files
+    pub fn supports_v2(&self) -> bool {
+        self.reserved[7] & 0x10 != 0
+    }
+}
 
-use super::parts::{BlockResp, PieceReq};
+#[derive(Debug)]
+pub enum PeerCmd {
+    Cancel(BlockReq),
+    Have(u32),
+    Choke,
+    Unchoke,
+}
 
 pub struct Peer {
-    _addr: SocketAddrV4,
-    reader: BufReader<OwnedReadHalf>,
-    writer: BufWriter<OwnedWriteHalf>,
+    addr: SocketAddr,
+    // we connected to it, so addr is where it accepts connections
+    outgoing: bool,
+    reader: PeerReader,
+    writer: PeerWriter,
+    timeouts: Timeouts,
 }
 
 impl Peer {
-    fn new(
-        addr: SocketAddrV4,
-        reader: BufReader<OwnedReadHalf>,
-        writer: BufWriter<OwnedWriteHalf>,
+    pub async fn create(addr: &SocketAddr, timeouts: Timeouts) -> Result<Self> {
+        let stream = timeout(timeouts.connect, TcpStream::connect(addr))
+            .await
+            .context("connect timed out")??;
+        let mut peer = Self::from_stream(*addr, stream, timeouts);
+        peer.outgoing = true;
+        Ok(peer)
+    }
+
+    // Over TCP, or uTP if that fails, encrypted as far as the policy and
+    // the peer allow.
+    pub async fn connect(
+        addr: &SocketAddr,
+        info_hash: &[u8; 20],
+        encryption: Encryption,
+        utp: Option<&Arc<UtpSocket>>,
+        timeouts: Timeouts,
+    ) -> Result<Self> {
+        let stream = open(addr, utp, timeouts).await?;
+        let stream = match encryption {
+            Encryption::Plaintext => stream,
+            _ => {
+                let result = timeout(
+                    timeouts.handshake,
+                    mse::initiate(stream, info_hash, encryption),
+                )
+                .await
+                .context("encryption handshake timed out");
+                match result.and_then(|result| result) {
+                    Ok(stream) => Box::new(stream),
+                    Err(err) if encryption == Encryption::Require => return Err(err),
+                    // the peer may not know encryption, and drops the connection
+                    Err(_) => open(addr, utp, timeouts).await?,
+                }
+            }
+        };
+        let mut peer = Self::from_stream(*addr, stream, timeouts);
+        peer.outgoing = true;
+        Ok(peer)
+    }
+
+    pub fn from_stream(
+        addr: SocketAddr,
+        stream: impl AsyncRead + AsyncWrite + Send + 'static,
+        timeouts: Timeouts,
     ) -> Self {
+        let (read_half, write_half) = split(stream);
         Self {
-            _addr: addr,
-            reader,
-            writer,
+            addr,
+            outgoing: false,
+            reader: BufReader::new(Box::new(read_half)),
+            writer: BufWriter::new(Box::new(write_half)),
+            timeouts,
         }
     }
 
-    pub async fn create(addr: &SocketAddrV4) -> Self {
-        let stream = TcpStream::connect(addr).await.unwrap();
-        let (read_half, write_half) = stream.into_split();
-        let reader = BufReader::new(read_half);
-        let writer = BufWriter::new(write_half);
-        Self::new(*addr, reader, writer)
+    pub async fn do_handshake(
+        &mut self,
+        info_hash: &[u8; 20],
+        my_peer_id: &str,
+    ) -> Result<Handshake> {
+        timeout(self.timeouts.handshake, async {
+            self.write_handshake(info_hash, my_peer_id).await?;
+            let handshake = self.read_handshake().await?;
+            ensure!(handshake.info_hash == *info_hash, "info hash mismatch");
+            Ok(handshake)
+        })
+        .await
+        .context("handshake timed out")?
+    }
+
+    // The incoming side: the remote names the torrent first, and we only
+    // answer if it is one of ours.
+    pub async fn accept_handshake(
+        &mut self,
+        my_peer_id: &str,
+        is_known: impl FnOnce(&[u8; 20]) -> bool,
+    ) -> Result<Handshake> {
+        timeout(self.timeouts.handshake, async {
+            let handshake = self.read_handshake().await?;
+            ensure!(is_known(&handshake.info_hash), "unknown info hash");
+            self.write_handshake(&handshake.info_hash, my_peer_id)
+                .await?;
+            Ok(handshake)
+        })
+        .await
+        .context("handshake timed out")?
     }
 
-    pub async fn do_handshake(&mut self, info_hash: &[u8; 20], my_peer_id: &str) -> Vec<u8> {
+    async fn write_handshake(&mut self, info_hash: &[u8; 20], my_peer_id: &str) -> Result<()> {
         let mut msg: [u8; 68] = [0; 68];
         let proto = "BitTorrent protocol";
         msg[0] = proto.len() as u8;
         msg[1..20].copy_from_slice(proto.as_bytes());
+        msg[20..28].copy_from_slice(&RESERVED);
         msg[28..48].copy_from_slice(info_hash);
         msg[48..68].copy_from_slice(my_peer_id.as_bytes());
-        self.writer.write_all(&msg).await.unwrap();
-        self.writer.flush().await.unwrap();
+        self.writer.write_all(&msg).await?;
+        self.writer.flush().await?;
+        Ok(())
+    }
 
-        self.reader.read_exact(&mut msg).await.unwrap();
-        let peer_id = msg[msg.len() - 20..].to_vec();
-        peer_id
+    async fn read_handshake(&mut self) -> Result<Handshake> {
+        let mut msg: [u8; 68] = [0; 68];
+        self.reader.read_exact(&mut msg).await?;
+        ensure!(
+            &msg[..20] == b"\x13BitTorrent protocol",
+            "invalid handshake"
+        );
+        Ok(Handshake {
+            info_hash: msg[28..48].try_into().unwrap(),
+            peer_id: msg[48..68].to_vec(),
+            reserved: msg[20..28].try_into().unwrap(),
+        })
     }
 
-    pub async fn init_download(&mut self) {
-        loop {
-            let msg = PeerMsg::read(&mut self.reader).await;
-            if let PeerMsg::Bitfield(_) = msg {
-                break;
-            }
-            dbg!(msg);
-        }
-        PeerMsg::Interested.write(&mut self.writer).await;
+    pub async fn download(self, handshake: &Handshake, swarm: Arc<Swarm>) -> Result<()> {
+        let session = Session::new(self.writer, self.addr, self.outgoing, handshake, swarm)?;
+        session.run(self.reader).await
+    }
 
-        loop {
-            let msg = PeerMsg::read(&mut self.reader).await;
-            if let PeerMsg::Unchoke = msg {
-                break;
-            }
-            dbg!(msg);
-        }
+    pub async fn fetch_metadata(
+        self,
+        handshake: &Handshake,
+        metadata: Arc<OnceLock<Vec<u8>>>,
+    ) -> Result<()> {
+        metadata::fetch(self.reader, self.writer, handshake, metadata, self.timeouts).await
     }
+}
 
-    pub fn start_download_tasks(
-        mut self,
-        piece_req_receiver: Receiver<PieceReq>,
-        block_resp_senders: Vec<Sender<BlockResp>>,
-        block_size: u32,
-    ) -> (JoinHandle<()>, JoinHandle<()>) {
-        let buffer_size = 5;
-        let (token_sender, mut token_receiver) = channel::<()>(buffer_size);
-
-        let request_writer = spawn(async move {
-            loop {
-                let Ok(piece) = piece_req_receiver.recv().await else {
-                    return;
-                };
-
-                let blocks = piece.into_block_reqs(block_size);
-                for block in blocks {
-                    token_sender.send(()).await.unwrap();
-                    let req = PeerMsg::from(block);
-                    req.write(&mut self.writer).await;
-                }
-            }
-        });
+async fn open(
+    addr: &SocketAddr,
+    utp: Option<&Arc<UtpSocket>>,
+    timeouts: Timeouts,
+) -> Result<Box<dyn PeerStream>> {
+    let err = match timeout(timeouts.connect, TcpStream::connect(addr)).await {
+        Ok(Ok(stream)) => return Ok(Box::new(stream)),
+        Ok(Err(err)) => anyhow::Error::from(err),
+        Err(_) => anyhow!("connect timed out"),
+    };
+    // some peers only accept uTP
+    let Some(utp) = utp else {
+        return Err(err);
+    };
+    match timeout(timeouts.connect, utp.connect(*addr)).await {
+        Ok(Ok(stream)) => Ok(Box::new(stream)),
+        _ => Err(err),
+    }
+}
 
-        let response_reader = spawn(async move {
-            loop {
-                if token_receiver.recv().await.is_none() {
-                    return;
-                };
-
-                let (piece_idx, block_resp) = loop {
-                    let msg = PeerMsg::read(&mut self.reader).await;
-                    if let PeerMsg::Piece { idx, begin, bytes } = msg {
-                        break (idx, BlockResp::new(begin, bytes));
-                    }
-                };
-
-                block_resp_senders[piece_idx as usize]
-                    .send(block_resp)
-                    .await
-                    .unwrap();
-            }
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use tokio::{
+        io::{duplex, AsyncReadExt, AsyncWriteExt},
+        time::Instant,
+    };
+
+    use crate::downloader::config::Timeouts;
+
+    use super::Peer;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+    const PEER_ID: &str = "00112233445566778899";
+    const REMOTE_ID: &[u8] = b"-RM0001-000000000000";
+
+    fn get_peer() -> (Peer, tokio::io::DuplexStream) {
+        get_peer_with(Timeouts::default())
+    }
+
+    fn get_peer_with(timeouts: Timeouts) -> (Peer, tokio::io::DuplexStream) {
+        let (local, remote) = duplex(1024);
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let peer = Peer::from_stream(addr, local, timeouts);
+        (peer, remote)
+    }
+
+    async fn reply_handshake(remote: &mut tokio::io::DuplexStream) {
+        let mut msg = [0; 68];
+        remote.read_exact(&mut msg).await.unwrap();
+        remote.write_all(&msg).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_handshake_timeout() {
+        let timeouts = Timeouts {
+            handshake: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let (mut peer, _remote) = get_peer_with(timeouts);
+
+        let start = Instant::now();
+        let err = peer.do_handshake(&INFO_HASH, PEER_ID).await.unwrap_err();
+        assert_eq!(err.to_string(), "handshake timed out");
+        assert!(start.elapsed() >= timeouts.handshake);
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut peer, mut remote) = get_peer();
+        let remote_task = tokio::spawn(async move { reply_handshake(&mut remote).await });
+
+        let handshake = peer.do_handshake(&INFO_HASH, PEER_ID).await.unwrap();
+        assert_eq!(handshake.peer_id, PEER_ID.as_bytes());
+        assert!(handshake.supports_fast());
+        assert!(handshake.supports_extensions());
+        remote_task.await.unwrap();
+    }
+
+    fn remote_handshake(info_hash: &[u8; 20]) -> [u8; 68] {
+        let mut msg = [0; 68];
+        msg[..20].copy_from_slice(b"\x13BitTorrent protocol");
+        msg[28..48].copy_from_slice(info_hash);
+        msg[48..].copy_from_slice(REMOTE_ID);
+        msg
+    }
+
+    #[tokio::test]
+    async fn test_accept_handshake() {
+        let (mut peer, mut remote) = get_peer();
+        let remote_task = tokio::spawn(async move {
+            remote
+                .write_all(&remote_handshake(&INFO_HASH))
+                .await
+                .unwrap();
+            let mut reply = [0; 68];
+            remote.read_exact(&mut reply).await.unwrap();
+            reply
         });
 
-        (request_writer, response_reader)
+        let handshake = peer
+            .accept_handshake(PEER_ID, |info_hash| *info_hash == INFO_HASH)
+            .await
+            .unwrap();
+        assert_eq!(handshake.info_hash, INFO_HASH);
+        assert_eq!(handshake.peer_id, REMOTE_ID);
+        assert!(!handshake.supports_fast());
+        assert!(!handshake.supports_extensions());
+
+        let reply = remote_task.await.unwrap();
+        assert_eq!(reply[28..48], INFO_HASH);
+        assert_eq!(&reply[48..], PEER_ID.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_accept_handshake_unknown_torrent() {
+        let (mut peer, mut remote) = get_peer();
+        remote.write_all(&remote_handshake(&[1; 20])).await.unwrap();
+
+        let err = peer
+            .accept_handshake(PEER_ID, |info_hash| *info_hash == INFO_HASH)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "unknown info hash");
     }
 }
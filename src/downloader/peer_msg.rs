@@ -1,116 +1,320 @@
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter},
-    net::tcp::{OwnedReadHalf, OwnedWriteHalf},
-};
+use anyhow::{ensure, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-#[derive(Debug)]
+use super::parts::HashReq;
+
+// the largest block served or accepted
+pub const MAX_BLOCK_LEN: u32 = 128 * 1024;
+// Anything else is far smaller, a bitfield of a million pieces included.
+// Lengths come from the peer, so this bounds what gets allocated.
+const MAX_MSG_LEN: u32 = 4 * 1024 * 1024;
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum PeerMsg {
+    KeepAlive,
+    Choke,
     Unchoke,
     Interested,
-    #[allow(dead_code)]
+    NotInterested,
+    Have(u32),
     Bitfield(Vec<u8>),
     Request {
         idx: u32,
         begin: u32,
         length: u32,
     },
-    #[allow(dead_code)]
     Piece {
         idx: u32,
         begin: u32,
         bytes: Vec<u8>,
     },
-    #[allow(dead_code)]
-    Unknown(Vec<u8>),
+    Cancel {
+        idx: u32,
+        begin: u32,
+        length: u32,
+    },
+    // fast extension (BEP 6)
+    Suggest(u32),
+    HaveAll,
+    HaveNone,
+    Reject {
+        idx: u32,
+        begin: u32,
+        length: u32,
+    },
+    AllowedFast(u32),
+    // extension protocol (BEP 10)
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    // merkle tree hashes of v2 torrents (BEP 52)
+    HashRequest(HashReq),
+    Hashes {
+        req: HashReq,
+        hashes: Vec<[u8; 32]>,
+    },
+    HashReject(HashReq),
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
 }
 
 impl PeerMsg {
-    pub async fn read(reader: &mut BufReader<OwnedReadHalf>) -> Self {
-        let length = read_u32(reader).await;
+    pub async fn read(reader: &mut (impl AsyncRead + Unpin)) -> Result<Self> {
+        let length = read_u32(reader).await?;
         if length == 0 {
-            panic!("peer message length is 0");
+            return Ok(Self::KeepAlive);
         }
+        ensure!(length <= MAX_MSG_LEN, "message length: {}", length);
 
-        let id = read_byte(reader).await;
-        match id {
+        let id = read_byte(reader).await?;
+        let msg = match id {
+            0 => {
+                ensure!(length == 1, "choke length: {}", length);
+                Self::Choke
+            }
             1 => {
-                if length != 1 {
-                    panic!("unchoke length: {}", length);
-                }
+                ensure!(length == 1, "unchoke length: {}", length);
                 Self::Unchoke
             }
             2 => {
-                if length != 1 {
-                    panic!("interested length: {}", length);
-                }
+                ensure!(length == 1, "interested length: {}", length);
                 Self::Interested
             }
+            3 => {
+                ensure!(length == 1, "not interested length: {}", length);
+                Self::NotInterested
+            }
+            4 => {
+                ensure!(length == 5, "have length: {}", length);
+                Self::Have(read_u32(reader).await?)
+            }
             5 => {
                 let mut buf = vec![0; length as usize - 1];
-                reader.read_exact(&mut buf).await.unwrap();
+                reader.read_exact(&mut buf).await?;
                 Self::Bitfield(buf)
             }
             6 => {
-                if length != 13 {
-                    panic!("request length: {}", length);
-                }
+                ensure!(length == 13, "request length: {}", length);
                 Self::Request {
-                    idx: read_u32(reader).await,
-                    begin: read_u32(reader).await,
-                    length: read_u32(reader).await,
+                    idx: read_u32(reader).await?,
+                    begin: read_u32(reader).await?,
+                    length: read_u32(reader).await?,
                 }
             }
             7 => {
-                if length < 9 {
-                    panic!("piece length: {}", length);
-                }
-                let idx = read_u32(reader).await;
-                let begin = read_u32(reader).await;
+                ensure!(
+                    length >= 9 && length - 9 <= MAX_BLOCK_LEN,
+                    "piece length: {}",
+                    length
+                );
+                let idx = read_u32(reader).await?;
+                let begin = read_u32(reader).await?;
                 let mut bytes = vec![0; length as usize - 9];
-                reader.read_exact(&mut bytes).await.unwrap();
+                reader.read_exact(&mut bytes).await?;
                 Self::Piece { idx, begin, bytes }
             }
+            8 => {
+                ensure!(length == 13, "cancel length: {}", length);
+                Self::Cancel {
+                    idx: read_u32(reader).await?,
+                    begin: read_u32(reader).await?,
+                    length: read_u32(reader).await?,
+                }
+            }
+            0x0d => {
+                ensure!(length == 5, "suggest length: {}", length);
+                Self::Suggest(read_u32(reader).await?)
+            }
+            0x0e => {
+                ensure!(length == 1, "have all length: {}", length);
+                Self::HaveAll
+            }
+            0x0f => {
+                ensure!(length == 1, "have none length: {}", length);
+                Self::HaveNone
+            }
+            0x10 => {
+                ensure!(length == 13, "reject length: {}", length);
+                Self::Reject {
+                    idx: read_u32(reader).await?,
+                    begin: read_u32(reader).await?,
+                    length: read_u32(reader).await?,
+                }
+            }
+            0x11 => {
+                ensure!(length == 5, "allowed fast length: {}", length);
+                Self::AllowedFast(read_u32(reader).await?)
+            }
+            20 => {
+                ensure!(length >= 2, "extended length: {}", length);
+                let id = read_byte(reader).await?;
+                let mut payload = vec![0; length as usize - 2];
+                reader.read_exact(&mut payload).await?;
+                Self::Extended { id, payload }
+            }
+            21 => {
+                ensure!(length == 49, "hash request length: {}", length);
+                Self::HashRequest(read_hash_req(reader).await?)
+            }
+            22 => {
+                ensure!(
+                    length >= 49 && (length - 49).is_multiple_of(32),
+                    "hashes length: {}",
+                    length
+                );
+                let req = read_hash_req(reader).await?;
+                let mut bytes = vec![0; length as usize - 49];
+                reader.read_exact(&mut bytes).await?;
+                let hashes = (bytes.chunks(32))
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect();
+                Self::Hashes { req, hashes }
+            }
+            23 => {
+                ensure!(length == 49, "hash reject length: {}", length);
+                Self::HashReject(read_hash_req(reader).await?)
+            }
             _ => {
-                let mut buf = vec![0; length as usize - 1];
-                reader.read_exact(&mut buf).await.unwrap();
-                Self::Unknown(buf)
+                let mut payload = vec![0; length as usize - 1];
+                reader.read_exact(&mut payload).await?;
+                Self::Unknown { id, payload }
             }
-        }
+        };
+        Ok(msg)
     }
 
-    pub async fn write(&self, writer: &mut BufWriter<OwnedWriteHalf>) {
+    pub async fn write(&self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<()> {
         match self {
-            Self::Unchoke => unimplemented!(),
+            Self::KeepAlive => {
+                writer.write_all(&[0; 4]).await?;
+                writer.flush().await?;
+                Ok(())
+            }
+            Self::Choke => {
+                let id = 0;
+                write(writer, id, &[], &[]).await
+            }
+            Self::Unchoke => {
+                let id = 1;
+                write(writer, id, &[], &[]).await
+            }
             Self::Interested => {
                 let id = 2;
-                write(writer, id, &[0; 0]).await;
+                write(writer, id, &[], &[]).await
+            }
+            Self::NotInterested => {
+                let id = 3;
+                write(writer, id, &[], &[]).await
+            }
+            Self::Have(idx) => {
+                let id = 4;
+                write(writer, id, &[*idx], &[]).await
+            }
+            Self::Bitfield(bytes) => {
+                let id = 5;
+                write(writer, id, &[], bytes).await
             }
-            Self::Bitfield(_) => unimplemented!(),
             Self::Request { idx, begin, length } => {
                 let id = 6;
-                write(writer, id, &[*idx, *begin, *length]).await;
+                write(writer, id, &[*idx, *begin, *length], &[]).await
+            }
+            Self::Piece { idx, begin, bytes } => {
+                let id = 7;
+                write(writer, id, &[*idx, *begin], bytes).await
+            }
+            Self::Cancel { idx, begin, length } => {
+                let id = 8;
+                write(writer, id, &[*idx, *begin, *length], &[]).await
+            }
+            Self::Suggest(idx) => {
+                let id = 0x0d;
+                write(writer, id, &[*idx], &[]).await
+            }
+            Self::HaveAll => {
+                let id = 0x0e;
+                write(writer, id, &[], &[]).await
+            }
+            Self::HaveNone => {
+                let id = 0x0f;
+                write(writer, id, &[], &[]).await
+            }
+            Self::Reject { idx, begin, length } => {
+                let id = 0x10;
+                write(writer, id, &[*idx, *begin, *length], &[]).await
+            }
+            Self::AllowedFast(idx) => {
+                let id = 0x11;
+                write(writer, id, &[*idx], &[]).await
+            }
+            Self::Extended {
+                id: ext_id,
+                payload,
+            } => {
+                let id = 20;
+                let mut bytes = Vec::with_capacity(1 + payload.len());
+                bytes.push(*ext_id);
+                bytes.extend_from_slice(payload);
+                write(writer, id, &[], &bytes).await
             }
-            Self::Piece { .. } => unimplemented!(),
-            Self::Unknown { .. } => unimplemented!(),
+            Self::HashRequest(req) => {
+                let id = 21;
+                write(writer, id, &[], &req.to_bytes()).await
+            }
+            Self::Hashes { req, hashes } => {
+                let id = 22;
+                let bytes = [req.to_bytes(), hashes.concat()].concat();
+                write(writer, id, &[], &bytes).await
+            }
+            Self::HashReject(req) => {
+                let id = 23;
+                write(writer, id, &[], &req.to_bytes()).await
+            }
+            Self::Unknown { id, payload } => write(writer, *id, &[], payload).await,
         }
     }
+
+    pub fn is_fast(&self) -> bool {
+        matches!(
+            self,
+            Self::Suggest(_)
+                | Self::HaveAll
+                | Self::HaveNone
+                | Self::Reject { .. }
+                | Self::AllowedFast(_)
+        )
+    }
 }
 
-async fn read_byte(reader: &mut BufReader<OwnedReadHalf>) -> u8 {
+async fn read_byte(reader: &mut (impl AsyncRead + Unpin)) -> Result<u8> {
     let mut buf = [0; 1];
-    reader.read_exact(&mut buf).await.unwrap();
-    u8::from_be_bytes(buf.try_into().unwrap())
+    reader.read_exact(&mut buf).await?;
+    Ok(u8::from_be_bytes(buf))
 }
 
-async fn read_u32(reader: &mut BufReader<OwnedReadHalf>) -> u32 {
+async fn read_u32(reader: &mut (impl AsyncRead + Unpin)) -> Result<u32> {
     let mut buf = [0; 4];
-    reader.read_exact(&mut buf).await.unwrap();
-    u32::from_be_bytes(buf.try_into().unwrap())
+    reader.read_exact(&mut buf).await?;
+    Ok(u32::from_be_bytes(buf))
 }
 
-async fn write(writer: &mut BufWriter<OwnedWriteHalf>, id: u8, rest: &[u32]) {
+async fn read_hash_req(reader: &mut (impl AsyncRead + Unpin)) -> Result<HashReq> {
+    let mut buf = [0; 48];
+    reader.read_exact(&mut buf).await?;
+    Ok(HashReq::from_bytes(&buf))
+}
+
+async fn write(
+    writer: &mut (impl AsyncWrite + Unpin),
+    id: u8,
+    rest: &[u32],
+    payload: &[u8],
+) -> Result<()> {
     let mut msg = [0; 17];
-    let length = 1 + 4 * rest.len();
+    let header_length = 1 + 4 * rest.len();
+    let length = header_length + payload.len();
     msg[..4].copy_from_slice(&(length as u32).to_be_bytes());
     msg[4] = id;
     for (idx, val) in rest.iter().enumerate() {
@@ -118,6 +322,118 @@ async fn write(writer: &mut BufWriter<OwnedWriteHalf>, id: u8, rest: &[u32]) {
         let end = start + 4;
         msg[start..end].copy_from_slice(&val.to_be_bytes());
     }
-    writer.write_all(&msg[..4 + length]).await.unwrap();
-    writer.flush().await.unwrap();
+    writer.write_all(&msg[..4 + header_length]).await?;
+    writer.write_all(payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::downloader::parts::HashReq;
+
+    use super::{PeerMsg, MAX_BLOCK_LEN};
+
+    #[tokio::test]
+    async fn test_fast_messages() {
+        let msgs = [
+            PeerMsg::Suggest(3),
+            PeerMsg::HaveAll,
+            PeerMsg::HaveNone,
+            PeerMsg::Reject {
+                idx: 1,
+                begin: 2,
+                length: 3,
+            },
+            PeerMsg::AllowedFast(7),
+        ];
+        let mut bytes = vec![];
+        for msg in &msgs {
+            msg.write(&mut bytes).await.unwrap();
+        }
+        assert_eq!(&bytes[..9], [0, 0, 0, 5, 0x0d, 0, 0, 0, 3]);
+        assert_eq!(&bytes[9..14], [0, 0, 0, 1, 0x0e]);
+
+        let mut reader = &bytes[..];
+        for msg in msgs {
+            assert!(msg.is_fast());
+            assert_eq!(PeerMsg::read(&mut reader).await.unwrap(), msg);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extended() {
+        let msg = PeerMsg::Extended {
+            id: 3,
+            payload: b"de".to_vec(),
+        };
+        let mut bytes = vec![];
+        msg.write(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0, 0, 0, 4, 20, 3, b'd', b'e']);
+        assert_eq!(PeerMsg::read(&mut &bytes[..]).await.unwrap(), msg);
+    }
+
+    #[tokio::test]
+    async fn test_hash_messages() {
+        let req = HashReq {
+            pieces_root: [7; 32],
+            base_layer: 0,
+            index: 4,
+            length: 2,
+            proof_layers: 3,
+        };
+        let msgs = [
+            PeerMsg::HashRequest(req),
+            PeerMsg::Hashes {
+                req,
+                hashes: vec![[1; 32], [2; 32], [3; 32]],
+            },
+            PeerMsg::HashReject(req),
+        ];
+        let mut bytes = vec![];
+        for msg in &msgs {
+            msg.write(&mut bytes).await.unwrap();
+        }
+        assert_eq!(&bytes[..5], [0, 0, 0, 49, 21]);
+        assert_eq!(
+            &bytes[37..53],
+            [0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0, 0, 3]
+        );
+        assert_eq!(&bytes[53..58], [0, 0, 0, 145, 22]);
+
+        let mut reader = &bytes[..];
+        for msg in msgs {
+            assert!(!msg.is_fast());
+            assert_eq!(PeerMsg::read(&mut reader).await.unwrap(), msg);
+        }
+
+        // hashes come in whole
+        let bytes = [&[0, 0, 0, 50, 22][..], &[0; 49]].concat();
+        assert!(PeerMsg::read(&mut &bytes[..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_and_limits() {
+        let msg = PeerMsg::Unknown {
+            id: 99,
+            payload: vec![1, 2],
+        };
+        let mut bytes = vec![];
+        msg.write(&mut bytes).await.unwrap();
+        assert_eq!(bytes, [0, 0, 0, 3, 99, 1, 2]);
+        assert_eq!(PeerMsg::read(&mut &bytes[..]).await.unwrap(), msg);
+
+        // refused before anything is allocated or read
+        for header in [[0xff, 0xff, 0xff, 0xff, 5], [0, 2, 0, 10, 7]] {
+            assert!(PeerMsg::read(&mut &header[..]).await.is_err());
+        }
+        let msg = PeerMsg::Piece {
+            idx: 0,
+            begin: 0,
+            bytes: vec![0; MAX_BLOCK_LEN as usize],
+        };
+        let mut bytes = vec![];
+        msg.write(&mut bytes).await.unwrap();
+        assert_eq!(PeerMsg::read(&mut &bytes[..]).await.unwrap(), msg);
+    }
 }
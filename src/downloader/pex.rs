@@ -0,0 +1,267 @@
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::time::Instant;
+
+use crate::bencoding::Value;
+
+use super::{
+    extension::{Extension, ExtensionHandshake},
+    swarm::{PeerKey, Swarm},
+};
+
+pub const UT_PEX: &str = "ut_pex";
+// BEP 11 limits every message to this many added and dropped peers
+const MAX_PEERS: usize = 50;
+// BEP 11 asks for at most one message a minute, as often as the tick
+const MIN_INTERVAL: Duration = Duration::from_secs(60);
+// from added.f
+const SEED: u8 = 0x02;
+
+// Peer exchange (BEP 11): tells the peer whom we are connected to and
+// passes the peers it tells us about on as candidates.
+pub struct UtPex {
+    key: PeerKey,
+    swarm: Arc<Swarm>,
+    enabled: bool,
+    sent: HashSet<SocketAddr>,
+    last_sent: Option<Instant>,
+}
+
+impl UtPex {
+    pub fn new(key: PeerKey, swarm: Arc<Swarm>) -> Self {
+        Self {
+            key,
+            swarm,
+            enabled: false,
+            sent: HashSet::new(),
+            last_sent: None,
+        }
+    }
+
+    // Changes wait for the next update if the last message is too recent.
+    fn update(&mut self) -> Vec<Vec<u8>> {
+        let now = Instant::now();
+        if (self.last_sent).is_some_and(|last| now < last + MIN_INTERVAL) {
+            return vec![];
+        }
+        let current: HashSet<_> = self
+            .swarm
+            .get_listen_addrs()
+            .into_iter()
+            .filter(|(key, _)| *key != self.key)
+            .map(|(_, addr)| addr)
+            .collect();
+        let added: Vec<_> = current
+            .difference(&self.sent)
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        let dropped: Vec<_> = self
+            .sent
+            .difference(&current)
+            .copied()
+            .take(MAX_PEERS)
+            .collect();
+        if added.is_empty() && dropped.is_empty() {
+            return vec![];
+        }
+        self.last_sent = Some(now);
+        self.sent.extend(&added);
+        for addr in &dropped {
+            self.sent.remove(addr);
+        }
+
+        let (added, added6) = to_compact(&added);
+        let (dropped, dropped6) = to_compact(&dropped);
+        let msg = Value::dict([
+            ("added.f", vec![0; added.len() / 6].into()),
+            ("added", added.into()),
+            ("added6.f", vec![0; added6.len() / 18].into()),
+            ("added6", added6.into()),
+            ("dropped", dropped.into()),
+            ("dropped6", dropped6.into()),
+        ]);
+        vec![msg.encode()]
+    }
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &'static str {
+        UT_PEX
+    }
+
+    // The first message lists everyone, then only changes follow.
+    fn on_handshake(&mut self, handshake: &ExtensionHandshake) -> Result<Vec<Vec<u8>>> {
+        let enabled = handshake.m.get(UT_PEX).is_some_and(|id| *id != 0);
+        if !enabled || self.enabled {
+            self.enabled = enabled;
+            return Ok(vec![]);
+        }
+        self.enabled = true;
+        Ok(self.update())
+    }
+
+    // Peers come in at most MAX_PEERS a message and the connector keeps
+    // each address once, so a chatty peer can't flood us.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let msg = Value::decode(payload)?;
+        let bytes = |key| msg.get(key).and_then(Value::as_bytes).unwrap_or_default();
+        let mut peers = from_compact(bytes("added"), bytes("added.f"), 6);
+        peers.extend(from_compact(bytes("added6"), bytes("added6.f"), 18));
+        // a seed has nothing to offer us once we are done as well
+        let seeding = self.swarm.picker().is_finished();
+        let candidates = peers
+            .into_iter()
+            .filter(|(_, flags)| !(seeding && flags & SEED != 0))
+            .map(|(addr, _)| addr)
+            .take(MAX_PEERS);
+        self.swarm.add_candidates(candidates);
+        Ok(vec![])
+    }
+
+    fn tick(&mut self) -> Result<Vec<Vec<u8>>> {
+        match self.enabled {
+            true => Ok(self.update()),
+            false => Ok(vec![]),
+        }
+    }
+}
+
+fn to_compact(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let mut v4 = vec![];
+    let mut v6 = vec![];
+    for addr in addrs {
+        match addr.ip() {
+            IpAddr::V4(ip) => {
+                v4.extend(ip.octets());
+                v4.extend(addr.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                v6.extend(ip.octets());
+                v6.extend(addr.port().to_be_bytes());
+            }
+        }
+    }
+    (v4, v6)
+}
+
+fn from_compact(bytes: &[u8], flags: &[u8], len: usize) -> Vec<(SocketAddr, u8)> {
+    bytes
+        .chunks_exact(len)
+        .enumerate()
+        .map(|(idx, chunk)| {
+            let ip = match len {
+                6 => IpAddr::from(<[u8; 4]>::try_from(&chunk[..4]).unwrap()),
+                _ => IpAddr::from(<[u8; 16]>::try_from(&chunk[..16]).unwrap()),
+            };
+            let port = u16::from_be_bytes([chunk[len - 2], chunk[len - 1]]);
+            let flags = flags.get(idx).copied().unwrap_or_default();
+            (SocketAddr::new(ip, port), flags)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        net::{Ipv6Addr, SocketAddr},
+        sync::Arc,
+    };
+
+    use tempfile::tempdir;
+    use tokio::{sync::mpsc::channel, time::Instant};
+
+    use crate::{
+        bencoding::Value,
+        downloader::{
+            block_hashes::BlockHashes,
+            config::Config,
+            extension::{Extension, ExtensionHandshake},
+            parts::Piece,
+            piece_picker::PiecePicker,
+            swarm::Swarm,
+        },
+        storage::Storage,
+    };
+
+    use super::{UtPex, MIN_INTERVAL, UT_PEX};
+
+    #[tokio::test]
+    async fn test_pex() {
+        let dir = tempdir().unwrap();
+        let storage = Storage::open(dir.path().join("file"), 4, 4).await.unwrap();
+        let picker = PiecePicker::new(&[Piece::new(0, 4, [0; 20])], 4);
+        let (block_resp_sender, _) = channel(1);
+        let config = Config::default();
+        let swarm = Swarm::new(
+            picker,
+            BlockHashes::default(),
+            storage,
+            vec![block_resp_sender],
+            config,
+            vec![],
+        );
+        let swarm = Arc::new(swarm);
+
+        let a: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let b: SocketAddr = "[2001:db8::1]:6882".parse().unwrap();
+        let (key, _a_cmds) = swarm.add_peer(b"a", Some(a)).unwrap();
+        let (other, _b_cmds) = swarm.add_peer(b"b", Some(b)).unwrap();
+        let mut pex = UtPex::new(key, swarm.clone());
+
+        // nothing is sent before the peer says it understands it
+        assert!(pex.tick().unwrap().is_empty());
+        let handshake = ExtensionHandshake {
+            m: BTreeMap::from([(UT_PEX.to_string(), 1)]),
+            ..Default::default()
+        };
+        let msgs = pex.on_handshake(&handshake).unwrap();
+        let msg = Value::decode(&msgs[0]).unwrap();
+        assert_eq!(msg.get("added").unwrap().as_bytes(), Some(&b""[..]));
+        let mut added6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        added6.extend(6882u16.to_be_bytes());
+        assert_eq!(msg.get("added6").unwrap().as_bytes(), Some(&added6[..]));
+
+        assert!(pex.tick().unwrap().is_empty());
+        // too soon after the first message, the change waits
+        swarm.remove_peer(other);
+        assert!(pex.tick().unwrap().is_empty());
+        // as if the first was sent a minute ago
+        pex.last_sent = Some(Instant::now() - MIN_INTERVAL);
+        let msgs = pex.tick().unwrap();
+        let msg = Value::decode(&msgs[0]).unwrap();
+        assert_eq!(msg.get("dropped6").unwrap().as_bytes(), Some(&added6[..]));
+
+        let msg = Value::dict([
+            (
+                "added",
+                vec![10, 0, 0, 2, 0x1a, 0xe1, 10, 0, 0, 3, 0x1a, 0xe1].into(),
+            ),
+            ("added.f", vec![0x10, 0x02].into()),
+        ]);
+        pex.on_message(&msg.encode()).unwrap();
+        let candidates = swarm.take_candidates();
+        assert_eq!(
+            candidates,
+            [
+                "10.0.0.2:6881".parse().unwrap(),
+                "10.0.0.3:6881".parse().unwrap()
+            ]
+        );
+
+        // however soon the next one comes, its peers are kept
+        let msg = Value::dict([("added", vec![10, 0, 0, 4, 0x1a, 0xe1].into())]);
+        pex.on_message(&msg.encode()).unwrap();
+        assert_eq!(
+            swarm.take_candidates(),
+            ["10.0.0.4:6881".parse::<SocketAddr>().unwrap()]
+        );
+    }
+}
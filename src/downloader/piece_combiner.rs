@@ -1,26 +1,50 @@
-use std::{io::SeekFrom, path::Path};
+use std::{sync::Arc, time::Duration};
 
+use anyhow::{Context, Result};
 use tokio::{
-    fs::File,
-    io::{AsyncSeekExt, AsyncWriteExt},
+    select,
     sync::mpsc::UnboundedReceiver,
+    time::{sleep_until, Instant},
 };
 
-use super::parts::PieceResp;
+use super::{parts::PieceResp, resume::Resume, swarm::Swarm};
+
+// how long written pieces may go without being in the resume file
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
 
 pub async fn piece_combiner(
     mut piece_receiver: UnboundedReceiver<PieceResp>,
-    piece_size: u32,
-    path: impl AsRef<Path>,
-) {
-    let mut file = File::create(&path).await.unwrap();
+    swarm: Arc<Swarm>,
+    resume: Resume,
+) -> Result<()> {
+    // when the pieces written since the last save are due
+    let mut save_at = None;
     loop {
-        let Some(piece) = piece_receiver.recv().await else {
-            return;
-        };
+        select! {
+            piece = piece_receiver.recv() => {
+                let Some(piece) = piece else {
+                    break;
+                };
+                (swarm.storage().write_piece(piece.idx, &piece.bytes))
+                    .await
+                    .with_context(|| format!("writing piece {} failed", piece.idx))?;
+                swarm.add_have(piece.idx);
+                save_at.get_or_insert(Instant::now() + SAVE_INTERVAL);
+            }
+            _ = sleep_until(save_at.unwrap_or_else(Instant::now)), if save_at.is_some() => {
+                save(&swarm, &resume).await;
+                save_at = None;
+            }
+        }
+    }
+    if save_at.is_some() {
+        save(&swarm, &resume).await;
+    }
+    Ok(())
+}
 
-        let seek_from = SeekFrom::Start(piece.idx as u64 * piece_size as u64);
-        file.seek(seek_from).await.unwrap();
-        file.write(&piece.bytes).await.unwrap();
+pub async fn save(swarm: &Swarm, resume: &Resume) {
+    if let Err(err) = resume.save(swarm.storage(), &swarm.have()).await {
+        eprintln!("saving the resume file failed: {:#}", err);
     }
 }
@@ -0,0 +1,424 @@
+use crate::random::Rng;
+
+use super::{
+    bitfield::Bitfield,
+    parts::{BlockReq, Piece},
+    swarm::PeerKey,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    Open,
+    Requested(Vec<PeerKey>),
+    Received,
+}
+
+struct PieceState {
+    len: u32,
+    blocks: Vec<BlockState>,
+    done: bool,
+}
+
+impl PieceState {
+    fn is_untouched(&self) -> bool {
+        self.blocks.iter().all(|block| *block == BlockState::Open)
+    }
+
+    fn next_open_block(&self) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|block| *block == BlockState::Open)
+    }
+}
+
+pub struct PiecePicker {
+    block_size: u32,
+    pieces: Vec<PieceState>,
+    availability: Vec<u32>,
+    remaining: usize,
+    rng: Rng,
+}
+
+impl PiecePicker {
+    pub fn new(pieces: &[Piece], block_size: u32) -> Self {
+        let pieces: Vec<_> = pieces
+            .iter()
+            .map(|piece| PieceState {
+                len: piece.len,
+                blocks: vec![BlockState::Open; piece.len.div_ceil(block_size) as usize],
+                done: false,
+            })
+            .collect();
+        Self {
+            block_size,
+            availability: vec![0; pieces.len()],
+            remaining: pieces.len(),
+            pieces,
+            rng: Rng::default(),
+        }
+    }
+
+    pub fn add_peer(&mut self, have: &Bitfield) {
+        for idx in 0..self.pieces.len() {
+            if have.has(idx as u32) {
+                self.availability[idx] += 1;
+            }
+        }
+    }
+
+    pub fn remove_peer(&mut self, have: &Bitfield) {
+        for idx in 0..self.pieces.len() {
+            if have.has(idx as u32) {
+                self.availability[idx] -= 1;
+            }
+        }
+    }
+
+    pub fn add_have(&mut self, idx: u32) {
+        if let Some(availability) = self.availability.get_mut(idx as usize) {
+            *availability += 1;
+        }
+    }
+
+    pub fn wants_any(&self, have: &Bitfield) -> bool {
+        self.pieces
+            .iter()
+            .enumerate()
+            .any(|(idx, piece)| !piece.done && have.has(idx as u32))
+    }
+
+    pub fn pick(&mut self, peer: PeerKey, have: &Bitfield) -> Option<BlockReq> {
+        if self.is_endgame() {
+            return self.pick_endgame(peer, have);
+        }
+
+        let wanted = |(idx, piece): &(usize, &PieceState)| !piece.done && have.has(*idx as u32);
+
+        // finish pieces that are already started before opening new ones
+        let partial = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(wanted)
+            .find(|(_, piece)| !piece.is_untouched() && piece.next_open_block().is_some());
+        if let Some((idx, _)) = partial {
+            return Some(self.request_next_block(peer, idx));
+        }
+
+        let untouched: Vec<_> = self
+            .pieces
+            .iter()
+            .enumerate()
+            .filter(wanted)
+            .filter(|(_, piece)| piece.is_untouched())
+            .map(|(idx, _)| idx)
+            .collect();
+        let rarest = untouched.iter().map(|idx| self.availability[*idx]).min()?;
+        let candidates: Vec<_> = untouched
+            .into_iter()
+            .filter(|idx| self.availability[*idx] == rarest)
+            .collect();
+        let idx = *self.rng.choose(&candidates).unwrap();
+        Some(self.request_next_block(peer, idx))
+    }
+
+    // Endgame starts once every missing block has been requested at least
+    // once. From then on blocks are requested from several peers.
+    fn is_endgame(&self) -> bool {
+        self.pieces
+            .iter()
+            .filter(|piece| !piece.done)
+            .all(|piece| piece.next_open_block().is_none())
+    }
+
+    fn pick_endgame(&mut self, peer: PeerKey, have: &Bitfield) -> Option<BlockReq> {
+        let (idx, block_idx, _) =
+            self.pieces
+                .iter()
+                .enumerate()
+                .filter(|(idx, piece)| !piece.done && have.has(*idx as u32))
+                .flat_map(|(idx, piece)| {
+                    piece.blocks.iter().enumerate().filter_map(
+                        move |(block_idx, block)| match block {
+                            BlockState::Requested(peers) if !peers.contains(&peer) => {
+                                Some((idx, block_idx, peers.len()))
+                            }
+                            _ => None,
+                        },
+                    )
+                })
+                .min_by_key(|(_, _, no_requesters)| *no_requesters)?;
+
+        let BlockState::Requested(peers) = &mut self.pieces[idx].blocks[block_idx] else {
+            unreachable!();
+        };
+        peers.push(peer);
+        Some(self.get_block_req(idx, block_idx))
+    }
+
+    fn request_next_block(&mut self, peer: PeerKey, idx: usize) -> BlockReq {
+        let piece = &mut self.pieces[idx];
+        let block_idx = piece.next_open_block().unwrap();
+        piece.blocks[block_idx] = BlockState::Requested(vec![peer]);
+        self.get_block_req(idx, block_idx)
+    }
+
+    fn get_block_req(&self, idx: usize, block_idx: usize) -> BlockReq {
+        let begin = block_idx as u32 * self.block_size;
+        let len = self.block_size.min(self.pieces[idx].len - begin);
+        BlockReq::new(idx as u32, begin, len)
+    }
+
+    // Returns the other peers the block is still requested from, or None if
+    // the block is a duplicate or wasn't requested from this peer, and must
+    // not reach the validator.
+    pub fn block_received(&mut self, peer: PeerKey, idx: u32, begin: u32) -> Option<Vec<PeerKey>> {
+        let block_size = self.block_size;
+        let piece = self.pieces.get_mut(idx as usize)?;
+        if piece.done || !begin.is_multiple_of(block_size) {
+            return None;
+        }
+        let block = piece.blocks.get_mut((begin / block_size) as usize)?;
+        let BlockState::Requested(peers) = block else {
+            return None;
+        };
+        if !peers.contains(&peer) {
+            return None;
+        }
+        let others = peers
+            .iter()
+            .copied()
+            .filter(|other| *other != peer)
+            .collect();
+        *block = BlockState::Received;
+        Some(others)
+    }
+
+    pub fn unrequest(&mut self, peer: PeerKey, block: BlockReq) {
+        let block_size = self.block_size;
+        let Some(state) = self
+            .pieces
+            .get_mut(block.piece_idx as usize)
+            .and_then(|piece| piece.blocks.get_mut((block.begin / block_size) as usize))
+        else {
+            return;
+        };
+        if let BlockState::Requested(peers) = state {
+            peers.retain(|other| *other != peer);
+            if peers.is_empty() {
+                *state = BlockState::Open;
+            }
+        }
+    }
+
+    pub fn piece_done(&mut self, idx: u32) {
+        let piece = &mut self.pieces[idx as usize];
+        if !piece.done {
+            piece.done = true;
+            self.remaining -= 1;
+        }
+    }
+
+    pub fn piece_failed(&mut self, idx: u32) {
+        let piece = &mut self.pieces[idx as usize];
+        piece.blocks.fill(BlockState::Open);
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.remaining == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::downloader::{bitfield::Bitfield, parts::Piece};
+
+    use super::PiecePicker;
+
+    const BLOCK_SIZE: u32 = 16 * 1024;
+
+    fn get_pieces(no_pieces: u32, piece_len: u32) -> Vec<Piece> {
+        (0..no_pieces)
+            .map(|idx| Piece::new(idx, piece_len, [0; 20]))
+            .collect()
+    }
+
+    fn bitfield(pieces: &[u32], len: usize) -> Bitfield {
+        let mut bitfield = Bitfield::new(len);
+        for idx in pieces {
+            bitfield.set(*idx);
+        }
+        bitfield
+    }
+
+    #[test]
+    fn test_pick_rarest_first() {
+        let mut picker = PiecePicker::new(&get_pieces(4, BLOCK_SIZE), BLOCK_SIZE);
+        picker.add_peer(&bitfield(&[0, 1, 2, 3], 4));
+        picker.add_peer(&bitfield(&[0, 1, 3], 4));
+        picker.add_peer(&bitfield(&[0, 3], 4));
+
+        let seeder = bitfield(&[0, 1, 2, 3], 4);
+        let got: Vec<_> = (0..4)
+            .map(|_| picker.pick(0, &seeder).unwrap().piece_idx)
+            .collect();
+        assert_eq!(got[0], 2);
+        assert_eq!(got[1], 1);
+        assert_eq!(
+            got[2..].iter().collect::<HashSet<_>>(),
+            [0, 3].iter().collect()
+        );
+        assert!(picker.pick(0, &seeder).is_none());
+    }
+
+    #[test]
+    fn test_pick_only_available() {
+        let mut picker = PiecePicker::new(&get_pieces(4, BLOCK_SIZE), BLOCK_SIZE);
+        let partial_seed = bitfield(&[1, 3], 4);
+        picker.add_peer(&partial_seed);
+        picker.add_peer(&bitfield(&[0, 1, 2, 3], 4));
+
+        let mut got: Vec<_> = (0..2)
+            .map(|_| picker.pick(0, &partial_seed).unwrap().piece_idx)
+            .collect();
+        got.sort();
+        assert_eq!(got, [1, 3]);
+        assert!(picker.pick(0, &partial_seed).is_none());
+    }
+
+    #[test]
+    fn test_pick_random_among_ties() {
+        let pieces = get_pieces(3, BLOCK_SIZE);
+        let seeder = bitfield(&[0, 1, 2], 3);
+
+        let got: HashSet<_> = (0..100)
+            .map(|_| {
+                let mut picker = PiecePicker::new(&pieces, BLOCK_SIZE);
+                picker.add_peer(&seeder);
+                picker.add_peer(&bitfield(&[1], 3));
+                picker.pick(0, &seeder).unwrap().piece_idx
+            })
+            .collect();
+        assert_eq!(got, [0, 2].into_iter().collect());
+    }
+
+    #[test]
+    fn test_pick_partial_first() {
+        let mut picker = PiecePicker::new(&get_pieces(3, 3 * BLOCK_SIZE), BLOCK_SIZE);
+        picker.add_peer(&bitfield(&[0, 1, 2], 3));
+        picker.add_peer(&bitfield(&[0, 2], 3));
+        picker.add_peer(&bitfield(&[0], 3));
+
+        let first = picker.pick(0, &bitfield(&[0], 3)).unwrap();
+        assert_eq!((first.piece_idx, first.begin), (0, 0));
+
+        // piece 0 is the most common one, but it is already started
+        let seeder = bitfield(&[0, 1, 2], 3);
+        let got: Vec<_> = (0..3)
+            .map(|_| {
+                let block = picker.pick(0, &seeder).unwrap();
+                (block.piece_idx, block.begin)
+            })
+            .collect();
+        assert_eq!(got, [(0, BLOCK_SIZE), (0, 2 * BLOCK_SIZE), (1, 0)]);
+    }
+
+    #[test]
+    fn test_last_block_len() {
+        let mut picker = PiecePicker::new(&get_pieces(1, BLOCK_SIZE + 10), BLOCK_SIZE);
+        let seeder = bitfield(&[0], 1);
+        picker.add_peer(&seeder);
+
+        assert_eq!(picker.pick(0, &seeder).unwrap().len, BLOCK_SIZE);
+        assert_eq!(picker.pick(0, &seeder).unwrap().len, 10);
+        assert!(picker.pick(0, &seeder).is_none());
+    }
+
+    #[test]
+    fn test_piece_failed_and_done() {
+        let mut picker = PiecePicker::new(&get_pieces(2, BLOCK_SIZE), BLOCK_SIZE);
+        let seeder = bitfield(&[0, 1], 2);
+        picker.add_peer(&seeder);
+
+        let first = picker.pick(0, &seeder).unwrap().piece_idx;
+        let second = picker.pick(0, &seeder).unwrap().piece_idx;
+        picker.block_received(0, first, 0);
+        picker.block_received(0, second, 0);
+
+        picker.piece_failed(first);
+        picker.piece_done(second);
+        assert!(!picker.is_finished());
+        assert_eq!(picker.pick(0, &seeder).unwrap().piece_idx, first);
+        assert!(picker.pick(0, &seeder).is_none());
+
+        picker.piece_done(first);
+        assert!(picker.is_finished());
+    }
+
+    #[test]
+    fn test_endgame() {
+        let mut picker = PiecePicker::new(&get_pieces(2, BLOCK_SIZE), BLOCK_SIZE);
+        let seeder = bitfield(&[0, 1], 2);
+        picker.add_peer(&seeder);
+        picker.add_peer(&seeder);
+        picker.add_peer(&seeder);
+
+        picker.pick(0, &seeder).unwrap();
+        picker.pick(0, &seeder).unwrap();
+        assert!(picker.pick(0, &seeder).is_none());
+
+        // every block is requested, so the others get the same blocks
+        let got: HashSet<_> = (0..2)
+            .map(|_| picker.pick(1, &seeder).unwrap().piece_idx)
+            .collect();
+        assert_eq!(got, [0, 1].into_iter().collect());
+        assert!(picker.pick(1, &seeder).is_none());
+        assert_eq!(picker.pick(2, &seeder).unwrap().piece_idx, 0);
+
+        assert_eq!(picker.block_received(1, 0, 0), Some(vec![0, 2]));
+        assert_eq!(picker.block_received(0, 0, 0), None);
+        assert_eq!(picker.block_received(2, 0, 0), None);
+
+        assert_eq!(picker.block_received(0, 1, 0), Some(vec![1]));
+        picker.piece_done(1);
+        assert_eq!(picker.block_received(1, 1, 0), None);
+    }
+
+    #[test]
+    fn test_unrequested_block() {
+        let mut picker = PiecePicker::new(&get_pieces(1, 2 * BLOCK_SIZE), BLOCK_SIZE);
+        let seeder = bitfield(&[0], 1);
+        picker.add_peer(&seeder);
+
+        // open, or requested from someone else
+        assert_eq!(picker.block_received(0, 0, 0), None);
+        let block = picker.pick(0, &seeder).unwrap();
+        assert_eq!(picker.block_received(1, 0, block.begin), None);
+        assert_eq!(picker.block_received(0, 0, block.begin), Some(vec![]));
+        assert_eq!(picker.pick(1, &seeder).unwrap().begin, BLOCK_SIZE);
+    }
+
+    #[test]
+    fn test_unrequest() {
+        let mut picker = PiecePicker::new(&get_pieces(1, 2 * BLOCK_SIZE), BLOCK_SIZE);
+        let seeder = bitfield(&[0], 1);
+        picker.add_peer(&seeder);
+        picker.add_peer(&seeder);
+
+        let first = picker.pick(0, &seeder).unwrap();
+        let second = picker.pick(0, &seeder).unwrap();
+        assert_eq!(picker.pick(1, &seeder), Some(first));
+
+        // still requested from peer 1
+        picker.unrequest(0, first);
+        assert_eq!(picker.pick(1, &seeder), Some(second));
+
+        picker.unrequest(0, second);
+        picker.unrequest(1, second);
+        picker.remove_peer(&seeder);
+        assert_eq!(picker.pick(2, &seeder), Some(second));
+        assert_eq!(picker.pick(2, &seeder), Some(first));
+    }
+}
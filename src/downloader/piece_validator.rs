@@ -1,12 +1,15 @@
-use async_channel::Sender;
-use sha1::{Digest, Sha1};
+use std::sync::Arc;
+
 use tokio::sync::mpsc::{Receiver, UnboundedSender};
 
-use super::parts::{BlockResp, Piece, PieceReq, PieceResp};
+use super::{
+    parts::{BlockResp, Piece, PieceResp},
+    swarm::Swarm,
+};
 
 pub async fn piece_validator(
     mut block_resp_receiver: Receiver<BlockResp>,
-    piece_req_sender: Sender<PieceReq>,
+    swarm: Arc<Swarm>,
     piece_resp_sender: UnboundedSender<PieceResp>,
     piece: Piece,
 ) {
@@ -23,19 +26,26 @@ pub async fn piece_validator(
                     continue;
                 }
                 State::Complete => {
-                    let bytes: Vec<_> = blocks.into_iter().flat_map(|block| block.bytes).collect();
-                    if !is_valid(&piece.hash, &bytes) {
+                    if !is_valid(&piece, &blocks, &swarm) {
                         break;
                     }
+                    let mut bytes: Vec<_> =
+                        blocks.into_iter().flat_map(|block| block.bytes).collect();
+                    // the rest of a v2 file's last piece
+                    bytes.resize(swarm.storage().get_piece_len(piece.idx) as usize, 0);
+                    let idx = piece.idx;
                     let piece_resp = PieceResp::from_piece(piece, bytes);
                     piece_resp_sender.send(piece_resp).unwrap();
+                    swarm.picker().piece_done(idx);
+                    swarm.notify();
                     return;
                 }
                 State::Invalid => break,
             };
         }
         drain(&mut block_resp_receiver);
-        piece_req_sender.send((&piece).into()).await.unwrap();
+        swarm.picker().piece_failed(piece.idx);
+        swarm.notify();
     }
 }
 
@@ -45,7 +55,7 @@ enum State {
     Invalid,
 }
 
-fn check_completeness(piece_len: u32, blocks: &mut Vec<BlockResp>) -> State {
+fn check_completeness(piece_len: u32, blocks: &mut [BlockResp]) -> State {
     blocks.sort_by_key(|block| block.begin);
 
     let mut begin = 0;
@@ -70,17 +80,22 @@ fn check_completeness(piece_len: u32, blocks: &mut Vec<BlockResp>) -> State {
     State::Complete
 }
 
-fn is_valid(piece_hash: &[u8; 20], bytes: &[u8]) -> bool {
-    let mut hasher = Sha1::new();
-    hasher.update(&bytes);
-    let hash: [u8; 20] = hasher.finalize().into();
-    hash == *piece_hash
+fn is_valid(piece: &Piece, blocks: &[BlockResp], swarm: &Swarm) -> bool {
+    let bytes: Vec<_> = blocks
+        .iter()
+        .flat_map(|block| &block.bytes)
+        .copied()
+        .collect();
+    match piece.check(&bytes) {
+        Some(valid) => valid,
+        // hashes that never came count as a failure too
+        None => blocks.iter().all(|block| {
+            let block_hashes = swarm.block_hashes();
+            block_hashes.check_block(piece.idx, block.begin, &block.bytes) == Some(true)
+        }),
+    }
 }
 
 fn drain(receiver: &mut Receiver<BlockResp>) {
-    loop {
-        if let Err(_) = receiver.try_recv() {
-            return;
-        }
-    }
+    while receiver.try_recv().is_ok() {}
 }
@@ -0,0 +1,47 @@
+pub struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (idx, byte) in state.iter_mut().enumerate() {
+            *byte = idx as u8;
+        }
+        let mut j: u8 = 0;
+        for idx in 0..256 {
+            j = j
+                .wrapping_add(state[idx])
+                .wrapping_add(key[idx % key.len()]);
+            state.swap(idx, j as usize);
+        }
+        Self { state, i: 0, j: 0 }
+    }
+
+    // encrypts and decrypts alike
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let idx = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[idx as usize];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Rc4;
+
+    #[test]
+    fn test_rc4() {
+        let mut data = b"Plaintext".to_vec();
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(hex::encode(&data), "bbf316e8d940af0ad3");
+        Rc4::new(b"Key").apply(&mut data);
+        assert_eq!(data, b"Plaintext");
+    }
+}
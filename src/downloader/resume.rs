@@ -0,0 +1,150 @@
+use std::{
+    path::{Path, PathBuf},
+    thread::available_parallelism,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Result;
+use tokio::{fs, task::JoinSet};
+
+use crate::{bencoding::Value, storage::Storage};
+
+use super::{bitfield::Bitfield, parts::Piece};
+
+// The pieces of a download that are verified, kept next to it so a
+// restart doesn't hash everything again. It only holds while the data
+// wasn't modified since.
+#[derive(Clone)]
+pub struct Resume {
+    path: PathBuf,
+    info_hash: [u8; 20],
+}
+
+impl Resume {
+    pub fn new(output_file_path: impl AsRef<Path>, info_hash: [u8; 20]) -> Self {
+        let mut path = output_file_path.as_ref().as_os_str().to_owned();
+        path.push(".resume");
+        Self {
+            path: path.into(),
+            info_hash,
+        }
+    }
+
+    pub async fn load(&self, storage: &Storage) -> Option<Bitfield> {
+        let bytes = fs::read(&self.path).await.ok()?;
+        let value = Value::decode(&bytes).ok()?;
+        let no_pieces = storage.get_no_pieces();
+        let pieces = value.get("pieces")?.as_bytes()?;
+        let valid = value.get("info hash")?.as_bytes()? == self.info_hash
+            && value.get("modified")?.as_integer()? == get_modified(storage).await.ok()?
+            && pieces.len() == no_pieces.div_ceil(8);
+        valid.then(|| Bitfield::from_bytes(pieces.to_vec(), no_pieces))
+    }
+
+    // Called after the pieces are written, so it never claims more than
+    // the data holds.
+    pub async fn save(&self, storage: &Storage, have: &Bitfield) -> Result<()> {
+        let value = Value::dict([
+            ("info hash", self.info_hash.as_slice().into()),
+            ("modified", get_modified(storage).await?.into()),
+            ("pieces", have.as_bytes().into()),
+        ]);
+        // replaced at once, a crash leaves the old one
+        let mut tmp = self.path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, value.encode()).await?;
+        fs::rename(&tmp, &self.path).await?;
+        Ok(())
+    }
+}
+
+fn to_nanos(modified: SystemTime) -> i64 {
+    modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_nanos() as i64)
+}
+
+async fn get_modified(storage: &Storage) -> Result<i64> {
+    Ok(to_nanos(storage.get_modified().await?))
+}
+
+// Hashes the data that is already there, on all cores. A piece that can't
+// be read doesn't count.
+pub async fn check_pieces(storage: &Storage, pieces: &[Piece]) -> Bitfield {
+    let parallel = available_parallelism().map_or(1, usize::from);
+    let mut have = Bitfield::new(pieces.len());
+    let mut hashing = JoinSet::new();
+    for piece in pieces {
+        if hashing.len() >= parallel {
+            let (idx, valid) = hashing.join_next().await.unwrap().unwrap();
+            if valid {
+                have.set(idx);
+            }
+        }
+        let Ok(mut bytes) = storage.read_piece(piece.idx).await else {
+            continue;
+        };
+        bytes.truncate(piece.len as usize);
+        let piece = Piece::new_v2(piece.idx, piece.len, piece.hash);
+        // without the hashes of the blocks, it is fetched again
+        hashing.spawn_blocking(move || (piece.idx, piece.check(&bytes) == Some(true)));
+    }
+    while let Some(result) = hashing.join_next().await {
+        let (idx, valid) = result.unwrap();
+        if valid {
+            have.set(idx);
+        }
+    }
+    have
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use sha1::{Digest, Sha1};
+    use tempfile::tempdir;
+    use tokio::time::sleep;
+
+    use crate::{
+        downloader::{bitfield::Bitfield, parts::Piece},
+        storage::Storage,
+    };
+
+    use super::{check_pieces, Resume};
+
+    #[tokio::test]
+    async fn test_resume() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+        let storage = Storage::open(&path, 10, 4).await.unwrap();
+        storage.write_piece(0, &[1; 4]).await.unwrap();
+        storage.write_piece(2, &[3; 2]).await.unwrap();
+        let pieces: Vec<_> = [[1; 4].as_slice(), &[2; 4], &[3; 2]]
+            .iter()
+            .enumerate()
+            .map(|(idx, bytes)| {
+                Piece::new(idx as u32, bytes.len() as u32, Sha1::digest(bytes).into())
+            })
+            .collect();
+        let have = check_pieces(&storage, &pieces).await;
+        assert_eq!(have, Bitfield::from_bytes(vec![0b1010_0000], 3));
+
+        let resume = Resume::new(&path, [7; 20]);
+        assert!(resume.load(&storage).await.is_none());
+        resume.save(&storage, &have).await.unwrap();
+        assert_eq!(resume.load(&storage).await, Some(have.clone()));
+        assert!(Resume::new(&path, [8; 20]).load(&storage).await.is_none());
+
+        // a restart trusts it without hashing anything again
+        drop(storage);
+        sleep(Duration::from_millis(20)).await;
+        let storage = Storage::open(&path, 10, 4).await.unwrap();
+        assert_eq!(resume.load(&storage).await, Some(have.clone()));
+
+        // the data changed since, past the timestamp granularity
+        sleep(Duration::from_millis(20)).await;
+        storage.write_piece(1, &[2; 4]).await.unwrap();
+        assert!(resume.load(&storage).await.is_none());
+    }
+}
@@ -0,0 +1,1101 @@
+use std::{
+    collections::VecDeque,
+    future::ready,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{bail, ensure, Result};
+use tokio::{
+    select, spawn,
+    sync::mpsc::{channel, Receiver, UnboundedReceiver},
+    time::{sleep_until, Instant},
+};
+
+use super::{
+    allowed_fast::get_allowed_fast_set,
+    bitfield::Bitfield,
+    choke_state::ChokeState,
+    extension::{Extension, ExtensionHandshake, Extensions, HANDSHAKE_ID},
+    metadata::UtMetadata,
+    parts::{BlockReq, BlockResp},
+    peer::{Handshake, PeerCmd, PeerReader, PeerWriter},
+    peer_msg::{PeerMsg, MAX_BLOCK_LEN},
+    pex::UtPex,
+    swarm::{PeerKey, Swarm},
+};
+
+const MAX_QUEUED_REQUESTS: usize = 256;
+// used until the peer tells us its reqq
+const DEFAULT_PIPELINE: usize = 5;
+// so a single peer can't grab every block of a small torrent
+const MAX_PIPELINE: usize = 64;
+const EXTENSION_TICK: Duration = Duration::from_secs(60);
+
+struct InFlight {
+    block: BlockReq,
+    sent_at: Instant,
+}
+
+pub struct Session {
+    key: PeerKey,
+    addr: SocketAddr,
+    writer: PeerWriter,
+    bitfield: Bitfield,
+    state: ChokeState,
+    fast: bool,
+    v2: bool,
+    // pieces we serve even while choking them, and the other way round
+    allowed_fast: Vec<u32>,
+    peer_allowed_fast: Vec<u32>,
+    extensions: Option<Extensions>,
+    next_tick: Instant,
+    pipeline: usize,
+    in_flight: Vec<InFlight>,
+    requests: VecDeque<BlockReq>,
+    last_received: Instant,
+    last_sent: Instant,
+    waiting_for_unchoke: Option<Instant>,
+    swarm: Arc<Swarm>,
+    cmd_receiver: UnboundedReceiver<PeerCmd>,
+}
+
+impl Session {
+    pub fn new(
+        writer: PeerWriter,
+        addr: SocketAddr,
+        outgoing: bool,
+        handshake: &Handshake,
+        swarm: Arc<Swarm>,
+    ) -> Result<Self> {
+        let (key, cmd_receiver) = swarm.add_peer(&handshake.peer_id, outgoing.then_some(addr))?;
+        let no_pieces = swarm.storage().get_no_pieces();
+        let fast = handshake.supports_fast();
+        let allowed_fast = match addr.ip() {
+            IpAddr::V4(ip) if fast => get_allowed_fast_set(
+                ip,
+                &handshake.info_hash,
+                no_pieces as u32,
+                swarm.config().allowed_fast,
+            ),
+            _ => vec![],
+        };
+        Ok(Self {
+            key,
+            addr,
+            writer,
+            bitfield: Bitfield::new(no_pieces),
+            state: ChokeState::default(),
+            fast,
+            v2: handshake.supports_v2(),
+            allowed_fast,
+            peer_allowed_fast: vec![],
+            extensions: handshake
+                .supports_extensions()
+                .then(|| new_extensions(key, handshake, &swarm)),
+            next_tick: Instant::now() + EXTENSION_TICK,
+            pipeline: DEFAULT_PIPELINE,
+            in_flight: vec![],
+            requests: VecDeque::new(),
+            last_received: Instant::now(),
+            last_sent: Instant::now(),
+            waiting_for_unchoke: None,
+            swarm,
+            cmd_receiver,
+        })
+    }
+
+    pub async fn run(mut self, mut reader: PeerReader) -> Result<()> {
+        let (msg_sender, msg_receiver) = channel(1);
+        let response_reader = spawn(async move {
+            loop {
+                let msg = PeerMsg::read(&mut reader).await;
+                let failed = msg.is_err();
+                if msg_sender.send(msg).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+
+        let result = self.download(msg_receiver).await;
+
+        response_reader.abort();
+        self.close();
+        result
+    }
+
+    async fn download(&mut self, mut msg_receiver: Receiver<Result<PeerMsg>>) -> Result<()> {
+        let have = self.swarm.have();
+        if self.fast && have.has_all() {
+            self.send(PeerMsg::HaveAll).await?;
+        } else if self.fast && have.has_none() {
+            self.send(PeerMsg::HaveNone).await?;
+        } else if !have.has_none() {
+            self.send(PeerMsg::Bitfield(have.as_bytes().to_vec()))
+                .await?;
+        }
+        for idx in self.allowed_fast.clone() {
+            self.send(PeerMsg::AllowedFast(idx)).await?;
+        }
+        if let Some(extensions) = &self.extensions {
+            let handshake = ExtensionHandshake {
+                p: Some(self.swarm.config().listen_addr.port()),
+                reqq: Some(MAX_QUEUED_REQUESTS as u32),
+                yourip: Some(self.addr.ip().to_canonical()),
+                ..extensions.get_handshake()
+            };
+            self.send_extended(HANDSHAKE_ID, handshake.encode()).await?;
+        }
+
+        loop {
+            let swarm = self.swarm.clone();
+            let changed = swarm.changed();
+            if swarm.picker().is_finished() && !swarm.config().seed {
+                return Ok(());
+            }
+
+            self.update_interest().await?;
+            self.fill_requests().await?;
+
+            let deadline = self.next_deadline();
+            select! {
+                biased;
+                msg = msg_receiver.recv() => {
+                    let Some(msg) = msg else {
+                        bail!("connection closed");
+                    };
+                    self.last_received = Instant::now();
+                    self.handle_msg(msg?).await?;
+                }
+                Some(cmd) = self.cmd_receiver.recv() => self.handle_cmd(cmd).await?,
+                _ = ready(()), if !self.requests.is_empty() => self.serve_request().await?,
+                _ = changed => {}
+                _ = sleep_until(deadline) => self.check_timeouts().await?,
+            }
+        }
+    }
+
+    async fn update_interest(&mut self) -> Result<()> {
+        let interested = self.swarm.picker().wants_any(&self.bitfield);
+        if interested && !self.state.am_interested {
+            self.send(PeerMsg::Interested).await?;
+        } else if !interested && self.state.am_interested {
+            self.send(PeerMsg::NotInterested).await?;
+        }
+        Ok(())
+    }
+
+    async fn fill_requests(&mut self) -> Result<()> {
+        let requestable = if self.state.can_request() {
+            self.bitfield.clone()
+        } else if self.state.am_interested && !self.peer_allowed_fast.is_empty() {
+            // choked, but some pieces may still be requested
+            let mut requestable = Bitfield::new(self.swarm.storage().get_no_pieces());
+            for idx in &self.peer_allowed_fast {
+                if self.bitfield.has(*idx) {
+                    requestable.set(*idx);
+                }
+            }
+            requestable
+        } else {
+            return Ok(());
+        };
+
+        while self.in_flight.len() < self.pipeline {
+            let Some(block) = self.swarm.picker().pick(self.key, &requestable) else {
+                break;
+            };
+            // so a bad block can be told from the peer that sent it
+            let hash_req = (self.v2)
+                .then(|| {
+                    let mut block_hashes = self.swarm.block_hashes();
+                    block_hashes.get_hash_req(self.key, block.piece_idx, block.begin)
+                })
+                .flatten();
+            if let Some(req) = hash_req {
+                self.send(PeerMsg::HashRequest(req)).await?;
+            }
+            self.send(PeerMsg::from(block)).await?;
+            self.in_flight.push(InFlight {
+                block,
+                sent_at: Instant::now(),
+            });
+        }
+        Ok(())
+    }
+
+    fn next_deadline(&self) -> Instant {
+        let timeouts = &self.swarm.config().timeouts;
+        let mut deadline =
+            (self.last_received + timeouts.idle).min(self.last_sent + timeouts.keep_alive);
+        if let Some(oldest) = self.in_flight.iter().map(|req| req.sent_at).min() {
+            deadline = deadline.min(oldest + timeouts.request);
+        }
+        if let Some(since) = self.waiting_for_unchoke {
+            deadline = deadline.min(since + timeouts.unchoke);
+        }
+        if self.extensions.is_some() {
+            deadline = deadline.min(self.next_tick);
+        }
+        deadline
+    }
+
+    async fn check_timeouts(&mut self) -> Result<()> {
+        let timeouts = self.swarm.config().timeouts;
+        let now = Instant::now();
+        if now >= self.last_received + timeouts.idle {
+            bail!("peer idle");
+        }
+        if self
+            .in_flight
+            .iter()
+            .any(|req| now >= req.sent_at + timeouts.request)
+        {
+            bail!("request timed out");
+        }
+        if let Some(since) = self.waiting_for_unchoke {
+            ensure!(now < since + timeouts.unchoke, "unchoke timed out");
+        }
+        if let Some(extensions) = &mut self.extensions {
+            if now >= self.next_tick {
+                self.next_tick = now + EXTENSION_TICK;
+                for (id, payload) in extensions.tick()? {
+                    self.send_extended(id, payload).await?;
+                }
+            }
+        }
+        if now >= self.last_sent + timeouts.keep_alive {
+            self.send(PeerMsg::KeepAlive).await?;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: PeerMsg) -> Result<()> {
+        msg.write(&mut self.writer).await?;
+        self.state.sent(&msg);
+        self.last_sent = Instant::now();
+        self.update_waiting_for_unchoke();
+        if let PeerMsg::Interested = msg {
+            // snubbing is measured from when we started wanting something
+            self.swarm
+                .update_transfer(self.key, |transfer| transfer.last_block = Instant::now());
+        }
+        self.report_interest(&msg);
+        Ok(())
+    }
+
+    fn report_interest(&self, msg: &PeerMsg) {
+        if let PeerMsg::Interested | PeerMsg::NotInterested = msg {
+            let state = self.state;
+            self.swarm.update_transfer(self.key, |transfer| {
+                transfer.am_interested = state.am_interested;
+                transfer.peer_interested = state.peer_interested;
+            });
+        }
+    }
+
+    fn update_waiting_for_unchoke(&mut self) {
+        let waiting = self.state.am_interested && self.state.peer_choking;
+        match (waiting, self.waiting_for_unchoke) {
+            (true, None) => self.waiting_for_unchoke = Some(Instant::now()),
+            (false, Some(_)) => self.waiting_for_unchoke = None,
+            _ => {}
+        }
+    }
+
+    async fn handle_msg(&mut self, msg: PeerMsg) -> Result<()> {
+        ensure!(
+            self.fast || !msg.is_fast(),
+            "fast extension message without negotiating it"
+        );
+        if let PeerMsg::Extended { id, payload } = msg {
+            return self.handle_extended(id, &payload).await;
+        }
+        self.state.received(&msg);
+        self.update_waiting_for_unchoke();
+        self.report_interest(&msg);
+        let no_pieces = self.swarm.storage().get_no_pieces();
+        match msg {
+            // without the fast extension, a choking peer drops all pending
+            // requests, with it every request gets rejected explicitly
+            PeerMsg::Choke if !self.fast => self.unrequest_all(),
+            PeerMsg::Bitfield(bytes) => self.set_bitfield(Bitfield::from_bytes(bytes, no_pieces)),
+            PeerMsg::HaveAll => {
+                let bitfield = Bitfield::from_bytes(vec![0xff; no_pieces.div_ceil(8)], no_pieces);
+                self.set_bitfield(bitfield);
+            }
+            PeerMsg::HaveNone => self.set_bitfield(Bitfield::new(no_pieces)),
+            PeerMsg::Reject { idx, begin, length } => {
+                let block = BlockReq::new(idx, begin, length);
+                if let Some(pos) = self.in_flight.iter().position(|req| req.block == block) {
+                    self.in_flight.remove(pos);
+                    self.swarm.picker().unrequest(self.key, block);
+                    self.swarm.notify();
+                }
+            }
+            PeerMsg::AllowedFast(idx)
+                if (idx as usize) < no_pieces && !self.peer_allowed_fast.contains(&idx) =>
+            {
+                self.peer_allowed_fast.push(idx)
+            }
+            PeerMsg::Have(idx) if !self.bitfield.has(idx) => {
+                self.bitfield.set(idx);
+                self.swarm.picker().add_have(idx);
+                self.swarm.notify();
+            }
+            PeerMsg::Request { idx, begin, length } => {
+                self.queue_request(BlockReq::new(idx, begin, length))
+                    .await?
+            }
+            PeerMsg::Cancel { idx, begin, length } => {
+                let block = BlockReq::new(idx, begin, length);
+                if let Some(pos) = self.requests.iter().position(|req| *req == block) {
+                    self.requests.remove(pos);
+                    if self.fast {
+                        self.send(block.to_reject()).await?;
+                    }
+                }
+            }
+            // there is no merkle tree here to serve from
+            PeerMsg::HashRequest(req) => self.send(PeerMsg::HashReject(req)).await?,
+            PeerMsg::Hashes { req, hashes } => {
+                let added = self
+                    .swarm
+                    .block_hashes()
+                    .add_hashes(self.key, &req, &hashes);
+                ensure!(added, "hashes that don't prove out");
+            }
+            PeerMsg::HashReject(req) => self.swarm.block_hashes().reject(self.key, &req),
+            PeerMsg::Piece { idx, begin, bytes } => {
+                // only what we asked this peer for, as long as we asked
+                let block = BlockReq::new(idx, begin, bytes.len() as u32);
+                let Some(pos) = self.in_flight.iter().position(|req| req.block == block) else {
+                    return Ok(());
+                };
+                self.in_flight.remove(pos);
+                if self.state.peer_choking && self.waiting_for_unchoke.is_some() {
+                    // allowed fast pieces keep coming, so this peer is useful
+                    self.waiting_for_unchoke = Some(Instant::now());
+                }
+                self.swarm.update_transfer(self.key, |transfer| {
+                    transfer.downloaded += bytes.len() as u64;
+                    transfer.last_block = Instant::now();
+                });
+                let checked = self.swarm.block_hashes().check_block(idx, begin, &bytes);
+                if checked == Some(false) {
+                    self.swarm.picker().unrequest(self.key, block);
+                    self.swarm.notify();
+                    bail!("bad block {} of piece {}", begin, idx);
+                }
+                let Some(others) = self.swarm.picker().block_received(self.key, idx, begin) else {
+                    return Ok(());
+                };
+                for other in others {
+                    self.swarm.send(other, PeerCmd::Cancel(block));
+                }
+                let _ = self
+                    .swarm
+                    .block_resp_sender(idx)
+                    .send(BlockResp::new(begin, bytes))
+                    .await;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<()> {
+        let Some(extensions) = &mut self.extensions else {
+            bail!("extension message without negotiating it");
+        };
+        let out = if id == HANDSHAKE_ID {
+            let handshake = ExtensionHandshake::decode(payload)?;
+            if let Some(reqq) = handshake.reqq {
+                self.pipeline = (reqq as usize).clamp(1, MAX_PIPELINE);
+            }
+            if let Some(port) = handshake.p.filter(|port| *port != 0) {
+                // an incoming connection comes from some other port
+                self.swarm
+                    .set_listen_addr(self.key, SocketAddr::new(self.addr.ip(), port));
+            }
+            extensions.on_handshake(&handshake)?
+        } else {
+            extensions.on_message(id, payload)?
+        };
+        for (id, payload) in out {
+            self.send_extended(id, payload).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_extended(&mut self, id: u8, payload: Vec<u8>) -> Result<()> {
+        self.send(PeerMsg::Extended { id, payload }).await
+    }
+
+    fn set_bitfield(&mut self, bitfield: Bitfield) {
+        let mut picker = self.swarm.picker();
+        picker.remove_peer(&self.bitfield);
+        picker.add_peer(&bitfield);
+        self.bitfield = bitfield;
+    }
+
+    async fn queue_request(&mut self, block: BlockReq) -> Result<()> {
+        let storage = self.swarm.storage();
+        ensure!(
+            (block.piece_idx as usize) < storage.get_no_pieces()
+                && block.len <= MAX_BLOCK_LEN
+                && block.begin as u64 + block.len as u64
+                    <= storage.get_piece_len(block.piece_idx) as u64,
+            "invalid request: {:?}",
+            block
+        );
+        ensure!(
+            self.requests.len() < MAX_QUEUED_REQUESTS,
+            "too many requests"
+        );
+        // choked peers and pieces we don't have are not served
+        let allowed = !self.state.am_choking || self.allowed_fast.contains(&block.piece_idx);
+        if allowed && self.swarm.have().has(block.piece_idx) {
+            self.requests.push_back(block);
+        } else if self.fast {
+            self.send(block.to_reject()).await?;
+        }
+        Ok(())
+    }
+
+    async fn serve_request(&mut self) -> Result<()> {
+        let Some(block) = self.requests.pop_front() else {
+            return Ok(());
+        };
+        let bytes = self
+            .swarm
+            .storage()
+            .read_block(block.piece_idx, block.begin, block.len)
+            .await?;
+        self.swarm.update_transfer(self.key, |transfer| {
+            transfer.uploaded += block.len as u64;
+        });
+        self.send(PeerMsg::Piece {
+            idx: block.piece_idx,
+            begin: block.begin,
+            bytes,
+        })
+        .await
+    }
+
+    async fn handle_cmd(&mut self, cmd: PeerCmd) -> Result<()> {
+        match cmd {
+            PeerCmd::Cancel(block) => {
+                if let Some(pos) = self.in_flight.iter().position(|req| req.block == block) {
+                    self.in_flight.remove(pos);
+                    self.send(block.to_cancel()).await?;
+                }
+            }
+            PeerCmd::Have(idx) => {
+                if !self.bitfield.has(idx) {
+                    self.send(PeerMsg::Have(idx)).await?;
+                }
+            }
+            PeerCmd::Choke => {
+                if !self.state.am_choking {
+                    self.send(PeerMsg::Choke).await?;
+                    // whatever they asked for so far is not served anymore
+                    let requests: Vec<_> = self.requests.drain(..).collect();
+                    for block in requests {
+                        if self.allowed_fast.contains(&block.piece_idx) {
+                            self.requests.push_back(block);
+                        } else if self.fast {
+                            self.send(block.to_reject()).await?;
+                        }
+                    }
+                }
+            }
+            PeerCmd::Unchoke => {
+                if self.state.am_choking {
+                    self.send(PeerMsg::Unchoke).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Give back everything this peer still owes, so other peers pick it up.
+    fn unrequest_all(&mut self) {
+        let mut picker = self.swarm.picker();
+        for req in self.in_flight.drain(..) {
+            picker.unrequest(self.key, req.block);
+        }
+        drop(picker);
+        self.swarm.notify();
+    }
+
+    fn close(mut self) {
+        self.unrequest_all();
+        self.swarm.picker().remove_peer(&self.bitfield);
+        self.swarm.remove_peer(self.key);
+    }
+}
+
+fn new_extensions(key: PeerKey, handshake: &Handshake, swarm: &Arc<Swarm>) -> Extensions {
+    let mut modules: Vec<Box<dyn Extension>> = vec![Box::new(UtMetadata::new(
+        handshake.info_hash,
+        swarm.metadata(),
+    ))];
+    if swarm.config().pex {
+        modules.push(Box::new(UtPex::new(key, swarm.clone())));
+    }
+    Extensions::new(modules)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use anyhow::Result;
+    use tempfile::{tempdir, TempDir};
+    use tokio::{
+        io::{duplex, split, AsyncReadExt, AsyncWriteExt, BufReader, BufWriter, DuplexStream},
+        spawn,
+        sync::mpsc::channel,
+        task::JoinHandle,
+        time::{sleep, timeout, Instant},
+    };
+
+    use crate::{
+        bencoding::Value,
+        downloader::{
+            bitfield::Bitfield,
+            block_hashes::BlockHashes,
+            config::{Config, Timeouts},
+            extension::ExtensionHandshake,
+            parts::{BlockReq, Piece},
+            peer_msg::PeerMsg,
+            piece_picker::PiecePicker,
+            swarm::Swarm,
+        },
+        merkle::{get_block_hashes, get_file_root, hash_pair},
+        metainfo::Metainfo,
+        storage::Storage,
+    };
+
+    use super::{get_allowed_fast_set, Handshake, PeerCmd, PeerWriter, Session};
+
+    const PIECE_LEN: u32 = 32 * 1024;
+    const REMOTE_ID: &[u8] = b"-RM0001-000000000000";
+    const ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 6881);
+    const FAST: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0x04];
+    const EXTENSIONS: [u8; 8] = [0, 0, 0, 0, 0, 0x10, 0, 0];
+    const V2: [u8; 8] = [0, 0, 0, 0, 0, 0, 0, 0x10];
+
+    struct Remote {
+        reader: DuplexStream,
+        writer: DuplexStream,
+    }
+
+    impl Remote {
+        async fn recv(&mut self) -> PeerMsg {
+            PeerMsg::read(&mut self.reader).await.unwrap()
+        }
+
+        async fn send(&mut self, msg: PeerMsg) {
+            msg.write(&mut self.writer).await.unwrap();
+        }
+
+        // Keeps the session from going idle, but otherwise stays silent.
+        fn keep_alive(mut self) -> DuplexStream {
+            spawn(async move {
+                loop {
+                    sleep(Duration::from_millis(50)).await;
+                    if self.writer.write_all(&[0; 4]).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            self.reader
+        }
+    }
+
+    // Short enough to wait out.
+    fn short_timeouts() -> Config {
+        Config {
+            timeouts: Timeouts {
+                unchoke: Duration::from_millis(300),
+                request: Duration::from_millis(300),
+                keep_alive: Duration::from_millis(300),
+                idle: Duration::from_millis(600),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    // The clock is real, so give or take a little.
+    fn assert_about(elapsed: Duration, expected: Duration) {
+        let slack = Duration::from_millis(150);
+        assert!(
+            elapsed + slack > expected && elapsed < expected + slack,
+            "{elapsed:?} instead of {expected:?}"
+        );
+    }
+
+    async fn new_session() -> (TempDir, Arc<Swarm>, Remote, Session, DuplexStream) {
+        new_session_with(Config::default(), [0; 8], [0; 20]).await
+    }
+
+    async fn new_session_with(
+        config: Config,
+        reserved: [u8; 8],
+        info_hash: [u8; 20],
+    ) -> (TempDir, Arc<Swarm>, Remote, Session, DuplexStream) {
+        new_session_with_hashes(config, reserved, info_hash, BlockHashes::default()).await
+    }
+
+    // Two pieces, of which we already have the second one.
+    async fn new_session_with_hashes(
+        config: Config,
+        reserved: [u8; 8],
+        info_hash: [u8; 20],
+        block_hashes: BlockHashes,
+    ) -> (TempDir, Arc<Swarm>, Remote, Session, DuplexStream) {
+        let dir = tempdir().unwrap();
+        let storage = Storage::open(dir.path().join("file"), 2 * PIECE_LEN as u64, PIECE_LEN)
+            .await
+            .unwrap();
+        storage
+            .write_piece(1, &[1; PIECE_LEN as usize])
+            .await
+            .unwrap();
+        let pieces = vec![
+            Piece::new(0, PIECE_LEN, [0; 20]),
+            Piece::new(1, PIECE_LEN, [0; 20]),
+        ];
+        let mut picker = PiecePicker::new(&pieces, 16 * 1024);
+        picker.piece_done(1);
+        let (block_resp_sender, _) = channel(1);
+        let block_resp_senders = vec![block_resp_sender.clone(), block_resp_sender];
+        let swarm = Swarm::new(
+            picker,
+            block_hashes,
+            storage,
+            block_resp_senders,
+            config,
+            vec![],
+        );
+        let swarm = Arc::new(swarm);
+        swarm.add_have(1);
+
+        let (local_writer, reader) = duplex(64 * 1024);
+        let (local_reader, writer) = duplex(64 * 1024);
+        let (_, write_half) = split(local_writer);
+        let local_writer: PeerWriter = BufWriter::new(Box::new(write_half));
+        let handshake = Handshake {
+            info_hash,
+            peer_id: REMOTE_ID.to_vec(),
+            reserved,
+        };
+        let session =
+            Session::new(local_writer, ADDR.into(), true, &handshake, swarm.clone()).unwrap();
+        (dir, swarm, Remote { reader, writer }, session, local_reader)
+    }
+
+    fn run(session: Session, local_reader: DuplexStream) -> JoinHandle<Result<()>> {
+        spawn(session.run(BufReader::new(Box::new(local_reader))))
+    }
+
+    fn has_first() -> PeerMsg {
+        PeerMsg::Bitfield(vec![0b1000_0000])
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_and_idle() {
+        let (_dir, _, mut remote, session, local_reader) =
+            new_session_with(short_timeouts(), [0; 8], [0; 20]).await;
+
+        let start = Instant::now();
+        let session_task = run(session, local_reader);
+
+        assert_eq!(remote.recv().await, PeerMsg::Bitfield(vec![0b0100_0000]));
+        assert_eq!(remote.recv().await, PeerMsg::KeepAlive);
+        assert_about(start.elapsed(), short_timeouts().timeouts.keep_alive);
+
+        let err = session_task.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "peer idle");
+        assert_about(start.elapsed(), short_timeouts().timeouts.idle);
+    }
+
+    #[tokio::test]
+    async fn test_unchoke_timeout() {
+        let (_dir, _, mut remote, session, local_reader) =
+            new_session_with(short_timeouts(), [0; 8], [0; 20]).await;
+        let session_task = run(session, local_reader);
+
+        remote.recv().await;
+        remote.send(has_first()).await;
+        assert_eq!(remote.recv().await, PeerMsg::Interested);
+
+        let start = Instant::now();
+        let _reader = remote.keep_alive();
+        let err = session_task.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "unchoke timed out");
+        assert_about(start.elapsed(), short_timeouts().timeouts.unchoke);
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let (_dir, swarm, mut remote, session, local_reader) =
+            new_session_with(short_timeouts(), [0; 8], [0; 20]).await;
+        let session_task = run(session, local_reader);
+
+        remote.recv().await;
+        remote.send(has_first()).await;
+        remote.send(PeerMsg::Unchoke).await;
+        assert_eq!(remote.recv().await, PeerMsg::Interested);
+        assert_eq!(remote.recv().await, BlockReq::new(0, 0, 16 * 1024).into());
+        assert_eq!(
+            remote.recv().await,
+            BlockReq::new(0, 16 * 1024, 16 * 1024).into()
+        );
+
+        let start = Instant::now();
+        let _reader = remote.keep_alive();
+        let err = session_task.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "request timed out");
+        assert_about(start.elapsed(), short_timeouts().timeouts.request);
+
+        // both blocks are back in the picker
+        let bitfield = Bitfield::from_bytes(vec![0b1000_0000], 2);
+        let mut picker = swarm.picker();
+        assert!(picker.pick(1, &bitfield).is_some());
+        assert!(picker.pick(1, &bitfield).is_some());
+        assert!(picker.pick(1, &bitfield).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_choke_and_unchoke() {
+        let (_dir, swarm, mut remote, session, local_reader) = new_session().await;
+        let session_task = run(session, local_reader);
+
+        remote.recv().await;
+        remote.send(has_first()).await;
+        remote.send(PeerMsg::Unchoke).await;
+        for _ in 0..3 {
+            remote.recv().await;
+        }
+
+        remote.send(PeerMsg::Choke).await;
+        sleep(Duration::from_millis(100)).await;
+
+        // the blocks are given back, so the session's own key (0) could
+        // request them again, but nothing new is requested while choked
+        let bitfield = Bitfield::from_bytes(vec![0b1000_0000], 2);
+        let blocks: Vec<_> = (0..2)
+            .map(|_| swarm.picker().pick(0, &bitfield).unwrap())
+            .collect();
+        for block in blocks {
+            swarm.picker().unrequest(0, block);
+        }
+        let mut buf = [0; 1];
+        assert!(
+            timeout(Duration::from_millis(200), remote.reader.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        remote.send(PeerMsg::Unchoke).await;
+        assert_eq!(remote.recv().await, BlockReq::new(0, 0, 16 * 1024).into());
+        assert_eq!(
+            remote.recv().await,
+            BlockReq::new(0, 16 * 1024, 16 * 1024).into()
+        );
+
+        session_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_serve_requests() {
+        let (_dir, swarm, mut remote, session, local_reader) = new_session().await;
+        let session_task = run(session, local_reader);
+
+        assert_eq!(remote.recv().await, PeerMsg::Bitfield(vec![0b0100_0000]));
+        remote.send(PeerMsg::Interested).await;
+        sleep(Duration::from_millis(100)).await;
+        assert!(swarm.get_transfers()[&0].peer_interested);
+        swarm.send(0, PeerCmd::Unchoke);
+        assert_eq!(remote.recv().await, PeerMsg::Unchoke);
+
+        // a piece we don't have is ignored
+        remote.send(BlockReq::new(0, 0, 16 * 1024).into()).await;
+        remote.send(BlockReq::new(1, 16 * 1024, 4).into()).await;
+        assert_eq!(
+            remote.recv().await,
+            PeerMsg::Piece {
+                idx: 1,
+                begin: 16 * 1024,
+                bytes: vec![1; 4]
+            }
+        );
+
+        assert_eq!(swarm.get_transfers()[&0].uploaded, 4);
+
+        // once choked, requests are ignored again
+        swarm.send(0, PeerCmd::Choke);
+        assert_eq!(remote.recv().await, PeerMsg::Choke);
+        remote.send(BlockReq::new(1, 0, 4).into()).await;
+        let mut buf = [0; 1];
+        assert!(
+            timeout(Duration::from_millis(200), remote.reader.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        remote.send(BlockReq::new(1, PIECE_LEN - 2, 4).into()).await;
+        let err = session_task.await.unwrap().unwrap_err();
+        assert!(err.to_string().starts_with("invalid request"));
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_block() {
+        let (_dir, swarm, mut remote, session, local_reader) = new_session().await;
+        let session_task = run(session, local_reader);
+
+        remote.recv().await;
+        remote.send(has_first()).await;
+        assert_eq!(remote.recv().await, PeerMsg::Interested);
+        let bytes = vec![0; 16 * 1024];
+        let (idx, begin) = (0, 0);
+        remote.send(PeerMsg::Piece { idx, begin, bytes }).await;
+        remote.send(PeerMsg::Unchoke).await;
+        assert_eq!(remote.recv().await, BlockReq::new(0, 0, 16 * 1024).into());
+        remote.recv().await;
+
+        // too short, then the one asked for
+        let bytes = vec![0; 100];
+        remote.send(PeerMsg::Piece { idx, begin, bytes }).await;
+        let (begin, bytes) = (16 * 1024, vec![0; 16 * 1024]);
+        remote.send(PeerMsg::Piece { idx, begin, bytes }).await;
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(swarm.get_transfers()[&0].downloaded, 16 * 1024);
+        let mut picker = swarm.picker();
+        assert_eq!(picker.block_received(0, 0, 16 * 1024), None);
+        assert_eq!(picker.block_received(0, 0, 0), Some(vec![]));
+        drop(picker);
+
+        session_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_cancel_request() {
+        let (_dir, _, _remote, mut session, _) = new_session().await;
+
+        session.handle_cmd(PeerCmd::Unchoke).await.unwrap();
+        let block = BlockReq::new(1, 0, 16 * 1024);
+        session.handle_msg(block.into()).await.unwrap();
+        assert_eq!(session.requests, [block]);
+
+        session.handle_msg(block.to_cancel()).await.unwrap();
+        assert!(session.requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_fast_reject() {
+        let (_dir, _, mut remote, session, local_reader) =
+            new_session_with(Config::default(), FAST, [0; 20]).await;
+        let session_task = run(session, local_reader);
+
+        assert_eq!(remote.recv().await, PeerMsg::Bitfield(vec![0b0100_0000]));
+        remote.send(PeerMsg::HaveAll).await;
+        remote.send(PeerMsg::Unchoke).await;
+        assert_eq!(remote.recv().await, PeerMsg::Interested);
+        let block = BlockReq::new(0, 0, 16 * 1024);
+        assert_eq!(remote.recv().await, block.into());
+        remote.recv().await;
+
+        // a rejected block is requested again right away
+        remote.send(block.to_reject()).await;
+        assert_eq!(remote.recv().await, block.into());
+
+        // a choke alone doesn't cancel anything
+        remote.send(PeerMsg::Choke).await;
+        remote.send(block.to_reject()).await;
+        let mut buf = [0; 1];
+        assert!(
+            timeout(Duration::from_millis(200), remote.reader.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        session_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_fast_peer_allowed_fast() {
+        let (_dir, _, mut remote, session, local_reader) =
+            new_session_with(Config::default(), FAST, [0; 20]).await;
+        let session_task = run(session, local_reader);
+
+        remote.recv().await;
+        remote.send(PeerMsg::HaveAll).await;
+        remote.send(PeerMsg::AllowedFast(0)).await;
+        assert_eq!(remote.recv().await, PeerMsg::Interested);
+        // still choked, but piece 0 is allowed
+        assert_eq!(remote.recv().await, BlockReq::new(0, 0, 16 * 1024).into());
+
+        session_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_fast_serve_allowed_fast() {
+        let config = Config {
+            allowed_fast: 1,
+            ..Default::default()
+        };
+        // an info hash whose allowed fast set for this address is piece 1
+        let info_hash = (0..=255)
+            .map(|byte| [byte; 20])
+            .find(|info_hash| get_allowed_fast_set(ADDR.0.into(), info_hash, 2, 1) == [1])
+            .unwrap();
+        let (_dir, _, mut remote, session, local_reader) =
+            new_session_with(config, FAST, info_hash).await;
+        let session_task = run(session, local_reader);
+
+        assert_eq!(remote.recv().await, PeerMsg::Bitfield(vec![0b0100_0000]));
+        assert_eq!(remote.recv().await, PeerMsg::AllowedFast(1));
+        remote.send(PeerMsg::HaveNone).await;
+
+        // served although choked, and anything else is rejected
+        let block = BlockReq::new(0, 0, 4);
+        remote.send(block.into()).await;
+        assert_eq!(remote.recv().await, block.to_reject());
+        remote.send(BlockReq::new(1, 0, 4).into()).await;
+        assert_eq!(
+            remote.recv().await,
+            PeerMsg::Piece {
+                idx: 1,
+                begin: 0,
+                bytes: vec![1; 4]
+            }
+        );
+
+        session_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_fast_not_negotiated() {
+        let (_dir, _, _remote, mut session, _) = new_session().await;
+
+        let err = session.handle_msg(PeerMsg::HaveAll).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "fast extension message without negotiating it"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_extension_handshake() {
+        let (_dir, _, mut remote, session, local_reader) =
+            new_session_with(Config::default(), EXTENSIONS, [0; 20]).await;
+        let session_task = run(session, local_reader);
+
+        remote.recv().await;
+        let PeerMsg::Extended { id: 0, payload } = remote.recv().await else {
+            panic!("expected an extension handshake");
+        };
+        let handshake = ExtensionHandshake::decode(&payload).unwrap();
+        assert_eq!(handshake.p, Some(6881));
+        assert_eq!(handshake.reqq, Some(256));
+        assert_eq!(handshake.yourip, Some(ADDR.0.into()));
+
+        // their reqq limits how many requests we pipeline
+        let handshake = ExtensionHandshake {
+            reqq: Some(1),
+            ..Default::default()
+        };
+        remote
+            .send(PeerMsg::Extended {
+                id: 0,
+                payload: handshake.encode(),
+            })
+            .await;
+        remote.send(has_first()).await;
+        remote.send(PeerMsg::Unchoke).await;
+        assert_eq!(remote.recv().await, PeerMsg::Interested);
+        assert_eq!(remote.recv().await, BlockReq::new(0, 0, 16 * 1024).into());
+        let mut buf = [0; 1];
+        assert!(
+            timeout(Duration::from_millis(200), remote.reader.read(&mut buf))
+                .await
+                .is_err()
+        );
+
+        session_task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_extensions_not_negotiated() {
+        let (_dir, _, _remote, mut session, _) = new_session().await;
+
+        let msg = PeerMsg::Extended {
+            id: 0,
+            payload: b"d1:mdee".to_vec(),
+        };
+        let err = session.handle_msg(msg).await.unwrap_err();
+        assert_eq!(err.to_string(), "extension message without negotiating it");
+    }
+
+    #[tokio::test]
+    async fn test_bad_block() {
+        // a v2 file over both pieces
+        let data: Vec<u8> = (0..2 * PIECE_LEN).map(|idx| (idx / 5) as u8).collect();
+        let blocks = get_block_hashes(&data);
+        let root = get_file_root(&blocks);
+        let file = Value::dict([
+            ("length", (data.len() as i64).into()),
+            ("pieces root", root.as_slice().into()),
+        ]);
+        let info = Value::dict([
+            ("file tree", Value::dict([("a", Value::dict([("", file)]))])),
+            ("meta version", 2.into()),
+            ("name", "a".into()),
+            ("piece length", (PIECE_LEN as i64).into()),
+        ])
+        .encode();
+        let metainfo = Metainfo::from_info("", &info).unwrap();
+        let block_hashes = BlockHashes::new(&metainfo.info);
+        let (_dir, swarm, mut remote, session, local_reader) =
+            new_session_with_hashes(Config::default(), V2, [0; 20], block_hashes).await;
+        let session_task = run(session, local_reader);
+
+        remote.recv().await;
+        remote.send(has_first()).await;
+        remote.send(PeerMsg::Unchoke).await;
+        assert_eq!(remote.recv().await, PeerMsg::Interested);
+        // the hashes of the piece's blocks are asked for once
+        let PeerMsg::HashRequest(req) = remote.recv().await else {
+            panic!("expected a hash request");
+        };
+        assert_eq!((req.index, req.length, req.proof_layers), (0, 2, 1));
+        let first = BlockReq::new(0, 0, 16 * 1024);
+        let second = BlockReq::new(0, 16 * 1024, 16 * 1024);
+        assert_eq!(remote.recv().await, first.into());
+        assert_eq!(remote.recv().await, second.into());
+
+        let hashes = vec![blocks[0], blocks[1], hash_pair(&blocks[2], &blocks[3])];
+        remote.send(PeerMsg::Hashes { req, hashes }).await;
+        let block = |begin: usize| data[begin..begin + 16 * 1024].to_vec();
+        let (idx, begin) = (0, 0);
+        let bytes = block(0);
+        remote.send(PeerMsg::Piece { idx, begin, bytes }).await;
+        let (begin, bytes) = (16 * 1024, block(0));
+        remote.send(PeerMsg::Piece { idx, begin, bytes }).await;
+
+        let err = session_task.await.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "bad block 16384 of piece 0");
+        // only the bad one is left for others
+        let bitfield = Bitfield::from_bytes(vec![0b1000_0000], 2);
+        let mut picker = swarm.picker();
+        assert_eq!(picker.pick(1, &bitfield), Some(second));
+        assert_eq!(picker.pick(1, &bitfield), None);
+    }
+}
@@ -0,0 +1,226 @@
+use std::{
+    collections::HashMap,
+    mem::take,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, OnceLock,
+    },
+};
+
+use anyhow::{ensure, Result};
+use tokio::{
+    sync::{
+        futures::Notified,
+        mpsc::{unbounded_channel, Sender, UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+    time::Instant,
+};
+
+use crate::storage::Storage;
+
+use super::{
+    bitfield::Bitfield, block_hashes::BlockHashes, config::Config, parts::BlockResp, peer::PeerCmd,
+    piece_picker::PiecePicker,
+};
+
+pub type PeerKey = usize;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    pub am_interested: bool,
+    pub peer_interested: bool,
+    pub last_block: Instant,
+}
+
+struct PeerHandle {
+    peer_id: Vec<u8>,
+    // where the peer accepts connections, if known
+    listen_addr: Option<SocketAddr>,
+    cmd_sender: UnboundedSender<PeerCmd>,
+    transfer: Transfer,
+}
+
+pub struct Swarm {
+    picker: Mutex<PiecePicker>,
+    block_hashes: Mutex<BlockHashes>,
+    storage: Storage,
+    block_resp_senders: Vec<Sender<BlockResp>>,
+    config: Config,
+    // the info dict, for peers that only have a magnet link
+    metadata: Arc<OnceLock<Vec<u8>>>,
+    have: Mutex<Bitfield>,
+    changed: Notify,
+    peers: Mutex<HashMap<PeerKey, PeerHandle>>,
+    next_peer_key: AtomicUsize,
+    // addresses learned from other peers, not yet connected to
+    candidates: Mutex<Vec<SocketAddr>>,
+}
+
+impl Swarm {
+    pub fn new(
+        picker: PiecePicker,
+        block_hashes: BlockHashes,
+        storage: Storage,
+        block_resp_senders: Vec<Sender<BlockResp>>,
+        config: Config,
+        metadata: Vec<u8>,
+    ) -> Self {
+        let have = Bitfield::new(storage.get_no_pieces());
+        Self {
+            picker: Mutex::new(picker),
+            block_hashes: Mutex::new(block_hashes),
+            storage,
+            block_resp_senders,
+            config,
+            metadata: Arc::new(OnceLock::from(metadata)),
+            have: Mutex::new(have),
+            changed: Notify::new(),
+            peers: Mutex::new(HashMap::new()),
+            next_peer_key: AtomicUsize::new(0),
+            candidates: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn picker(&self) -> MutexGuard<'_, PiecePicker> {
+        self.picker.lock().unwrap()
+    }
+
+    pub fn block_hashes(&self) -> MutexGuard<'_, BlockHashes> {
+        self.block_hashes.lock().unwrap()
+    }
+
+    pub fn storage(&self) -> &Storage {
+        &self.storage
+    }
+
+    pub fn block_resp_sender(&self, idx: u32) -> &Sender<BlockResp> {
+        &self.block_resp_senders[idx as usize]
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn metadata(&self) -> Arc<OnceLock<Vec<u8>>> {
+        self.metadata.clone()
+    }
+
+    pub fn have(&self) -> Bitfield {
+        self.have.lock().unwrap().clone()
+    }
+
+    // Called once a piece is verified and stored, so it can be served.
+    pub fn add_have(&self, idx: u32) {
+        self.have.lock().unwrap().set(idx);
+        for peer in self.peers.lock().unwrap().values() {
+            let _ = peer.cmd_sender.send(PeerCmd::Have(idx));
+        }
+    }
+
+    // Must be created before inspecting the picker, so no change in
+    // between is missed.
+    pub fn changed(&self) -> Notified<'_> {
+        self.changed.notified()
+    }
+
+    pub fn notify(&self) {
+        self.changed.notify_waiters();
+    }
+
+    pub fn add_peer(
+        &self,
+        peer_id: &[u8],
+        listen_addr: Option<SocketAddr>,
+    ) -> Result<(PeerKey, UnboundedReceiver<PeerCmd>)> {
+        ensure!(
+            peer_id != self.config.peer_id.as_bytes(),
+            "connected to ourselves"
+        );
+        let mut peers = self.peers.lock().unwrap();
+        ensure!(
+            peers.values().all(|peer| peer.peer_id != peer_id),
+            "duplicate peer id"
+        );
+        ensure!(peers.len() < self.config.max_peers, "too many peers");
+
+        let key = self.new_key();
+        let (cmd_sender, cmd_receiver) = unbounded_channel();
+        let transfer = Transfer {
+            downloaded: 0,
+            uploaded: 0,
+            am_interested: false,
+            peer_interested: false,
+            last_block: Instant::now(),
+        };
+        let peer = PeerHandle {
+            peer_id: peer_id.to_vec(),
+            listen_addr,
+            cmd_sender,
+            transfer,
+        };
+        peers.insert(key, peer);
+        Ok((key, cmd_receiver))
+    }
+
+    // Also for sources that aren't peers, like web seeds.
+    pub fn new_key(&self) -> PeerKey {
+        self.next_peer_key.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn remove_peer(&self, key: PeerKey) {
+        self.peers.lock().unwrap().remove(&key);
+        self.block_hashes().remove_peer(key);
+        self.notify();
+    }
+
+    pub fn get_no_peers(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn set_listen_addr(&self, key: PeerKey, addr: SocketAddr) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&key) {
+            peer.listen_addr = Some(addr);
+        }
+    }
+
+    pub fn get_listen_addrs(&self) -> HashMap<PeerKey, SocketAddr> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .filter_map(|(key, peer)| Some((*key, peer.listen_addr?)))
+            .collect()
+    }
+
+    pub fn add_candidates(&self, addrs: impl IntoIterator<Item = SocketAddr>) {
+        self.candidates.lock().unwrap().extend(addrs);
+        self.notify();
+    }
+
+    pub fn take_candidates(&self) -> Vec<SocketAddr> {
+        take(&mut self.candidates.lock().unwrap())
+    }
+
+    pub fn update_transfer(&self, key: PeerKey, update: impl FnOnce(&mut Transfer)) {
+        if let Some(peer) = self.peers.lock().unwrap().get_mut(&key) {
+            update(&mut peer.transfer);
+        }
+    }
+
+    pub fn get_transfers(&self) -> HashMap<PeerKey, Transfer> {
+        let peers = self.peers.lock().unwrap();
+        peers
+            .iter()
+            .map(|(key, peer)| (*key, peer.transfer))
+            .collect()
+    }
+
+    pub fn send(&self, key: PeerKey, cmd: PeerCmd) {
+        if let Some(peer) = self.peers.lock().unwrap().get(&key) {
+            let _ = peer.cmd_sender.send(cmd);
+        }
+    }
+}
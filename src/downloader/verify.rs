@@ -0,0 +1,422 @@
+use std::{
+    borrow::Cow,
+    fmt::{self, Display},
+    path::{Path, PathBuf},
+    thread::{self, available_parallelism},
+};
+
+use anyhow::{ensure, Result};
+use serde_json::json;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    merkle::{get_block_hashes, get_file_root, get_layer_root, get_piece_root},
+    metainfo::{Metainfo, TreeFile},
+    storage::{read_range, DataFile},
+};
+
+pub struct FileReport {
+    // with the torrent's directory in front, for multi-file torrents
+    pub path: String,
+    pub length: u64,
+    pub ok: bool,
+}
+
+// How data compares to a torrent, piece by piece and file by file.
+pub struct Report {
+    pub pieces: Vec<bool>,
+    pub files: Vec<FileReport>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.pieces.iter().all(|ok| *ok)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let files: Vec<_> = (self.files.iter())
+            .map(|file| json!({ "path": file.path, "length": file.length, "ok": file.ok }))
+            .collect();
+        json!({ "ok": self.is_ok(), "pieces": self.pieces, "files": files })
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, ok) in self.pieces.iter().enumerate() {
+            if !ok {
+                writeln!(f, "piece {}: mismatch", idx)?;
+            }
+        }
+        let no_ok = self.pieces.iter().filter(|ok| **ok).count();
+        writeln!(f, "{} of {} pieces ok", no_ok, self.pieces.len())?;
+        for file in &self.files {
+            let status = if file.ok { "ok" } else { "mismatch" };
+            writeln!(f, "{}: {}", file.path, status)?;
+        }
+        Ok(())
+    }
+}
+
+// Checks the data at `data_path`. A file is taken to hold all of it back
+// to back, the way `download` stores it. A directory holds the torrent's
+// file or directory by its name, each file at its own path, the way it is
+// shared. Files that are missing or short there count as mismatches.
+// v2 files are checked against their merkle trees as well.
+pub fn verify(data_path: &str, metainfo: &Metainfo) -> Result<Report> {
+    let info = &metainfo.info;
+    let data_path = Path::new(data_path);
+    ensure!(data_path.exists(), "{} doesn't exist", data_path.display());
+    let root = data_path.join(info.name.as_ref());
+    let layout = match data_path.is_dir() {
+        true => Layout::Files(root),
+        false => Layout::Single(data_path.to_path_buf()),
+    };
+
+    let no_pieces = info.length.div_ceil(info.piece_length as u64) as usize;
+    let mut pieces = match info.piece_hashes.is_empty() {
+        true => vec![true; no_pieces],
+        false => {
+            let files = layout.get_files(metainfo);
+            on_all_cores(no_pieces, |idx| {
+                let start = idx as u64 * info.piece_length as u64;
+                let len = metainfo.get_piece_len(idx as u32) as u64;
+                read_range(&files, start, len).is_ok_and(|bytes| {
+                    <[u8; 20]>::from(Sha1::digest(&bytes)) == info.piece_hashes[idx]
+                })
+            })
+        }
+    };
+    let tree: Vec<_> = (info.file_tree.iter())
+        .map(|file| check_file(&layout, metainfo, file))
+        .collect();
+
+    if tree.is_empty() {
+        return Ok(Report {
+            files: get_files(metainfo, &pieces),
+            pieces,
+        });
+    }
+    let piece_length = info.piece_length as u64;
+    for (file, (_, file_pieces)) in info.file_tree.iter().zip(&tree) {
+        let first = (file.start / piece_length) as usize;
+        for (idx, ok) in file_pieces.iter().enumerate() {
+            pieces[first + idx] &= ok;
+        }
+    }
+    let files = (info.file_tree.iter().zip(tree))
+        .map(|(file, (ok, file_pieces))| {
+            let first = (file.start / piece_length) as usize;
+            let ok = ok
+                && pieces[first..first + file_pieces.len()]
+                    .iter()
+                    .all(|ok| *ok);
+            FileReport {
+                path: get_shown_path(metainfo, &file.path),
+                length: file.length,
+                ok,
+            }
+        })
+        .collect();
+    Ok(Report { pieces, files })
+}
+
+enum Layout {
+    // all the data in one file
+    Single(PathBuf),
+    // the torrent's file or directory
+    Files(PathBuf),
+}
+
+impl Layout {
+    // The files the v1 pieces run across.
+    fn get_files(&self, metainfo: &Metainfo) -> Vec<DataFile> {
+        let info = &metainfo.info;
+        let (root, files) = match (self, &info.files) {
+            (Self::Files(root), Some(files)) => (root, files),
+            (Self::Single(path) | Self::Files(path), _) => {
+                return vec![DataFile {
+                    full_path: Some(path.clone()),
+                    path: vec![],
+                    length: info.length,
+                }];
+            }
+        };
+        (files.iter())
+            .map(|file| DataFile {
+                full_path: (!file.pad).then(|| join(root, &file.path)),
+                path: file.path.iter().map(|part| part.to_string()).collect(),
+                length: file.length,
+            })
+            .collect()
+    }
+
+    // Where a v2 file is, and the offset of its data there.
+    fn get_tree_file(&self, metainfo: &Metainfo, file: &TreeFile) -> (DataFile, u64) {
+        let (full_path, start) = match self {
+            Self::Single(path) => (path.clone(), file.start),
+            Self::Files(root) if is_single(metainfo) => (root.clone(), 0),
+            Self::Files(root) => (join(root, &file.path), 0),
+        };
+        let data_file = DataFile {
+            full_path: Some(full_path),
+            path: file.path.iter().map(|part| part.to_string()).collect(),
+            length: start + file.length,
+        };
+        (data_file, start)
+    }
+}
+
+fn join(root: &Path, path: &[Cow<str>]) -> PathBuf {
+    path.iter()
+        .fold(root.to_path_buf(), |path, part| path.join(part.as_ref()))
+}
+
+// A v2 torrent of one file, named like the torrent.
+fn is_single(metainfo: &Metainfo) -> bool {
+    let tree = &metainfo.info.file_tree;
+    tree.len() == 1 && tree[0].path == [metainfo.info.name.as_ref()]
+}
+
+// with the torrent's directory in front, for multi-file torrents
+fn get_shown_path(metainfo: &Metainfo, path: &[Cow<str>]) -> String {
+    let info = &metainfo.info;
+    match info.files.is_none() && (info.file_tree.is_empty() || is_single(metainfo)) {
+        true => info.name.to_string(),
+        false => format!("{}/{}", info.name, path.join("/")),
+    }
+}
+
+// Each thread takes every n-th index, like hashing when creating.
+fn on_all_cores<T: Default + Send>(len: usize, f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let parallel = available_parallelism().map_or(1, usize::from);
+    let mut results: Vec<T> = (0..len).map(|_| T::default()).collect();
+    thread::scope(|scope| {
+        let workers: Vec<_> = (0..parallel)
+            .map(|first| {
+                let f = &f;
+                scope.spawn(move || {
+                    (first..len)
+                        .step_by(parallel)
+                        .map(|idx| (idx, f(idx)))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        for worker in workers {
+            for (idx, result) in worker.join().unwrap() {
+                results[idx] = result;
+            }
+        }
+    });
+    results
+}
+
+// Whether a v2 file matches its pieces root, and which of its pieces are
+// fine. Pieces are told apart by the piece layer, if the torrent has one
+// that fits the root.
+fn check_file(layout: &Layout, metainfo: &Metainfo, file: &TreeFile) -> (bool, Vec<bool>) {
+    let Some(root) = file.pieces_root else {
+        return (true, vec![]);
+    };
+    let piece_length = metainfo.info.piece_length;
+    let no_pieces = file.length.div_ceil(piece_length as u64) as usize;
+    let (data_file, start) = layout.get_tree_file(metainfo, file);
+    let data_files = [data_file];
+    let blocks = on_all_cores(no_pieces, |idx| {
+        let offset = idx as u64 * piece_length as u64;
+        let len = (file.length - offset).min(piece_length as u64);
+        let bytes = read_range(&data_files, start + offset, len).ok()?;
+        Some(get_block_hashes(&bytes))
+    });
+
+    let all: Option<Vec<_>> = blocks.iter().cloned().collect();
+    let ok = all.is_some_and(|all| get_file_root(&all.concat()) == root);
+    let layer = (metainfo.piece_layers.get(&root))
+        .filter(|layer| layer.len() == no_pieces && get_layer_root(layer, piece_length) == root);
+    let pieces = (blocks.iter().enumerate())
+        .map(|(idx, blocks)| match (blocks, layer) {
+            (None, _) => false,
+            (Some(blocks), Some(layer)) => get_piece_root(blocks, piece_length) == layer[idx],
+            (Some(_), None) => ok,
+        })
+        .collect();
+    (ok, pieces)
+}
+
+// A file is ok if all the pieces it overlaps are. Padding isn't shown.
+fn get_files(metainfo: &Metainfo, pieces: &[bool]) -> Vec<FileReport> {
+    let info = &metainfo.info;
+    let files: Vec<_> = match &info.files {
+        Some(files) => (files.iter())
+            .map(|file| (get_shown_path(metainfo, &file.path), file.length, file.pad))
+            .collect(),
+        None => vec![(info.name.to_string(), info.length, false)],
+    };
+    let piece_length = info.piece_length as u64;
+    let mut start = 0;
+    (files.into_iter())
+        .filter_map(|(path, length, pad)| {
+            let end = start + length;
+            let mut overlapped = (start / piece_length)..end.div_ceil(piece_length);
+            let ok = length == 0 || overlapped.all(|idx| pieces[idx as usize]);
+            start = end;
+            (!pad).then_some(FileReport { path, length, ok })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs};
+
+    use sha1::{Digest, Sha1};
+    use tempfile::tempdir;
+
+    use crate::{
+        bencoding::Value,
+        merkle::{get_block_hashes, get_file_root, get_piece_root},
+        metainfo::Metainfo,
+    };
+
+    use super::verify;
+
+    #[test]
+    fn test_verify() {
+        let data: Vec<u8> = (0..10).collect();
+        let mut torrent = b"d8:announce0:4:infod5:filesl".to_vec();
+        for (path, length) in [("a", 3), ("b", 0), ("c", 7)] {
+            torrent.extend(format!("d6:lengthi{}e4:pathl1:{}ee", length, path).as_bytes());
+        }
+        let hashes: Vec<u8> = (data.chunks(4))
+            .flat_map(|piece| <[u8; 20]>::from(Sha1::digest(piece)))
+            .collect();
+        torrent.extend(b"e4:name3:dir12:piece lengthi4e6:pieces60:");
+        torrent.extend(hashes);
+        torrent.extend(b"ee");
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+        let path = path.to_str().unwrap();
+        assert!(verify(path, &metainfo).is_err());
+
+        fs::write(path, &data).unwrap();
+        let report = verify(path, &metainfo).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.files.len(), 3);
+        assert_eq!(report.files[2].path, "dir/c");
+
+        // in the second piece, which only c overlaps
+        let mut corrupt = data.clone();
+        corrupt[5] = 0;
+        fs::write(path, &corrupt).unwrap();
+        let report = verify(path, &metainfo).unwrap();
+        assert!(!report.is_ok());
+        assert_eq!(report.pieces, [true, false, true]);
+        let ok: Vec<_> = report.files.iter().map(|file| file.ok).collect();
+        assert_eq!(ok, [true, true, false]);
+        assert_eq!(report.to_json()["pieces"][1], false);
+
+        // too short
+        fs::write(path, &data[..9]).unwrap();
+        let report = verify(path, &metainfo).unwrap();
+        assert_eq!(report.pieces, [true, true, false]);
+
+        // the files under a directory, the way they are shared
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("dir")).unwrap();
+        fs::write(tree.join("dir/a"), &data[..3]).unwrap();
+        fs::write(tree.join("dir/b"), []).unwrap();
+        fs::write(tree.join("dir/c"), &data[3..]).unwrap();
+        let tree = tree.to_str().unwrap();
+        let report = verify(tree, &metainfo).unwrap();
+        assert!(report.is_ok());
+
+        // c is short
+        fs::write(dir.path().join("tree/dir/c"), &data[3..9]).unwrap();
+        let report = verify(tree, &metainfo).unwrap();
+        assert_eq!(report.pieces, [true, true, false]);
+        let ok: Vec<_> = report.files.iter().map(|file| file.ok).collect();
+        assert_eq!(ok, [true, true, false]);
+
+        // a is missing
+        fs::remove_file(dir.path().join("tree/dir/a")).unwrap();
+        let report = verify(tree, &metainfo).unwrap();
+        assert_eq!(report.pieces, [false, true, false]);
+        let ok: Vec<_> = report.files.iter().map(|file| file.ok).collect();
+        assert_eq!(ok, [false, true, false]);
+    }
+
+    #[test]
+    fn test_verify_v2() {
+        let piece_length = 32 * 1024;
+        let a = vec![1; 3];
+        let b: Vec<u8> = (0..40_000).map(|idx| idx as u8).collect();
+        let (a_blocks, b_blocks) = (get_block_hashes(&a), get_block_hashes(&b));
+        let (a_root, b_root) = (get_file_root(&a_blocks), get_file_root(&b_blocks));
+        let layer = [
+            get_piece_root(&b_blocks[..2], piece_length),
+            get_piece_root(&b_blocks[2..], piece_length),
+        ]
+        .concat();
+
+        let file = |length: usize, root: [u8; 32]| {
+            let file = Value::dict([
+                ("length", (length as i64).into()),
+                ("pieces root", root.as_slice().into()),
+            ]);
+            Value::dict([("", file)])
+        };
+        let tree = Value::dict([("a", file(3, a_root)), ("b", file(40_000, b_root))]);
+        let info = Value::dict([
+            ("file tree", tree),
+            ("meta version", 2.into()),
+            ("name", "dir".into()),
+            ("piece length", (piece_length as i64).into()),
+        ]);
+        let layers = Value::Dict(BTreeMap::from([(b_root.to_vec(), layer.into())]));
+        let torrent = Value::dict([("info", info.clone()), ("piece layers", layers)]).encode();
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("data");
+        let path = path.to_str().unwrap();
+        let mut data = vec![0; 32 * 1024];
+        data[..3].copy_from_slice(&a);
+        data.extend(&b);
+        fs::write(path, &data).unwrap();
+        let report = verify(path, &metainfo).unwrap();
+        assert!(report.is_ok());
+        assert_eq!(report.pieces.len(), 3);
+        assert_eq!(report.files[1].path, "dir/b");
+
+        // the layer tells which piece of b is bad
+        data[32 * 1024 + 35_000] ^= 1;
+        fs::write(path, &data).unwrap();
+        let report = verify(path, &metainfo).unwrap();
+        assert_eq!(report.pieces, [true, true, false]);
+        let ok: Vec<_> = report.files.iter().map(|file| file.ok).collect();
+        assert_eq!(ok, [true, false]);
+
+        // the files under a directory, the way they are shared
+        let tree = dir.path().join("tree");
+        fs::create_dir_all(tree.join("dir")).unwrap();
+        fs::write(tree.join("dir/a"), &a).unwrap();
+        fs::write(tree.join("dir/b"), &b).unwrap();
+        let tree = tree.to_str().unwrap();
+        assert!(verify(tree, &metainfo).unwrap().is_ok());
+        fs::write(dir.path().join("tree/dir/b"), &b[..39_000]).unwrap();
+        let report = verify(tree, &metainfo).unwrap();
+        assert_eq!(report.pieces, [true, true, false]);
+        fs::remove_file(dir.path().join("tree/dir/a")).unwrap();
+        let report = verify(tree, &metainfo).unwrap();
+        assert_eq!(report.pieces, [false, true, false]);
+
+        // without it, all of b is
+        let torrent = Value::dict([("info", info)]).encode();
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        let report = verify(path, &metainfo).unwrap();
+        assert_eq!(report.pieces, [true, false, false]);
+    }
+}
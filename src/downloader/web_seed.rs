@@ -0,0 +1,544 @@
+use std::{
+    borrow::Cow,
+    error::Error,
+    fmt::{self, Display},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use reqwest::{
+    header::{RANGE, RETRY_AFTER},
+    Client, Response, StatusCode, Url,
+};
+use tokio::time::sleep;
+
+use crate::{metainfo::Info, storage::Storage};
+
+use super::{
+    bitfield::Bitfield,
+    parts::{BlockReq, BlockResp},
+    peer::PeerCmd,
+    swarm::{PeerKey, Swarm},
+};
+
+const MIN_BACKOFF: Duration = Duration::from_secs(5);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+struct WebFile {
+    url: Url,
+    // where the file is in the torrent's data
+    start: u64,
+    length: u64,
+}
+
+enum Source {
+    // the torrent's files (BEP 19), read with range requests
+    Files(Vec<WebFile>),
+    // a script serving pieces by index (BEP 17)
+    Pieces { url: Url, info_hash: [u8; 20] },
+}
+
+// The server asked us to come back later.
+#[derive(Debug)]
+struct RetryAfter(Duration);
+
+impl Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "busy for {}s", self.0.as_secs())
+    }
+}
+
+impl Error for RetryAfter {}
+
+// An HTTP server that counts as a peer with every piece. The blocks picked
+// for it are fetched in runs.
+pub struct WebSeed {
+    url: String,
+    source: Source,
+    client: Client,
+}
+
+impl WebSeed {
+    pub fn new(url: &str, info: &Info, timeout: Duration) -> Result<Self> {
+        let base = Url::parse(url).with_context(|| format!("invalid web seed {}", url))?;
+        let get_url = |path: &[Cow<str>]| -> Result<Url> {
+            let mut url = base.clone();
+            (url.path_segments_mut())
+                .map_err(|_| anyhow!("invalid web seed {}", base))?
+                .pop_if_empty()
+                .push(&info.name)
+                .extend(path);
+            Ok(url)
+        };
+        let files = match &info.files {
+            // a URL ending with a slash names the directory of the file
+            None if url.ends_with('/') => vec![(get_url(&[])?, info.length)],
+            None => vec![(base.clone(), info.length)],
+            Some(files) => (files.iter())
+                .map(|file| Ok((get_url(&file.path)?, file.length)))
+                .collect::<Result<_>>()?,
+        };
+        let mut start = 0;
+        let files = (files.into_iter())
+            .map(|(url, length)| {
+                start += length;
+                WebFile {
+                    url,
+                    start: start - length,
+                    length,
+                }
+            })
+            .collect();
+        Ok(Self {
+            url: url.to_string(),
+            source: Source::Files(files),
+            client: Client::builder().timeout(timeout).build()?,
+        })
+    }
+
+    pub fn new_http_seed(url: &str, info_hash: [u8; 20], timeout: Duration) -> Result<Self> {
+        Ok(Self {
+            url: url.to_string(),
+            source: Source::Pieces {
+                url: Url::parse(url).with_context(|| format!("invalid HTTP seed {}", url))?,
+                info_hash,
+            },
+            client: Client::builder().timeout(timeout).build()?,
+        })
+    }
+
+    // The blocks of the run follow each other in one piece.
+    async fn fetch(&self, run: BlockReq, storage: &Storage) -> Result<Vec<u8>> {
+        match &self.source {
+            Source::Files(files) => {
+                let start = storage.get_piece_start(run.piece_idx) + run.begin as u64;
+                self.fetch_files(files, start, run.len as u64).await
+            }
+            Source::Pieces { url, info_hash } => {
+                let whole = run.len == storage.get_piece_len(run.piece_idx);
+                let url = get_piece_url(url, info_hash, run, whole);
+                let resp = self.client.get(url.clone()).send().await?;
+                let resp = check_retry_after(resp).await?;
+                ensure!(
+                    resp.status() == StatusCode::OK,
+                    "{} answered {}",
+                    url,
+                    resp.status()
+                );
+                let body = resp.bytes().await?;
+                ensure!(
+                    body.len() == run.len as usize,
+                    "{} sent the wrong length",
+                    url
+                );
+                Ok(body.to_vec())
+            }
+        }
+    }
+
+    async fn fetch_files(&self, files: &[WebFile], start: u64, len: u64) -> Result<Vec<u8>> {
+        let end = start + len;
+        let mut bytes = Vec::with_capacity(len as usize);
+        for file in files {
+            let (from, to) = (start.max(file.start), end.min(file.start + file.length));
+            if from >= to {
+                continue;
+            }
+            let range = format!("bytes={}-{}", from - file.start, to - file.start - 1);
+            let resp = (self.client.get(file.url.clone()))
+                .header(RANGE, range)
+                .send()
+                .await?;
+            let resp = check_retry_after(resp).await?;
+            // some servers ignore ranges covering the whole file
+            let whole = to - from == file.length;
+            let status = resp.status();
+            ensure!(
+                status == StatusCode::PARTIAL_CONTENT || (status == StatusCode::OK && whole),
+                "{} answered {}",
+                file.url,
+                status
+            );
+            let body = resp.bytes().await?;
+            ensure!(
+                body.len() as u64 == to - from,
+                "{} sent the wrong length",
+                file.url
+            );
+            bytes.extend_from_slice(&body);
+        }
+        Ok(bytes)
+    }
+}
+
+fn get_piece_url(url: &Url, info_hash: &[u8; 20], run: BlockReq, whole: bool) -> Url {
+    let mut url = url.clone();
+    let mut query = url
+        .query()
+        .map_or(String::new(), |query| format!("{}&", query));
+    query += "info_hash=";
+    for byte in info_hash {
+        query += &format!("%{:02X}", byte);
+    }
+    query += &format!("&piece={}", run.piece_idx);
+    if !whole {
+        // inclusive, like HTTP ranges
+        query += &format!("&ranges={}-{}", run.begin, run.begin + run.len - 1);
+    }
+    url.set_query(Some(&query));
+    url
+}
+
+// A busy server answers 503, with the seconds to wait in the Retry-After
+// header or, for HTTP seeds, in the body.
+async fn check_retry_after(resp: Response) -> Result<Response> {
+    if resp.status() != StatusCode::SERVICE_UNAVAILABLE {
+        return Ok(resp);
+    }
+    let header = (resp.headers().get(RETRY_AFTER))
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let secs = match header {
+        Some(secs) => Some(secs),
+        None => (resp.text().await.ok()).and_then(|body| body.trim().parse().ok()),
+    };
+    match secs {
+        Some(secs) => Err(RetryAfter(Duration::from_secs(secs)).into()),
+        None => bail!("server unavailable"),
+    }
+}
+
+// Runs until the download is finished. A server that fails is left alone
+// for longer each time.
+pub async fn run_web_seed(web_seed: WebSeed, swarm: Arc<Swarm>) {
+    let key = swarm.new_key();
+    let no_pieces = swarm.storage().get_no_pieces();
+    let have = Bitfield::from_bytes(vec![0xff; no_pieces.div_ceil(8)], no_pieces);
+    swarm.picker().add_peer(&have);
+    let mut failures = 0;
+    loop {
+        let changed = swarm.changed();
+        if swarm.picker().is_finished() {
+            break;
+        }
+        let blocks = pick_run(&swarm, key, &have);
+        let (Some(first), Some(last)) = (blocks.first(), blocks.last()) else {
+            changed.await;
+            continue;
+        };
+        let len = last.begin + last.len - first.begin;
+        let run = BlockReq::new(first.piece_idx, first.begin, len);
+        match web_seed.fetch(run, swarm.storage()).await {
+            Ok(bytes) => {
+                failures = 0;
+                deliver(&swarm, key, blocks, bytes).await;
+            }
+            Err(err) => {
+                {
+                    let mut picker = swarm.picker();
+                    for block in blocks {
+                        picker.unrequest(key, block);
+                    }
+                }
+                swarm.notify();
+                let backoff = match err.downcast_ref::<RetryAfter>() {
+                    Some(RetryAfter(backoff)) => *backoff,
+                    None => {
+                        failures += 1;
+                        get_backoff(failures - 1)
+                    }
+                };
+                eprintln!(
+                    "web seed {} failed, retrying in {}s: {:#}",
+                    web_seed.url,
+                    backoff.as_secs(),
+                    err
+                );
+                sleep(backoff).await;
+            }
+        }
+    }
+    swarm.picker().remove_peer(&have);
+}
+
+// Blocks that follow each other in one piece, so one request covers them.
+fn pick_run(swarm: &Swarm, key: PeerKey, have: &Bitfield) -> Vec<BlockReq> {
+    let mut picker = swarm.picker();
+    let mut blocks: Vec<BlockReq> = vec![];
+    while let Some(block) = picker.pick(key, have) {
+        if let Some(last) = blocks.last() {
+            if (block.piece_idx, block.begin) != (last.piece_idx, last.begin + last.len) {
+                picker.unrequest(key, block);
+                break;
+            }
+        }
+        blocks.push(block);
+    }
+    blocks
+}
+
+async fn deliver(swarm: &Swarm, key: PeerKey, blocks: Vec<BlockReq>, bytes: Vec<u8>) {
+    let mut pos = 0;
+    for block in blocks {
+        let data = bytes[pos..pos + block.len as usize].to_vec();
+        pos += block.len as usize;
+        let others = swarm
+            .picker()
+            .block_received(key, block.piece_idx, block.begin);
+        let Some(others) = others else {
+            continue;
+        };
+        for other in others {
+            swarm.send(other, PeerCmd::Cancel(block));
+        }
+        let _ = swarm
+            .block_resp_sender(block.piece_idx)
+            .send(BlockResp::new(block.begin, data))
+            .await;
+    }
+}
+
+fn get_backoff(failures: u32) -> Duration {
+    MIN_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failures))
+        .min(MAX_BACKOFF)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use reqwest::Url;
+    use sha1::{Digest, Sha1};
+    use tempfile::tempdir;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        spawn,
+        sync::mpsc::{channel, unbounded_channel},
+        time::Instant,
+    };
+
+    use crate::{
+        downloader::{
+            block_hashes::BlockHashes, config::Config, parts::BlockReq, piece_picker::PiecePicker,
+            piece_validator::piece_validator, swarm::Swarm,
+        },
+        metainfo::Metainfo,
+        storage::Storage,
+    };
+
+    use super::{get_backoff, get_piece_url, run_web_seed, Source, WebSeed, MAX_BACKOFF};
+
+    const DATA_LEN: usize = 35_000;
+    const PIECE_LEN: usize = 16 * 1024;
+
+    // One request per connection, answered with the status line and body
+    // the handler returns for the target and range header.
+    async fn serve<F>(handler: F) -> SocketAddr
+    where
+        F: Fn(&str, Option<(usize, usize)>) -> (&'static str, Vec<u8>) + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    request.push(stream.read_u8().await.unwrap());
+                }
+                let request = String::from_utf8(request).unwrap();
+                let target = request.split(' ').nth(1).unwrap();
+                let range = (request.lines())
+                    .find_map(|line| line.strip_prefix("range: bytes="))
+                    .map(|range| {
+                        let (from, to) = range.split_once('-').unwrap();
+                        (from.parse().unwrap(), to.parse().unwrap())
+                    });
+                let (status, body) = handler(target, range);
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).await.unwrap();
+                stream.write_all(&body).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    fn get_data() -> Vec<u8> {
+        (0..DATA_LEN).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    fn get_torrent(files: &[(&str, usize)], data: &[u8]) -> Vec<u8> {
+        let mut bytes = b"d8:announce0:4:infod5:filesl".to_vec();
+        for (path, len) in files {
+            bytes.extend(format!("d6:lengthi{}e4:pathl{}:{}ee", len, path.len(), path).as_bytes());
+        }
+        let hashes: Vec<u8> = (data.chunks(PIECE_LEN))
+            .flat_map(|piece| <[u8; 20]>::from(Sha1::digest(piece)))
+            .collect();
+        bytes.extend(b"e4:name6:my dir12:piece length");
+        bytes.extend(format!("i{}e6:pieces{}:", PIECE_LEN, hashes.len()).as_bytes());
+        bytes.extend(hashes);
+        bytes.extend(b"ee");
+        bytes
+    }
+
+    fn get_urls(web_seed: &WebSeed) -> Vec<(&str, u64, u64)> {
+        let Source::Files(files) = &web_seed.source else {
+            panic!("not a web seed");
+        };
+        (files.iter())
+            .map(|file| (file.url.as_str(), file.start, file.length))
+            .collect()
+    }
+
+    // Runs the web seed until every piece passed the validators.
+    async fn download(web_seed: WebSeed, metainfo: &Metainfo<'_>) -> Vec<u8> {
+        let dir = tempdir().unwrap();
+        let storage = Storage::open(dir.path().join("file"), DATA_LEN as u64, PIECE_LEN as u32)
+            .await
+            .unwrap();
+        let pieces = metainfo.get_pieces().unwrap();
+        let no_pieces = pieces.len();
+        let picker = PiecePicker::new(&pieces, 4096);
+        let (senders, receivers): (Vec<_>, Vec<_>) = pieces.iter().map(|_| channel(1)).unzip();
+        let swarm = Swarm::new(
+            picker,
+            BlockHashes::default(),
+            storage,
+            senders,
+            Config::default(),
+            vec![],
+        );
+        let swarm = Arc::new(swarm);
+        let (piece_resp_sender, mut piece_resp_receiver) = unbounded_channel();
+        for (receiver, piece) in receivers.into_iter().zip(pieces) {
+            let sender = piece_resp_sender.clone();
+            spawn(piece_validator(receiver, swarm.clone(), sender, piece));
+        }
+
+        run_web_seed(web_seed, swarm).await;
+        let mut received = vec![vec![]; no_pieces];
+        for _ in 0..no_pieces {
+            let piece = piece_resp_receiver.recv().await.unwrap();
+            received[piece.idx as usize] = piece.bytes;
+        }
+        received.concat()
+    }
+
+    #[test]
+    fn test_urls() {
+        let torrent = get_torrent(&[("a", 1), ("b c", 2)], &[0; 3]);
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        let timeout = Duration::from_secs(1);
+        let web_seed = WebSeed::new("http://host/files/", &metainfo.info, timeout).unwrap();
+        assert_eq!(
+            get_urls(&web_seed),
+            [
+                ("http://host/files/my%20dir/a", 0, 1),
+                ("http://host/files/my%20dir/b%20c", 1, 2)
+            ]
+        );
+
+        let info = b"d6:lengthi3e4:name5:a.iso12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let metainfo = Metainfo::from_info("", info).unwrap();
+        let web_seed = WebSeed::new("http://host/", &metainfo.info, timeout).unwrap();
+        assert_eq!(get_urls(&web_seed), [("http://host/a.iso", 0, 3)]);
+        let web_seed = WebSeed::new("http://host/b.iso", &metainfo.info, timeout).unwrap();
+        assert_eq!(get_urls(&web_seed), [("http://host/b.iso", 0, 3)]);
+        assert!(WebSeed::new("not a url", &metainfo.info, timeout).is_err());
+
+        let url = Url::parse("http://host/seed.php?key=1").unwrap();
+        let url = get_piece_url(&url, &[0xab; 20], BlockReq::new(3, 0, 16), true);
+        let info_hash = "%AB".repeat(20);
+        let want = format!("http://host/seed.php?key=1&info_hash={}&piece=3", info_hash);
+        assert_eq!(url.as_str(), want);
+        let url = Url::parse("http://host/seed").unwrap();
+        let url = get_piece_url(&url, &[0xab; 20], BlockReq::new(3, 4, 8), false);
+        let want = format!(
+            "http://host/seed?info_hash={}&piece=3&ranges=4-11",
+            info_hash
+        );
+        assert_eq!(url.as_str(), want);
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(get_backoff(0), Duration::from_secs(5));
+        assert_eq!(get_backoff(2), Duration::from_secs(20));
+        assert_eq!(get_backoff(100), MAX_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_web_seed() {
+        let data = get_data();
+        let torrent = get_torrent(&[("a", 5_000), ("b", 30_000)], &data);
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        let served = data.clone();
+        let addr = serve(move |target, range| {
+            let (from, to) = range.unwrap();
+            match target {
+                "/my%20dir/a" => ("206 Partial Content", served[from..=to].to_vec()),
+                "/my%20dir/b" => ("206 Partial Content", served[5_000..][from..=to].to_vec()),
+                _ => ("404 Not Found", vec![]),
+            }
+        })
+        .await;
+
+        let url = format!("http://{}/", addr);
+        let web_seed = WebSeed::new(&url, &metainfo.info, Duration::from_secs(5)).unwrap();
+        assert!(download(web_seed, &metainfo).await == data);
+    }
+
+    #[tokio::test]
+    async fn test_http_seed() {
+        let data = get_data();
+        let torrent = get_torrent(&[("a", DATA_LEN)], &data);
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        let info_hash = metainfo.get_info_hash();
+        let escaped: String = info_hash.iter().map(|b| format!("%{:02X}", b)).collect();
+        let served = data.clone();
+        let requests = AtomicUsize::new(0);
+        let addr = serve(move |target, _| {
+            // busy at first
+            if requests.fetch_add(1, Ordering::Relaxed) == 0 {
+                return ("503 Service Unavailable", b"1".to_vec());
+            }
+            let prefix = format!("/seed?info_hash={}&", escaped);
+            assert!(target.starts_with(&prefix));
+            let url = Url::parse(&format!("http://host{}", target)).unwrap();
+            let mut query = url.query_pairs().skip(1);
+            let (_, piece) = query.next().unwrap();
+            let piece: usize = piece.parse().unwrap();
+            let piece = &served[piece * PIECE_LEN..served.len().min((piece + 1) * PIECE_LEN)];
+            match query.next() {
+                Some((_, ranges)) => {
+                    let (from, to) = ranges.split_once('-').unwrap();
+                    let (from, to): (usize, usize) = (from.parse().unwrap(), to.parse().unwrap());
+                    ("200 OK", piece[from..=to].to_vec())
+                }
+                None => ("200 OK", piece.to_vec()),
+            }
+        })
+        .await;
+
+        let url = format!("http://{}/seed", addr);
+        let web_seed = WebSeed::new_http_seed(&url, info_hash, Duration::from_secs(5)).unwrap();
+        let start = Instant::now();
+        assert!(download(web_seed, &metainfo).await == data);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+}
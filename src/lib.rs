@@ -1,18 +1,39 @@
 mod bencoding;
 mod bytes_reader;
 mod cli;
+mod create;
+mod dht;
 mod downloader;
+mod lsd;
+mod magnet;
+mod merkle;
 mod metainfo;
+mod random;
+mod sha256;
+mod storage;
 mod tracker;
+mod utp;
 
-use std::fs::{self, read, write};
+use std::{
+    fs::{self, read, write},
+    net::SocketAddr,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
 use clap::Parser;
 use tokio::runtime::Runtime;
 
 use bencoding::to_json;
-use cli::{Cli, SCommand};
-use downloader::{download, peer::Peer};
+use cli::{Cli, DownloadArgs, SCommand};
+use create::TorrentBuilder;
+use downloader::{
+    config::{Config, Timeouts},
+    download, fetch_metadata,
+    peer::Peer,
+    verify::verify,
+};
+use magnet::Magnet;
 use metainfo::Metainfo;
 use tracker::{get_peers, QueryParams};
 
@@ -21,16 +42,16 @@ pub fn run() {
 
     match cli.s_command {
         SCommand::Decode { bencoded_value } => {
-            println!("{}", to_json(bencoded_value.as_bytes()));
+            println!("{}", to_json(bencoded_value.as_bytes()).unwrap());
         }
         SCommand::Info { torrent_file_path } => {
             let bytes = fs::read(torrent_file_path).unwrap();
-            let metainfo = Metainfo::from_bytes(&bytes);
+            let metainfo = Metainfo::from_bytes(&bytes).unwrap();
             println!("{}", metainfo);
         }
         SCommand::Peers { torrent_file_path } => {
             let bytes = fs::read(torrent_file_path).unwrap();
-            let metainfo = Metainfo::from_bytes(&bytes);
+            let metainfo = Metainfo::from_bytes(&bytes).unwrap();
 
             let query_params = QueryParams {
                 info_hash: &metainfo.get_info_hash(),
@@ -41,7 +62,7 @@ pub fn run() {
                 left: metainfo.info.length,
                 compact: 1,
             };
-            let peers = get_peers(metainfo.announce, query_params);
+            let peers = get_peers(metainfo.announce, query_params).unwrap();
 
             for peer in peers {
                 println!("{}", peer);
@@ -52,16 +73,19 @@ pub fn run() {
             peer_addr,
         } => {
             let bytes = fs::read(torrent_file_path).unwrap();
-            let metainfo = Metainfo::from_bytes(&bytes);
+            let metainfo = Metainfo::from_bytes(&bytes).unwrap();
 
             let rt = Runtime::new().unwrap();
             rt.block_on(async {
                 let my_peer_id = "00112233445566778899";
-                let mut peer = Peer::create(&peer_addr.parse().unwrap()).await;
-                let peer_id = peer
+                let mut peer = Peer::create(&peer_addr.parse().unwrap(), Timeouts::default())
+                    .await
+                    .unwrap();
+                let handshake = peer
                     .do_handshake(&metainfo.get_info_hash(), my_peer_id)
-                    .await;
-                println!("Peer ID: {}", hex::encode(&peer_id))
+                    .await
+                    .unwrap();
+                println!("Peer ID: {}", hex::encode(&handshake.peer_id))
             })
         }
         SCommand::DownloadPiece {
@@ -70,10 +94,12 @@ pub fn run() {
             piece_no,
         } => {
             let bytes = fs::read(torrent_file_path).unwrap();
-            let metainfo = Metainfo::from_bytes(&bytes);
+            let metainfo = Metainfo::from_bytes(&bytes).unwrap();
 
-            let pieces = metainfo.into_pieces();
-            download(&output_file_path, &metainfo, pieces);
+            let pieces = metainfo.get_pieces().unwrap();
+            download(&output_file_path, &metainfo, pieces, &Config::default()).unwrap();
+            // only the piece is kept, so there is nothing to resume
+            let _ = fs::remove_file(format!("{}.resume", output_file_path));
 
             let piece_no: usize = piece_no.parse().unwrap();
             let contents = read(&output_file_path).unwrap();
@@ -84,12 +110,104 @@ pub fn run() {
         SCommand::Download {
             output_file_path,
             torrent_file_path,
+            args,
         } => {
             let bytes = fs::read(torrent_file_path).unwrap();
-            let metainfo = Metainfo::from_bytes(&bytes);
+            let metainfo = Metainfo::from_bytes(&bytes).unwrap();
 
-            let pieces = metainfo.into_pieces();
-            download(&output_file_path, &metainfo, pieces);
+            let config = get_config(args);
+            let pieces = metainfo.get_pieces().unwrap();
+            download(&output_file_path, &metainfo, pieces, &config).unwrap();
         }
+        SCommand::Verify {
+            torrent_file_path,
+            data_path,
+            json,
+        } => {
+            let bytes = fs::read(torrent_file_path).unwrap();
+            let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+
+            let report = verify(&data_path, &metainfo).unwrap();
+            match json {
+                true => println!("{}", report.to_json()),
+                false => print!("{}", report),
+            }
+            if !report.is_ok() {
+                process::exit(1);
+            }
+        }
+        SCommand::Create {
+            output_file_path,
+            path,
+            piece_length,
+            trackers,
+            comment,
+            created_by,
+            no_creation_date,
+            private,
+            source,
+            web_seeds,
+        } => {
+            let mut builder = TorrentBuilder::new(path)
+                .created_by(&created_by)
+                .private(private);
+            if let Some(piece_length) = piece_length {
+                builder = builder.piece_length(piece_length);
+            }
+            for tier in trackers {
+                builder = builder.tracker_tier(tier.split(',').map(str::to_string).collect());
+            }
+            if let Some(comment) = comment {
+                builder = builder.comment(&comment);
+            }
+            if !no_creation_date {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                builder = builder.creation_date(now.as_secs() as i64);
+            }
+            if let Some(source) = source {
+                builder = builder.source(&source);
+            }
+            for url in web_seeds {
+                builder = builder.web_seed(&url);
+            }
+
+            let bytes = builder.build().unwrap();
+            write(&output_file_path, &bytes).unwrap();
+            let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+            println!("Info Hash: {}", hex::encode(metainfo.get_info_hash()));
+        }
+        SCommand::Magnet {
+            output_file_path,
+            magnet_link,
+            args,
+        } => {
+            let magnet = Magnet::parse(&magnet_link).unwrap();
+            let mut config = get_config(args);
+            config.peers.extend(&magnet.peers);
+
+            let info = fetch_metadata(&magnet, &config).unwrap();
+            let announce = magnet.trackers.first().map_or("", String::as_str);
+            let metainfo = Metainfo::from_info(announce, &info).unwrap();
+            let pieces = metainfo.get_pieces().unwrap();
+            download(&output_file_path, &metainfo, pieces, &config).unwrap();
+        }
+    }
+}
+
+fn get_config(args: DownloadArgs) -> Config {
+    let mut config = Config {
+        seed: args.seed,
+        listen_addr: SocketAddr::new(args.bind, args.port),
+        max_peers: args.max_peers,
+        pex: !args.no_pex,
+        dht: args.dht,
+        lsd: args.lsd,
+        utp: args.utp,
+        encryption: args.encryption,
+        ..Default::default()
+    };
+    if !args.dht_nodes.is_empty() {
+        config.dht_nodes = args.dht_nodes;
     }
+    config
 }
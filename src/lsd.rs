@@ -0,0 +1,164 @@
+// Local service discovery (BEP 14): clients announce the torrents they
+// are on to a multicast group, so peers on the same network find each
+// other without a tracker.
+
+use std::{
+    future::pending,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+};
+
+use anyhow::{Context, Result};
+use tokio::net::UdpSocket;
+
+use crate::random::random_u64;
+
+pub const GROUP_V4: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(239, 192, 152, 143), 6771));
+pub const GROUP_V6: SocketAddr = SocketAddr::V6(SocketAddrV6::new(
+    Ipv6Addr::new(0xff15, 0, 0, 0, 0, 0, 0xefc0, 0x988f),
+    6771,
+    0,
+    0,
+));
+
+pub struct Lsd {
+    group: SocketAddr,
+    sender: UdpSocket,
+    // None if another client on this host receives on the group already,
+    // it still hears our announcements
+    receiver: Option<UdpSocket>,
+    // where we accept peers
+    port: u16,
+    // tells our own announcements apart
+    cookie: String,
+}
+
+impl Lsd {
+    pub async fn bind(group: SocketAddr, port: u16) -> Result<Self> {
+        let unspecified = match group {
+            SocketAddr::V4(_) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+            SocketAddr::V6(_) => SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)),
+        };
+        let sender = UdpSocket::bind(unspecified).await?;
+        // bound to the group address, so nothing else arrives
+        let receiver = match UdpSocket::bind(group).await {
+            Ok(receiver) => {
+                match group {
+                    SocketAddr::V4(group) => {
+                        receiver.join_multicast_v4(*group.ip(), Ipv4Addr::UNSPECIFIED)
+                    }
+                    SocketAddr::V6(group) => receiver.join_multicast_v6(group.ip(), 0),
+                }
+                .context("joining the multicast group failed")?;
+                Some(receiver)
+            }
+            Err(_) => None,
+        };
+        Ok(Self {
+            group,
+            sender,
+            receiver,
+            port,
+            cookie: format!("{:016x}", random_u64()),
+        })
+    }
+
+    pub async fn announce(&self, info_hashes: &[[u8; 20]]) -> Result<()> {
+        let host = match self.group {
+            SocketAddr::V4(group) => group.to_string(),
+            SocketAddr::V6(group) => format!("[{}]:{}", group.ip(), group.port()),
+        };
+        let mut msg = format!(
+            "BT-SEARCH * HTTP/1.1\r\nHost: {}\r\nPort: {}\r\n",
+            host, self.port
+        );
+        for info_hash in info_hashes {
+            msg += &format!("Infohash: {}\r\n", hex::encode(info_hash));
+        }
+        msg += &format!("cookie: {}\r\n\r\n\r\n", self.cookie);
+        self.sender.send_to(msg.as_bytes(), self.group).await?;
+        Ok(())
+    }
+
+    // Waits for the next announcement of another client, with the address
+    // it accepts peers on.
+    pub async fn recv(&self) -> Result<(SocketAddr, Vec<[u8; 20]>)> {
+        let Some(receiver) = &self.receiver else {
+            return pending().await;
+        };
+        let mut buf = vec![0; 1500];
+        loop {
+            let (len, from) = receiver.recv_from(&mut buf).await?;
+            if let Some((port, info_hashes, cookie)) = decode(&buf[..len]) {
+                if cookie != Some(&self.cookie) && port != 0 && !info_hashes.is_empty() {
+                    return Ok((SocketAddr::new(from.ip(), port), info_hashes));
+                }
+            }
+        }
+    }
+}
+
+type Announcement<'a> = (u16, Vec<[u8; 20]>, Option<&'a str>);
+
+fn decode(datagram: &[u8]) -> Option<Announcement<'_>> {
+    let msg = std::str::from_utf8(datagram).ok()?;
+    let mut lines = msg.split("\r\n");
+    if lines.next()? != "BT-SEARCH * HTTP/1.1" {
+        return None;
+    }
+    let mut port = None;
+    let mut info_hashes = vec![];
+    let mut cookie = None;
+    for line in lines.take_while(|line| !line.is_empty()) {
+        let (name, value) = line.split_once(':')?;
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "port" => port = Some(value.parse().ok()?),
+            "infohash" => {
+                let info_hash = hex::decode(value).ok()?;
+                info_hashes.push(info_hash.try_into().ok()?);
+            }
+            "cookie" => cookie = Some(value),
+            _ => {}
+        }
+    }
+    Some((port?, info_hashes, cookie))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use crate::random::random_u64;
+
+    use super::{decode, Lsd};
+
+    #[test]
+    fn test_decode() {
+        let msg = b"BT-SEARCH * HTTP/1.1\r\nHost: 239.192.152.143:6771\r\nPort: 6881\r\nInfohash: 0707070707070707070707070707070707070707\r\ninfohash: 0101010101010101010101010101010101010101\r\ncookie: abc\r\n\r\n\r\n";
+        let (port, info_hashes, cookie) = decode(msg).unwrap();
+        assert_eq!(port, 6881);
+        assert_eq!(info_hashes, [[7; 20], [1; 20]]);
+        assert_eq!(cookie, Some("abc"));
+
+        assert!(decode(b"M-SEARCH * HTTP/1.1\r\nPort: 6881\r\n\r\n").is_none());
+        assert!(decode(b"BT-SEARCH * HTTP/1.1\r\nInfohash: 07\r\n\r\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_announce() {
+        // the standard group, on a port of its own
+        let port = 40000 + (random_u64() % 20000) as u16;
+        let group = SocketAddr::from((Ipv4Addr::new(239, 192, 152, 143), port));
+        let a = Lsd::bind(group, 6881).await.unwrap();
+        let b = Lsd::bind(group, 6882).await.unwrap();
+        assert!(a.receiver.is_some() && b.receiver.is_none());
+
+        // a ignores itself
+        a.announce(&[[1; 20]]).await.unwrap();
+        b.announce(&[[7; 20], [8; 20]]).await.unwrap();
+        let (addr, info_hashes) = a.recv().await.unwrap();
+        assert_eq!(addr.port(), 6882);
+        assert_eq!(info_hashes, [[7; 20], [8; 20]]);
+    }
+}
@@ -0,0 +1,146 @@
+use std::net::SocketAddr;
+
+use anyhow::{bail, ensure, Context, Result};
+
+// A magnet link (BEP 9). Only the v1 info hash is supported.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    pub fn parse(uri: &str) -> Result<Self> {
+        let query = uri.strip_prefix("magnet:?").context("not a magnet link")?;
+        let mut info_hash = None;
+        let mut magnet = Self::default();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = percent_decode(value)?;
+            match key {
+                "xt" => {
+                    // other kinds of exact topics may come along, e.g. btmh
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => magnet.name = Some(value),
+                "tr" => magnet.trackers.push(value),
+                "x.pe" => {
+                    let addr = value
+                        .parse()
+                        .with_context(|| format!("invalid peer address: {}", value))?;
+                    magnet.peers.push(addr);
+                }
+                _ => {}
+            }
+        }
+        magnet.info_hash = info_hash.context("magnet link without btih info hash")?;
+        Ok(magnet)
+    }
+}
+
+fn decode_info_hash(hash: &str) -> Result<[u8; 20]> {
+    let bytes = match hash.len() {
+        40 => hex::decode(hash)?,
+        32 => base32_decode(hash)?,
+        _ => bail!("invalid info hash: {}", hash),
+    };
+    Ok(bytes.try_into().unwrap())
+}
+
+fn base32_decode(text: &str) -> Result<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut buffer = 0u64;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => bail!("invalid base32: {}", text),
+        };
+        buffer = buffer << 5 | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+fn percent_decode(text: &str) -> Result<String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((first, tail)) = rest.split_first() {
+        match first {
+            b'%' => {
+                ensure!(tail.len() >= 2, "invalid percent encoding: {}", text);
+                let byte = hex::decode(&tail[..2])
+                    .with_context(|| format!("invalid percent encoding: {}", text))?;
+                bytes.extend(byte);
+                rest = &tail[2..];
+            }
+            b'+' => {
+                bytes.push(b' ');
+                rest = tail;
+            }
+            _ => {
+                bytes.push(*first);
+                rest = tail;
+            }
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Magnet;
+
+    const INFO_HASH: &str = "ad42ce8109f54c99613ce38f9b4d87e70f24a165";
+
+    #[test]
+    fn test_parse() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=magnet1.gif&tr=http%3A%2F%2Fbittorrent-test-tracker.codecrafters.io%2Fannounce&x.pe=127.0.0.1:6881&x.pe=[::1]:6882",
+            INFO_HASH
+        );
+        let magnet = Magnet::parse(&uri).unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+        assert_eq!(magnet.name.as_deref(), Some("magnet1.gif"));
+        assert_eq!(
+            magnet.trackers,
+            ["http://bittorrent-test-tracker.codecrafters.io/announce"]
+        );
+        assert_eq!(
+            magnet.peers,
+            [
+                "127.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_base32() {
+        let magnet = Magnet::parse("magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJILF").unwrap();
+        assert_eq!(hex::encode(magnet.info_hash), INFO_HASH);
+        assert!(magnet.trackers.is_empty());
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        for uri in [
+            "http://example.com",
+            "magnet:?dn=x",
+            "magnet:?xt=urn:btih:1234",
+            "magnet:?xt=urn:btih:VVBM5AIJ6VGJSYJ44OHZWTMH44HSJIL1",
+            "magnet:?xt=urn:btih:ad42ce8109f54c99613ce38f9b4d87e70f24a165&x.pe=nope",
+        ] {
+            assert!(Magnet::parse(uri).is_err(), "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+// Merkle trees of v2 torrents (BEP 52). Each file has one: the leaves are
+// the hashes of its 16 KiB blocks, padded with zeros to a power of two,
+// and the root is the file's `pieces root`. The `piece layers` hold the
+// nodes that cover a piece each.
+
+use crate::{downloader::parts::HashReq, sha256::sha256};
+
+pub const BLOCK_LEN: usize = 16 * 1024;
+
+pub fn hash_pair(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    sha256(&[left.as_slice(), right].concat())
+}
+
+pub fn get_block_hashes(data: &[u8]) -> Vec<[u8; 32]> {
+    data.chunks(BLOCK_LEN).map(sha256).collect()
+}
+
+// The root of a tree `width` leaves wide, a power of two. The leaves the
+// hashes don't fill are `pad`.
+pub fn get_root(hashes: &[[u8; 32]], width: usize, pad: [u8; 32]) -> [u8; 32] {
+    let mut layer = hashes.to_vec();
+    let mut pad = pad;
+    let mut width = width;
+    while width > 1 {
+        layer = (layer.chunks(2))
+            .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pad)))
+            .collect();
+        pad = hash_pair(&pad, &pad);
+        width /= 2;
+    }
+    layer.first().copied().unwrap_or(pad)
+}
+
+// what a piece layer entry is for a piece of data
+pub fn get_piece_root(block_hashes: &[[u8; 32]], piece_length: u32) -> [u8; 32] {
+    get_root(block_hashes, piece_length as usize / BLOCK_LEN, [0; 32])
+}
+
+pub fn get_file_root(block_hashes: &[[u8; 32]]) -> [u8; 32] {
+    get_root(
+        block_hashes,
+        block_hashes.len().next_power_of_two(),
+        [0; 32],
+    )
+}
+
+// The root a piece layer leads to, to check it against the pieces root.
+pub fn get_layer_root(layer: &[[u8; 32]], piece_length: u32) -> [u8; 32] {
+    let pad = get_piece_root(&[], piece_length);
+    get_root(layer, layer.len().next_power_of_two(), pad)
+}
+
+// Whether `hashes`, a run of nodes of a layer `width` wide starting at
+// `index`, lead to `root` with the `uncles` of their subtree, bottom up.
+pub fn verify_hashes(
+    hashes: &[[u8; 32]],
+    index: usize,
+    uncles: &[[u8; 32]],
+    width: usize,
+    root: [u8; 32],
+) -> bool {
+    let length = hashes.len();
+    if !length.is_power_of_two()
+        || !index.is_multiple_of(length)
+        || length.checked_shl(uncles.len() as u32) != Some(width)
+    {
+        return false;
+    }
+    let mut node = get_root(hashes, length, [0; 32]);
+    let mut pos = index / length;
+    for uncle in uncles {
+        node = match pos % 2 {
+            0 => hash_pair(&node, uncle),
+            _ => hash_pair(uncle, &node),
+        };
+        pos /= 2;
+    }
+    node == root
+}
+
+// Checks the blocks of a file as they arrive, against block hashes peers
+// sent with a proof up to the file's pieces root. A bad block then points
+// at the peer that sent it, rather than failing the whole piece.
+pub struct BlockVerifier {
+    root: [u8; 32],
+    leaves: Vec<Option<[u8; 32]>>,
+}
+
+impl BlockVerifier {
+    pub fn new(root: [u8; 32], no_blocks: usize) -> Self {
+        let mut leaves = vec![None; no_blocks];
+        // a tree of one leaf is just that
+        if no_blocks == 1 {
+            leaves[0] = Some(root);
+        }
+        Self { root, leaves }
+    }
+
+    fn get_width(&self) -> usize {
+        self.leaves.len().next_power_of_two()
+    }
+
+    // Asks for the block hashes of an aligned run of `length` blocks that
+    // holds `block_idx`, with all the uncles up to the root.
+    pub fn get_hash_req(&self, block_idx: usize, length: usize) -> HashReq {
+        let length = length.next_power_of_two().min(self.get_width());
+        HashReq {
+            pieces_root: self.root,
+            base_layer: 0,
+            index: (block_idx / length * length) as u32,
+            length: length as u32,
+            proof_layers: (self.get_width() / length).ilog2(),
+        }
+    }
+
+    // Keeps the block hashes of a hashes message if they prove out.
+    pub fn add_hashes(&mut self, req: &HashReq, hashes: &[[u8; 32]]) -> bool {
+        let length = req.length as usize;
+        if req.pieces_root != self.root || req.base_layer != 0 || hashes.len() < length {
+            return false;
+        }
+        let (leaves, uncles) = hashes.split_at(length);
+        let index = req.index as usize;
+        if !verify_hashes(leaves, index, uncles, self.get_width(), self.root) {
+            return false;
+        }
+        for (idx, leaf) in leaves.iter().enumerate() {
+            if let Some(slot) = self.leaves.get_mut(index + idx) {
+                *slot = Some(*leaf);
+            }
+        }
+        true
+    }
+
+    pub fn has_hash(&self, block_idx: usize) -> bool {
+        self.leaves.get(block_idx).is_some_and(Option::is_some)
+    }
+
+    // None until the block's hash is known.
+    pub fn check_block(&self, block_idx: usize, bytes: &[u8]) -> Option<bool> {
+        let leaf = self.leaves.get(block_idx).copied().flatten()?;
+        Some(sha256(bytes) == leaf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{downloader::parts::HashReq, sha256::sha256};
+
+    use super::{
+        get_block_hashes, get_file_root, get_layer_root, get_piece_root, hash_pair, verify_hashes,
+        BlockVerifier, BLOCK_LEN,
+    };
+
+    #[test]
+    fn test_roots() {
+        // three blocks, the last one short
+        let data: Vec<u8> = (0..2 * BLOCK_LEN + 100).map(|idx| idx as u8).collect();
+        let blocks = get_block_hashes(&data);
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[2], sha256(&data[2 * BLOCK_LEN..]));
+
+        let want = hash_pair(
+            &hash_pair(&blocks[0], &blocks[1]),
+            &hash_pair(&blocks[2], &[0; 32]),
+        );
+        assert_eq!(get_file_root(&blocks), want);
+
+        // pieces of two blocks each
+        let piece_length = 2 * BLOCK_LEN as u32;
+        let layer = [
+            get_piece_root(&blocks[..2], piece_length),
+            get_piece_root(&blocks[2..], piece_length),
+        ];
+        assert_eq!(layer[0], hash_pair(&blocks[0], &blocks[1]));
+        assert_eq!(get_layer_root(&layer, piece_length), want);
+
+        // with one block a piece, the layer is the blocks
+        assert_eq!(get_layer_root(&blocks, BLOCK_LEN as u32), want);
+        // a piece of zeros pads it otherwise
+        let pad = hash_pair(&[0; 32], &[0; 32]);
+        assert_eq!(get_piece_root(&[], piece_length), pad);
+        let root = get_layer_root(&layer[..1], piece_length);
+        assert_eq!(root, layer[0]);
+        let root = get_layer_root(&[layer[0], layer[1], layer[0]], piece_length);
+        let want = hash_pair(
+            &hash_pair(&layer[0], &layer[1]),
+            &hash_pair(&layer[0], &pad),
+        );
+        assert_eq!(root, want);
+
+        assert_eq!(get_file_root(&blocks[..1]), blocks[0]);
+    }
+
+    #[test]
+    fn test_block_verifier() {
+        // five blocks, a tree eight wide
+        let data: Vec<u8> = (0..5 * BLOCK_LEN).map(|idx| (idx / 7) as u8).collect();
+        let blocks = get_block_hashes(&data);
+        let root = get_file_root(&blocks);
+        let mut leaves = blocks.clone();
+        leaves.resize(8, [0; 32]);
+        let pairs: Vec<_> = (leaves.chunks(2))
+            .map(|pair| hash_pair(&pair[0], &pair[1]))
+            .collect();
+        let quads = [
+            hash_pair(&pairs[0], &pairs[1]),
+            hash_pair(&pairs[2], &pairs[3]),
+        ];
+
+        let mut verifier = BlockVerifier::new(root, 5);
+        let req = verifier.get_hash_req(5, 2);
+        assert_eq!(
+            req,
+            HashReq {
+                pieces_root: root,
+                base_layer: 0,
+                index: 4,
+                length: 2,
+                proof_layers: 2,
+            }
+        );
+        let block = |idx: usize| &data[idx * BLOCK_LEN..(idx + 1) * BLOCK_LEN];
+        assert_eq!(verifier.check_block(4, block(4)), None);
+
+        // a bad uncle, the wrong order or a short proof don't prove anything
+        let hashes = [leaves[4], leaves[5], pairs[3], quads[0]];
+        assert!(!verifier.add_hashes(&req, &[leaves[4], leaves[5], pairs[2], quads[0]]));
+        assert!(!verifier.add_hashes(&req, &[leaves[4], leaves[5], quads[0], pairs[3]]));
+        assert!(!verifier.add_hashes(&req, &hashes[..3]));
+        assert_eq!(verifier.check_block(4, block(4)), None);
+
+        assert!(verifier.add_hashes(&req, &hashes));
+        assert_eq!(verifier.check_block(4, block(4)), Some(true));
+        assert_eq!(verifier.check_block(4, block(3)), Some(false));
+        assert_eq!(verifier.check_block(0, block(0)), None);
+
+        // the first half, in one go
+        let req = verifier.get_hash_req(1, 4);
+        assert_eq!((req.index, req.proof_layers), (0, 1));
+        assert!(verifier.add_hashes(&req, &[&leaves[..4], &[quads[1]]].concat()));
+        assert_eq!(verifier.check_block(0, block(0)), Some(true));
+        assert!(verifier.has_hash(3) && !verifier.has_hash(6));
+
+        let verifier = BlockVerifier::new(blocks[0], 1);
+        assert_eq!(verifier.check_block(0, block(0)), Some(true));
+
+        // the whole tree, without uncles
+        assert!(verify_hashes(&leaves, 0, &[], 8, root));
+        assert!(!verify_hashes(&leaves[..3], 0, &[], 8, root));
+        assert!(!verify_hashes(
+            &leaves[..2],
+            1,
+            &[pairs[1], quads[1]],
+            8,
+            root
+        ));
+    }
+}
@@ -1,80 +1,390 @@
 use std::{
-    cmp::{max, min},
+    borrow::Cow,
+    cmp::min,
+    collections::HashMap,
     fmt::{self, Display},
 };
 
+use anyhow::{ensure, Context, Result};
 use sha1::{Digest, Sha1};
 
-use crate::{bencoding::Decoder, bytes_reader::BytesReader, downloader::parts::Piece};
+use crate::{
+    bencoding::Decoder,
+    bytes_reader::BytesReader,
+    downloader::parts::{Piece, PieceHash},
+    merkle::{get_layer_root, BLOCK_LEN},
+    sha256::sha256,
+};
+
+pub struct File<'a> {
+    pub length: u64,
+    // below the torrent's directory
+    pub path: Vec<Cow<'a, str>>,
+    // aligns the next file to a piece (BEP 47), zeros that aren't stored
+    pub pad: bool,
+}
+
+// A file of the v2 file tree (BEP 52).
+pub struct TreeFile<'a> {
+    pub path: Vec<Cow<'a, str>>,
+    pub length: u64,
+    // where the data starts, each file on a piece of its own
+    pub start: u64,
+    // the root of its merkle tree, None if empty
+    pub pieces_root: Option<[u8; 32]>,
+}
 
 pub struct Info<'a> {
     pub encoded: &'a [u8],
+    // the file, or the directory of a multi-file torrent
+    pub name: Cow<'a, str>,
+    // None for single-file torrents
+    pub files: Option<Vec<File<'a>>>,
+    // empty unless v2
+    pub file_tree: Vec<TreeFile<'a>>,
+    // 2 for v2 and hybrid torrents
+    pub meta_version: i64,
+    // of all files together, with the padding v2 needs
     pub length: u64,
     pub piece_length: u32,
+    // empty for v2-only torrents
     pub piece_hashes: Vec<[u8; 20]>,
+    // no peers but the tracker's (BEP 27)
+    pub private: bool,
+    // tells apart the same data on different private trackers
+    pub source: Option<Cow<'a, str>>,
 }
 
 impl<'a> Info<'a> {
-    pub fn decode(decoder: &mut Decoder<'a>) -> Self {
-        let start = decoder.start_dict();
+    pub fn decode(decoder: &mut Decoder<'a>) -> Result<Self> {
+        let start = decoder.start_dict()?;
 
-        decoder.find_key("length");
-        let length = decoder.read_integer();
+        let mut file_tree = vec![];
+        if decoder.find_optional_key("file tree")? {
+            decode_file_tree(decoder, &mut vec![], &mut file_tree)?;
+        }
+        let files = match decoder.find_optional_key("files")? {
+            true => Some(decode_files(decoder)?),
+            false => None,
+        };
+        let length = match &files {
+            Some(files) => Some(files.iter().map(|file| file.length).sum()),
+            None => match decoder.find_optional_key("length")? {
+                true => Some(read_length(decoder)?),
+                false => None,
+            },
+        };
+        let meta_version = match decoder.find_optional_key("meta version")? {
+            true => decoder.read_integer()?,
+            false => 1,
+        };
 
-        decoder.find_key("piece length");
-        let piece_length = decoder.read_integer();
+        let name = match decoder.find_optional_key("name")? {
+            true => decoder.read_string_lossy()?,
+            false => "".into(),
+        };
 
-        decoder.find_key("pieces");
-        let pieces = decoder.read_string_bytes();
-        let piece_hashes = pieces
-            .chunks(20)
-            .map(|hash| hash.try_into().unwrap())
-            .collect();
+        decoder.find_key("piece length")?;
+        let piece_length = decoder.read_integer()?;
+        ensure!(
+            piece_length > 0 && piece_length <= u32::MAX as i64,
+            "invalid piece length {}",
+            piece_length
+        );
+        // v2 needs whole blocks and a balanced merkle tree per piece
+        ensure!(
+            meta_version != 2 || (piece_length >= 16 * 1024 && piece_length.count_ones() == 1),
+            "invalid v2 piece length {}",
+            piece_length
+        );
+        let piece_length = piece_length as u64;
+
+        let mut piece_hashes = vec![];
+        if decoder.find_optional_key("pieces")? {
+            let pieces = decoder.read_string_bytes()?;
+            ensure!(
+                pieces.len().is_multiple_of(20),
+                "pieces isn't made of SHA-1 hashes"
+            );
+            piece_hashes = (pieces.chunks(20))
+                .map(|hash| hash.try_into().unwrap())
+                .collect();
+        }
+
+        // files start on a piece boundary, which hybrid torrents pad to
+        let mut end: u64 = 0;
+        for file in &mut file_tree {
+            if file.length > 0 {
+                file.start = end.next_multiple_of(piece_length);
+                end = file.start + file.length;
+            }
+        }
+
+        let private = decoder.find_optional_key("private")? && decoder.read_integer()? == 1;
+        let source = (decoder.find_optional_key("source")?)
+            .then(|| decoder.read_string_lossy())
+            .transpose()?;
+
+        let encoded = decoder.finish_dict(start)?;
 
-        let encoded = decoder.finish_dict(start);
+        let length = length.unwrap_or(end);
+        ensure!(
+            piece_hashes.is_empty() || piece_hashes.len() as u64 == length.div_ceil(piece_length),
+            "{} pieces don't fit a length of {}",
+            piece_hashes.len(),
+            length
+        );
+        // and so no info hash
+        ensure!(
+            !piece_hashes.is_empty() || meta_version == 2,
+            "neither v1 pieces nor v2"
+        );
 
-        Self {
+        Ok(Self {
             encoded,
-            length: length as u64,
+            name,
+            files,
+            file_tree,
+            meta_version,
+            length,
             piece_length: piece_length as u32,
             piece_hashes,
+            private,
+            source,
+        })
+    }
+}
+
+fn read_length(decoder: &mut Decoder) -> Result<u64> {
+    let length = decoder.read_integer()?;
+    u64::try_from(length).with_context(|| format!("invalid length {}", length))
+}
+
+fn decode_files<'a>(decoder: &mut Decoder<'a>) -> Result<Vec<File<'a>>> {
+    let mut files = vec![];
+    decoder.start_list()?;
+    while !decoder.is_end()? {
+        let start = decoder.start_dict()?;
+        let pad =
+            decoder.find_optional_key("attr")? && decoder.read_string_bytes()?.contains(&b'p');
+        decoder.find_key("length")?;
+        let length = read_length(decoder)?;
+        decoder.find_key("path")?;
+        let mut path = vec![];
+        decoder.start_list()?;
+        while !decoder.is_end()? {
+            path.push(decoder.read_string_lossy()?);
         }
+        decoder.finish_list()?;
+        decoder.finish_dict(start)?;
+        files.push(File { length, path, pad });
     }
+    decoder.finish_list()?;
+    Ok(files)
+}
+
+fn decode_file_tree<'a>(
+    decoder: &mut Decoder<'a>,
+    path: &mut Vec<Cow<'a, str>>,
+    files: &mut Vec<TreeFile<'a>>,
+) -> Result<()> {
+    let start = decoder.start_dict()?;
+    while !decoder.is_end()? {
+        let name = decoder.read_string_lossy()?;
+        if !name.is_empty() {
+            path.push(name);
+            decode_file_tree(decoder, path, files)?;
+            path.pop();
+            continue;
+        }
+        // an empty name holds the file itself
+        let start = decoder.start_dict()?;
+        decoder.find_key("length")?;
+        let length = read_length(decoder)?;
+        let pieces_root = match decoder.find_optional_key("pieces root")? {
+            true => Some(
+                (decoder.read_string_bytes()?.try_into())
+                    .context("pieces root isn't a SHA-256 hash")?,
+            ),
+            false => None,
+        };
+        decoder.finish_dict(start)?;
+        files.push(TreeFile {
+            path: path.clone(),
+            length,
+            start: 0,
+            pieces_root,
+        });
+    }
+    decoder.finish_dict(start)?;
+    Ok(())
 }
 
 pub struct Metainfo<'a> {
     pub announce: &'a str,
+    pub comment: Option<Cow<'a, str>>,
+    pub created_by: Option<Cow<'a, str>>,
+    // seconds since the epoch
+    pub creation_date: Option<i64>,
+    // of the strings, usually UTF-8
+    pub encoding: Option<Cow<'a, str>>,
+    // HTTP seeds (BEP 17)
+    pub httpseeds: Vec<&'a str>,
     pub info: Info<'a>,
+    // DHT nodes to bootstrap from (BEP 5)
+    pub nodes: Vec<(&'a str, u16)>,
+    // by pieces root, the hashes of each piece of the larger v2 files
+    pub piece_layers: HashMap<[u8; 32], Vec<[u8; 32]>>,
+    // web seeds (BEP 19)
+    pub url_list: Vec<&'a str>,
 }
 
 impl<'a> Metainfo<'a> {
-    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+    pub fn from_bytes(bytes: &'a [u8]) -> Result<Self> {
         Metainfo::decode(&mut Decoder::new(BytesReader::new(bytes)))
     }
 
-    pub fn decode(decoder: &mut Decoder<'a>) -> Self {
-        let start = decoder.start_dict();
+    // For magnet links, where the info dict comes from peers.
+    pub fn from_info(announce: &'a str, info: &'a [u8]) -> Result<Self> {
+        let info = Info::decode(&mut Decoder::new(BytesReader::new(info)))?;
+        Ok(Self {
+            announce,
+            comment: None,
+            created_by: None,
+            creation_date: None,
+            encoding: None,
+            httpseeds: vec![],
+            info,
+            nodes: vec![],
+            piece_layers: HashMap::new(),
+            url_list: vec![],
+        })
+    }
 
-        decoder.find_key("announce");
-        let announce = decoder.read_string();
+    pub fn decode(decoder: &mut Decoder<'a>) -> Result<Self> {
+        let start = decoder.start_dict()?;
 
-        decoder.find_key("info");
-        let info = Info::decode(decoder);
+        // trackerless torrents have none
+        let announce = match decoder.find_optional_key("announce")? {
+            true => decoder.read_string()?,
+            false => "",
+        };
+        let comment = (decoder.find_optional_key("comment")?)
+            .then(|| decoder.read_string_lossy())
+            .transpose()?;
+        let created_by = (decoder.find_optional_key("created by")?)
+            .then(|| decoder.read_string_lossy())
+            .transpose()?;
+        let creation_date = (decoder.find_optional_key("creation date")?)
+            .then(|| decoder.read_integer())
+            .transpose()?;
+        let encoding = (decoder.find_optional_key("encoding")?)
+            .then(|| decoder.read_string_lossy())
+            .transpose()?;
 
-        decoder.finish_dict(start);
+        let mut httpseeds = vec![];
+        if decoder.find_optional_key("httpseeds")? {
+            decoder.start_list()?;
+            while !decoder.is_end()? {
+                httpseeds.push(decoder.read_string()?);
+            }
+            decoder.finish_list()?;
+        }
+
+        decoder.find_key("info")?;
+        let info = Info::decode(decoder)?;
+
+        let mut nodes = vec![];
+        if decoder.find_optional_key("nodes")? {
+            decoder.start_list()?;
+            while !decoder.is_end()? {
+                decoder.start_list()?;
+                let host = decoder.read_string()?;
+                let port = decoder.read_integer()?;
+                decoder.finish_list()?;
+                nodes.extend(u16::try_from(port).ok().map(|port| (host, port)));
+            }
+            decoder.finish_list()?;
+        }
 
-        Self { announce, info }
+        let mut piece_layers = HashMap::new();
+        if decoder.find_optional_key("piece layers")? {
+            let start = decoder.start_dict()?;
+            while !decoder.is_end()? {
+                let root = (decoder.read_string_bytes()?.try_into())
+                    .context("piece layers key isn't a SHA-256 hash")?;
+                let layer = decoder.read_string_bytes()?;
+                ensure!(
+                    layer.len().is_multiple_of(32),
+                    "piece layer isn't made of SHA-256 hashes"
+                );
+                let layer = (layer.chunks(32))
+                    .map(|hash| hash.try_into().unwrap())
+                    .collect();
+                piece_layers.insert(root, layer);
+            }
+            decoder.finish_dict(start)?;
+        }
+
+        // a single URL or a list of them
+        let mut url_list = vec![];
+        if decoder.find_optional_key("url-list")? {
+            match decoder.is_list()? {
+                true => {
+                    decoder.start_list()?;
+                    while !decoder.is_end()? {
+                        url_list.push(decoder.read_string()?);
+                    }
+                    decoder.finish_list()?;
+                }
+                false => url_list.push(decoder.read_string()?),
+            }
+            url_list.retain(|url| !url.is_empty());
+        }
+
+        decoder.finish_dict(start)?;
+
+        Ok(Self {
+            announce,
+            comment,
+            created_by,
+            creation_date,
+            encoding,
+            httpseeds,
+            info,
+            nodes,
+            piece_layers,
+            url_list,
+        })
     }
 
+    // What the swarm goes by: the v1 hash, or the v2 one cut short for
+    // v2-only torrents.
     pub fn get_info_hash(&self) -> [u8; 20] {
+        match self.get_info_hash_v1() {
+            Some(info_hash) => info_hash,
+            None => self.get_truncated_info_hash().unwrap(),
+        }
+    }
+
+    pub fn get_info_hash_v1(&self) -> Option<[u8; 20]> {
+        if self.info.piece_hashes.is_empty() {
+            return None;
+        }
         let mut hasher = Sha1::new();
         hasher.update(self.info.encoded);
         let hash = hasher.finalize();
-        hash.into()
+        Some(hash.into())
+    }
+
+    pub fn get_info_hash_v2(&self) -> Option<[u8; 32]> {
+        (self.info.meta_version == 2).then(|| sha256(self.info.encoded))
     }
 
-    pub fn get_no_pieces(&self) -> usize {
-        self.info.piece_hashes.len()
+    // how v2 torrents show up where there is only room for 20 bytes
+    pub fn get_truncated_info_hash(&self) -> Option<[u8; 20]> {
+        let info_hash = self.get_info_hash_v2()?;
+        Some(info_hash[..20].try_into().unwrap())
     }
 
     pub fn get_piece_start(&self, piece_idx: u32) -> u64 {
@@ -82,64 +392,160 @@ impl<'a> Metainfo<'a> {
     }
 
     pub fn get_piece_len(&self, piece_idx: u32) -> u32 {
-        min(
-            self.info.piece_length,
-            max(
-                0,
-                (self.info.length - self.get_piece_start(piece_idx)) as u32,
-            ),
-        )
+        let left = self
+            .info
+            .length
+            .saturating_sub(self.get_piece_start(piece_idx));
+        min(self.info.piece_length as u64, left) as u32
     }
 
-    fn get_last_piece_len(&self) -> u32 {
-        let no_pieces = self.get_no_pieces() as u64;
-        let last_piece_len = self.info.length - (no_pieces - 1) * self.info.piece_length as u64;
-        last_piece_len as u32
-    }
-
-    pub fn into_pieces(&self) -> Vec<Piece> {
-        let piece_hashes = &self.info.piece_hashes;
-        let mut pieces: Vec<_> = piece_hashes
-            .iter()
-            .enumerate()
-            .map(|(idx, hash)| Piece::new(idx as u32, self.info.piece_length, *hash))
-            .collect();
-        pieces.last_mut().unwrap().len = self.get_last_piece_len();
-        pieces
+    // From the v1 hashes, or the v2 file tree for v2-only torrents. A v2
+    // file's last piece is short, what is left of it is zeros.
+    pub fn get_pieces(&self) -> Result<Vec<Piece>> {
+        let info = &self.info;
+        if !info.piece_hashes.is_empty() {
+            let pieces = (info.piece_hashes.iter().enumerate())
+                .map(|(idx, hash)| Piece::new(idx as u32, self.get_piece_len(idx as u32), *hash))
+                .collect();
+            return Ok(pieces);
+        }
+        let piece_length = info.piece_length as u64;
+        let mut pieces = vec![];
+        for file in info.file_tree.iter().filter(|file| file.length > 0) {
+            let path = file.path.join("/");
+            let root =
+                (file.pieces_root).with_context(|| format!("{} has no pieces root", path))?;
+            let no_pieces = file.length.div_ceil(piece_length);
+            let hashes = match (no_pieces, self.piece_layers.get(&root)) {
+                // a file of a piece is its own piece layer
+                (1, _) => {
+                    let width = file.length.div_ceil(BLOCK_LEN as u64).next_power_of_two();
+                    vec![PieceHash::V2 {
+                        root,
+                        width: width as usize,
+                    }]
+                }
+                (_, Some(layer)) => {
+                    ensure!(
+                        layer.len() as u64 == no_pieces
+                            && get_layer_root(layer, info.piece_length) == root,
+                        "the piece layer of {} doesn't match its root",
+                        path
+                    );
+                    let width = piece_length as usize / BLOCK_LEN;
+                    (layer.iter())
+                        .map(|&root| PieceHash::V2 { root, width })
+                        .collect()
+                }
+                (_, None) => vec![PieceHash::Blocks; no_pieces as usize],
+            };
+            let first = file.start / piece_length;
+            for (idx, hash) in hashes.into_iter().enumerate() {
+                let len = piece_length.min(file.length - idx as u64 * piece_length);
+                pieces.push(Piece::new_v2((first + idx as u64) as u32, len as u32, hash));
+            }
+        }
+        Ok(pieces)
     }
 }
 
 impl<'a> Display for Metainfo<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Tracker URL: {}", self.announce)?;
-        write!(f, "Length: {}", self.info.length)?;
-        write!(f, "Info Hash: {}", hex::encode(self.get_info_hash()))?;
-        write!(f, "Piece Length: {}", self.info.piece_length)?;
+        writeln!(f, "Tracker URL: {}", self.announce)?;
+        writeln!(f, "Length: {}", self.info.length)?;
+        writeln!(f, "Info Hash: {}", hex::encode(self.get_info_hash()))?;
+        if let Some(info_hash) = self.get_info_hash_v2() {
+            writeln!(f, "Info Hash v2: {}", hex::encode(info_hash))?;
+        }
+        writeln!(f, "Piece Length: {}", self.info.piece_length)?;
+        if !self.info.name.is_empty() {
+            writeln!(f, "Name: {}", self.info.name)?;
+        }
+        if self.info.private {
+            writeln!(f, "Private: yes")?;
+        }
+        if let Some(source) = &self.info.source {
+            writeln!(f, "Source: {}", source)?;
+        }
+        if let Some(comment) = &self.comment {
+            writeln!(f, "Comment: {}", comment)?;
+        }
+        if let Some(created_by) = &self.created_by {
+            writeln!(f, "Created By: {}", created_by)?;
+        }
+        if let Some(creation_date) = self.creation_date {
+            writeln!(f, "Creation Date: {}", format_date(creation_date))?;
+        }
+        if let Some(encoding) = &self.encoding {
+            writeln!(f, "Encoding: {}", encoding)?;
+        }
         write!(f, "Piece Hashes:")?;
         for hash in &self.info.piece_hashes {
-            write!(f, "{}", hex::encode(hash))?;
+            write!(f, "\n{}", hex::encode(hash))?;
         }
         Ok(())
     }
 }
 
+// As UTC, days counted to the civil calendar the usual way.
+fn format_date(secs: i64) -> String {
+    let (days, secs) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
 #[cfg(test)]
 mod tests {
-    use std::fs;
+    use std::{collections::BTreeMap, fs};
+
+    use sha1::{Digest, Sha1};
 
-    use super::Metainfo;
+    use crate::{
+        bencoding::Value,
+        downloader::parts::PieceHash,
+        merkle::{get_block_hashes, get_file_root},
+        sha256::sha256,
+    };
+
+    use super::{format_date, Metainfo};
 
     #[test]
     fn test_metainfo() {
         let metainfo_path = "sample.torrent";
         let bytes = fs::read(metainfo_path).unwrap();
-        let metainfo = Metainfo::from_bytes(&bytes);
+        let metainfo = Metainfo::from_bytes(&bytes).unwrap();
 
         let announce_want = "http://bittorrent-test-tracker.codecrafters.io/announce";
         assert_eq!(metainfo.announce, announce_want);
 
         assert_eq!(metainfo.info.length, 92063);
         assert_eq!(metainfo.info.piece_length, 32768);
+        assert!(!metainfo.info.private);
+        assert!(metainfo.nodes.is_empty());
+        assert!(metainfo.url_list.is_empty());
+        assert!(metainfo.httpseeds.is_empty());
+        assert_eq!(metainfo.info.name, "sample.txt");
+        assert!(metainfo.info.files.is_none());
+        assert_eq!(metainfo.created_by.as_deref(), Some("mktorrent 1.1"));
+        assert_eq!(metainfo.comment, None);
+        assert_eq!(metainfo.info.source, None);
 
         let piece_hashes_want = vec![
             [
@@ -156,5 +562,341 @@ mod tests {
             ],
         ];
         assert_eq!(metainfo.info.piece_hashes, piece_hashes_want);
+
+        let from_info = Metainfo::from_info(metainfo.announce, metainfo.info.encoded).unwrap();
+        assert_eq!(from_info.get_info_hash(), metainfo.get_info_hash());
+        assert_eq!(from_info.info.piece_hashes, piece_hashes_want);
+    }
+
+    #[test]
+    fn test_private() {
+        let info = b"d6:lengthi1e12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1ee";
+        let metainfo = Metainfo::from_info("", info).unwrap();
+        assert!(metainfo.info.private);
+        assert_eq!(metainfo.info.encoded, info);
+
+        // only an integer will do
+        let info = b"d6:lengthi1e12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:private1:1e";
+        assert!(Metainfo::from_info("", info).is_err());
+    }
+
+    #[test]
+    fn test_optional_fields() {
+        let bytes = b"d8:announce0:7:comment2:hi10:created by2:me13:creation datei1700000000e8:encoding5:UTF-84:infod6:lengthi1e4:name1:a12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source1:xee";
+        let metainfo = Metainfo::from_bytes(bytes).unwrap();
+        assert_eq!(metainfo.comment.as_deref(), Some("hi"));
+        assert_eq!(metainfo.created_by.as_deref(), Some("me"));
+        assert_eq!(metainfo.creation_date, Some(1_700_000_000));
+        assert_eq!(metainfo.encoding.as_deref(), Some("UTF-8"));
+        assert!(metainfo.info.private);
+        assert_eq!(metainfo.info.source.as_deref(), Some("x"));
+
+        let shown = metainfo.to_string();
+        let lines: Vec<_> = shown.lines().collect();
+        assert_eq!(lines[0], "Tracker URL: ");
+        assert!(lines.contains(&"Creation Date: 2023-11-14 22:13:20 UTC"));
+        assert!(lines.contains(&"Source: x"));
+        assert_eq!(
+            lines[lines.len() - 2..],
+            ["Piece Hashes:", &hex::encode([b'a'; 20])]
+        );
+    }
+
+    #[test]
+    fn test_format_date() {
+        assert_eq!(format_date(0), "1970-01-01 00:00:00 UTC");
+        assert_eq!(format_date(951_782_400), "2000-02-29 00:00:00 UTC");
+        assert_eq!(format_date(-1), "1969-12-31 23:59:59 UTC");
+    }
+
+    #[test]
+    fn test_v2() {
+        let file = |length: i64, root: Option<[u8; 32]>| {
+            let mut entries = vec![("length", length.into())];
+            entries.extend(root.map(|root| ("pieces root", root.as_slice().into())));
+            Value::dict([("", Value::dict(entries))])
+        };
+        let tree = Value::dict([
+            ("a", file(3, Some([1; 32]))),
+            ("e", file(0, None)),
+            ("sub", Value::dict([("b", file(40_000, Some([2; 32])))])),
+        ]);
+        let mut info = vec![
+            ("file tree", tree),
+            ("meta version", 2.into()),
+            ("name", "dir".into()),
+            ("piece length", 16_384.into()),
+        ];
+        let layers = Value::Dict(BTreeMap::from([(vec![2; 32], vec![3; 96].into())]));
+        let torrent = |info: Vec<(&str, Value)>| {
+            let info = Value::dict(info);
+            let torrent = Value::dict([("info", info.clone()), ("piece layers", layers.clone())]);
+            (info.encode(), torrent.encode())
+        };
+
+        let (encoded, bytes) = torrent(info.clone());
+        let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+        let tree = &metainfo.info.file_tree;
+        let paths: Vec<_> = tree.iter().map(|file| file.path.join("/")).collect();
+        assert_eq!(paths, ["a", "e", "sub/b"]);
+        let starts: Vec<_> = tree.iter().map(|file| file.start).collect();
+        assert_eq!(starts, [0, 0, 16_384]);
+        assert_eq!(tree[1].pieces_root, None);
+        assert_eq!(metainfo.info.length, 56_384);
+        assert_eq!(metainfo.piece_layers[&[2; 32]], [[3; 32]; 3]);
+        assert_eq!(metainfo.get_info_hash_v2(), Some(sha256(&encoded)));
+        assert_eq!(metainfo.get_info_hash_v1(), None);
+        assert_eq!(metainfo.get_info_hash(), sha256(&encoded)[..20]);
+
+        // hybrid, with v1 pieces and a pad file
+        info.push(("pieces", vec![b'a'; 80].into()));
+        let v1_file = |length: i64, path: &str| {
+            Value::dict([
+                ("length", length.into()),
+                ("path", Value::List(vec![path.into()])),
+            ])
+        };
+        let pad = Value::dict([
+            ("attr", "p".into()),
+            ("length", 16_381.into()),
+            ("path", Value::List(vec![".pad".into(), "16381".into()])),
+        ]);
+        let files = [v1_file(3, "a"), pad, v1_file(40_000, "b")];
+        info.push(("files", Value::List(files.to_vec())));
+        let (encoded, bytes) = torrent(info);
+        let metainfo = Metainfo::from_bytes(&bytes).unwrap();
+        assert_eq!(metainfo.info.length, 56_384);
+        let pads: Vec<_> = (metainfo.info.files.as_ref().unwrap().iter())
+            .map(|file| file.pad)
+            .collect();
+        assert_eq!(pads, [false, true, false]);
+        assert_eq!(
+            metainfo.get_info_hash(),
+            <[u8; 20]>::from(Sha1::digest(&encoded))
+        );
+        assert_eq!(metainfo.get_info_hash_v2(), Some(sha256(&encoded)));
+        assert!(metainfo
+            .to_string()
+            .contains(&hex::encode(sha256(&encoded))));
+    }
+
+    #[test]
+    fn test_nodes() {
+        let bytes = b"d8:announce0:4:infod6:lengthi1e12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae5:nodesll9:127.0.0.1i6881eel7:1.2.3.4i80eeee";
+        let metainfo = Metainfo::from_bytes(bytes).unwrap();
+        assert_eq!(metainfo.nodes, [("127.0.0.1", 6881), ("1.2.3.4", 80)]);
+    }
+
+    #[test]
+    fn test_files() {
+        let bytes = b"d8:announce0:4:infod5:filesld6:lengthi3e4:pathl1:aeed6:lengthi4e4:pathl3:sub1:beee4:name3:dir12:piece lengthi4e6:pieces40:aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaae8:url-listl18:http://example.comee";
+        let metainfo = Metainfo::from_bytes(bytes).unwrap();
+        assert_eq!(metainfo.info.name, "dir");
+        assert_eq!(metainfo.info.length, 7);
+        let files = metainfo.info.files.unwrap();
+        assert_eq!(files[0].length, 3);
+        assert_eq!(files[0].path, ["a"]);
+        assert_eq!(files[1].length, 4);
+        assert_eq!(files[1].path, ["sub", "b"]);
+        assert_eq!(metainfo.url_list, ["http://example.com"]);
+
+        let bytes = b"d8:announce0:9:httpseedsl18:http://example.come4:infod6:lengthi1e12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae8:url-list18:http://example.come";
+        let metainfo = Metainfo::from_bytes(bytes).unwrap();
+        assert_eq!(metainfo.url_list, ["http://example.com"]);
+        assert_eq!(metainfo.httpseeds, ["http://example.com"]);
+    }
+
+    #[test]
+    fn test_pieces() {
+        let bytes = b"d8:announce0:4:infod6:lengthi9e12:piece lengthi4e6:pieces60:aaaaaaaaaaaaaaaaaaaabbbbbbbbbbbbbbbbbbbbccccccccccccccccccccee";
+        let metainfo = Metainfo::from_bytes(bytes).unwrap();
+        let lens: Vec<_> = metainfo
+            .get_pieces()
+            .unwrap()
+            .iter()
+            .map(|piece| piece.len)
+            .collect();
+        assert_eq!(lens, [4, 4, 1]);
+
+        // v2-only
+        let file = Value::dict([
+            ("length", 3.into()),
+            ("pieces root", [1; 32].as_slice().into()),
+        ]);
+        let info = |piece_length: i64| {
+            let info = Value::dict([
+                (
+                    "file tree",
+                    Value::dict([("a", Value::dict([("", file.clone())]))]),
+                ),
+                ("meta version", 2.into()),
+                ("name", "a".into()),
+                ("piece length", piece_length.into()),
+            ]);
+            info.encode()
+        };
+        let info_v2 = info(16_384);
+        let metainfo = Metainfo::from_info("", &info_v2).unwrap();
+        assert_eq!(metainfo.get_pieces().unwrap().len(), 1);
+        assert!(Metainfo::from_info("", &info(24_576)).is_err());
+        assert!(Metainfo::from_info("", &info(8_192)).is_err());
+    }
+
+    #[test]
+    fn test_pieces_v2() {
+        // a file of a piece, then one of three
+        let b: Vec<u8> = (0..40_000).map(|idx| (idx % 251) as u8).collect();
+        let layer = get_block_hashes(&b);
+        let root = get_file_root(&layer);
+        let torrent = |layer: Option<Vec<[u8; 32]>>| {
+            let file = |length: usize, root: [u8; 32]| {
+                let file = Value::dict([
+                    ("length", (length as i64).into()),
+                    ("pieces root", root.as_slice().into()),
+                ]);
+                Value::dict([("", file)])
+            };
+            let info = Value::dict([
+                (
+                    "file tree",
+                    Value::dict([("a", file(3, [1; 32])), ("b", file(b.len(), root))]),
+                ),
+                ("meta version", 2.into()),
+                ("name", "dir".into()),
+                ("piece length", 16_384.into()),
+            ]);
+            let mut torrent = vec![("info", info)];
+            if let Some(layer) = layer {
+                let layers = BTreeMap::from([(root.to_vec(), layer.concat().into())]);
+                torrent.push(("piece layers", Value::Dict(layers)));
+            }
+            Value::dict(torrent).encode()
+        };
+        let v2 = |root, width| PieceHash::V2 { root, width };
+
+        let bytes = torrent(Some(layer.clone()));
+        let pieces = Metainfo::from_bytes(&bytes).unwrap().get_pieces().unwrap();
+        let got: Vec<_> = (pieces.iter())
+            .map(|piece| (piece.idx, piece.len, piece.hash))
+            .collect();
+        assert_eq!(
+            got,
+            [
+                (0, 3, v2([1; 32], 1)),
+                (1, 16_384, v2(layer[0], 1)),
+                (2, 16_384, v2(layer[1], 1)),
+                (3, 7_232, v2(layer[2], 1)),
+            ]
+        );
+        assert_eq!(pieces[3].check(&b[32_768..]), Some(true));
+        assert_eq!(pieces[3].check(&b[..7_232]), Some(false));
+
+        // without the layer, the blocks are all there is to go by
+        let bytes = torrent(None);
+        let pieces = Metainfo::from_bytes(&bytes).unwrap().get_pieces().unwrap();
+        assert_eq!(pieces[3].hash, PieceHash::Blocks);
+        assert_eq!(pieces[3].check(&b[32_768..]), None);
+
+        let mut swapped = layer.clone();
+        swapped.swap(0, 1);
+        let bytes = torrent(Some(swapped));
+        assert!(Metainfo::from_bytes(&bytes).unwrap().get_pieces().is_err());
+    }
+
+    #[test]
+    fn test_malformed() {
+        let info = |fields: &[u8]| {
+            let mut info = b"d6:lengthi9e".to_vec();
+            info.extend(fields);
+            info.push(b'e');
+            info
+        };
+        let invalid: [&[u8]; 6] = [
+            b"12:piece lengthi0e6:pieces20:aaaaaaaaaaaaaaaaaaaa",
+            b"12:piece lengthi-4e6:pieces20:aaaaaaaaaaaaaaaaaaaa",
+            // not a whole hash, or not as many as the length needs
+            b"12:piece lengthi16e6:pieces19:aaaaaaaaaaaaaaaaaaa",
+            b"12:piece lengthi4e6:pieces20:aaaaaaaaaaaaaaaaaaaa",
+            // no pieces at all
+            b"12:piece lengthi16e",
+            b"12:piece lengthi16e6:pieces0:",
+        ];
+        for fields in invalid {
+            assert!(Metainfo::from_info("", &info(fields)).is_err());
+        }
+        assert!(Metainfo::from_info("", b"d6:lengthi-1e12:piece lengthi1e6:pieces0:e").is_err());
+
+        let file = Value::dict([
+            ("length", 3.into()),
+            ("pieces root", [1; 31].as_slice().into()),
+        ]);
+        let info = Value::dict([
+            ("file tree", Value::dict([("a", Value::dict([("", file)]))])),
+            ("meta version", 2.into()),
+            ("name", "a".into()),
+            ("piece length", 16_384.into()),
+        ]);
+        assert!(Metainfo::from_info("", &info.encode()).is_err());
+
+        let info = b"d6:lengthi1e12:piece lengthi1e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let layers = |key: &[u8], layer: &[u8]| {
+            let layers = Value::Dict(BTreeMap::from([(key.to_vec(), layer.into())]));
+            let info = Value::decode(info).unwrap();
+            Value::dict([("info", info), ("piece layers", layers)]).encode()
+        };
+        assert!(Metainfo::from_bytes(&layers(&[1; 32], &[2; 64])).is_ok());
+        assert!(Metainfo::from_bytes(&layers(&[1; 31], &[2; 64])).is_err());
+        assert!(Metainfo::from_bytes(&layers(&[1; 32], &[2; 63])).is_err());
+    }
+
+    #[test]
+    fn test_malformed_files() {
+        let torrent = |info: &[&[u8]]| {
+            let mut torrent = b"d8:announce9:localhost4:infod6:lengthi9e".to_vec();
+            torrent.extend(info.concat());
+            torrent.extend(b"ee");
+            torrent
+        };
+        let name: &[u8] = b"4:name1:a";
+        let piece_length: &[u8] = b"12:piece lengthi16e";
+        let pieces: &[u8] = b"6:pieces20:aaaaaaaaaaaaaaaaaaaa";
+        let valid = torrent(&[name, piece_length, pieces]);
+        assert!(Metainfo::from_bytes(&valid).is_ok());
+
+        let malformed = [
+            torrent(&[name, pieces]),
+            torrent(&[name, piece_length, b"6:pieces40:aaaaaaaaaaaaaaaaaaaa"]),
+            torrent(&[name, piece_length, pieces, b"7:private3:yes"]),
+        ];
+        for bytes in malformed {
+            assert!(Metainfo::from_bytes(&bytes).is_err());
+        }
+        // cut short anywhere
+        for len in 0..valid.len() {
+            assert!(Metainfo::from_bytes(&valid[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_non_utf8_name() {
+        // GBK, as some clients still write it
+        let gbk = b"\xc4\xe3\xba\xc3";
+        let file = Value::dict([
+            ("length", 9.into()),
+            ("path", Value::List(vec![gbk.as_slice().into()])),
+        ]);
+        let info = Value::dict([
+            ("files", Value::List(vec![file])),
+            ("name", gbk.as_slice().into()),
+            ("piece length", 16.into()),
+            ("pieces", [0; 20].as_slice().into()),
+        ]);
+        let torrent = Value::dict([("comment", gbk.as_slice().into()), ("info", info)]).encode();
+
+        let metainfo = Metainfo::from_bytes(&torrent).unwrap();
+        let lossy = String::from_utf8_lossy(gbk);
+        assert_eq!(metainfo.info.name, lossy);
+        assert_eq!(metainfo.info.files.unwrap()[0].path, [lossy.as_ref()]);
+        assert_eq!(metainfo.comment, Some(lossy));
     }
 }
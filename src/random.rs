@@ -0,0 +1,97 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Random numbers without another dependency. The standard library keys
+// its hash maps with randomness from the OS, so a counter and the clock
+// hashed with a fresh key can't be guessed and don't repeat.
+pub fn random_u64() -> u64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+    hasher.write_u128(now.map_or(0, |since| since.as_nanos()));
+    hasher.finish()
+}
+
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&random_u64().to_le_bytes()[..chunk.len()]);
+    }
+    bytes
+}
+
+// A quick generator for tie-breaks and the like (SplitMix64), seeded
+// randomly unless a test needs the same sequence every time.
+pub struct Rng {
+    state: u64,
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::seed(random_u64())
+    }
+}
+
+impl Rng {
+    pub fn seed(seed: u64) -> Self {
+        Self { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    // in 0..n, close enough to uniform for n far below 2^64
+    pub fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+
+    #[cfg(test)]
+    pub fn chance(&mut self, probability: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+
+    pub fn choose<'a, T>(&mut self, items: &'a [T]) -> Option<&'a T> {
+        match items.is_empty() {
+            true => None,
+            false => Some(&items[self.below(items.len() as u64) as usize]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{random_bytes, random_u64, Rng};
+
+    #[test]
+    fn test_random() {
+        assert_ne!(random_u64(), random_u64());
+        let bytes: [u8; 20] = random_bytes();
+        assert_ne!(bytes, random_bytes());
+        assert_ne!(bytes[12..], [0; 8]);
+
+        let mut rng = Rng::default();
+        let mut seen = [false; 5];
+        for _ in 0..200 {
+            let n = rng.below(5) as usize;
+            seen[n] = true;
+            assert_eq!(rng.choose(&[n]), Some(&n));
+        }
+        assert_eq!(seen, [true; 5]);
+        assert_eq!(rng.choose::<u8>(&[]), None);
+        assert!(!rng.chance(0.0) && rng.chance(1.0));
+
+        // the same seed, the same numbers
+        let (mut a, mut b) = (Rng::seed(7), Rng::seed(7));
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{ensure, Context, Result};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+pub struct Storage {
+    file: Mutex<File>,
+    length: u64,
+    piece_length: u32,
+}
+
+impl Storage {
+    // Keeps what is already there, so an interrupted download can resume.
+    // The file is only resized if it has to be, since that counts as a
+    // modification and the resume file would no longer hold.
+    pub async fn open(path: impl AsRef<Path>, length: u64, piece_length: u32) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .await?;
+        if file.metadata().await?.len() != length {
+            file.set_len(length).await?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            length,
+            piece_length,
+        })
+    }
+
+    pub fn get_no_pieces(&self) -> usize {
+        self.length.div_ceil(self.piece_length as u64) as usize
+    }
+
+    pub fn get_piece_start(&self, piece_idx: u32) -> u64 {
+        piece_idx as u64 * self.piece_length as u64
+    }
+
+    pub fn get_piece_len(&self, piece_idx: u32) -> u32 {
+        let start = piece_idx as u64 * self.piece_length as u64;
+        self.length
+            .saturating_sub(start)
+            .min(self.piece_length as u64) as u32
+    }
+
+    pub async fn write_piece(&self, piece_idx: u32, bytes: &[u8]) -> Result<()> {
+        ensure!(
+            bytes.len() == self.get_piece_len(piece_idx) as usize,
+            "piece {} has the wrong length",
+            piece_idx
+        );
+        let mut file = self.file.lock().await;
+        let start = piece_idx as u64 * self.piece_length as u64;
+        file.seek(SeekFrom::Start(start)).await?;
+        file.write_all(bytes).await?;
+        // done writing once this returns, which the resume file relies on
+        file.flush().await?;
+        Ok(())
+    }
+
+    pub async fn read_piece(&self, piece_idx: u32) -> Result<Vec<u8>> {
+        self.read_block(piece_idx, 0, self.get_piece_len(piece_idx))
+            .await
+    }
+
+    pub async fn get_modified(&self) -> Result<SystemTime> {
+        let file = self.file.lock().await;
+        Ok(file.metadata().await?.modified()?)
+    }
+
+    pub async fn read_block(&self, piece_idx: u32, begin: u32, len: u32) -> Result<Vec<u8>> {
+        ensure!(
+            begin as u64 + len as u64 <= self.get_piece_len(piece_idx) as u64,
+            "block out of bounds"
+        );
+        let mut file = self.file.lock().await;
+        let start = piece_idx as u64 * self.piece_length as u64 + begin as u64;
+        file.seek(SeekFrom::Start(start)).await?;
+        let mut bytes = vec![0; len as usize];
+        file.read_exact(&mut bytes).await?;
+        Ok(bytes)
+    }
+}
+
+// A file of a torrent, for data laid out file by file the way it is
+// shared, rather than in one piece.
+pub struct DataFile {
+    // None for padding, which reads as zeros
+    pub full_path: Option<PathBuf>,
+    // below the torrent's directory
+    pub path: Vec<String>,
+    pub length: u64,
+}
+
+// Pieces run across file boundaries. A missing or short file fails it.
+pub fn read_range(files: &[DataFile], start: u64, len: u64) -> Result<Vec<u8>> {
+    let end = start + len;
+    let mut bytes = Vec::with_capacity(len as usize);
+    let mut file_start = 0;
+    for file in files {
+        let file_end = file_start + file.length;
+        let (from, to) = (start.max(file_start), end.min(file_end));
+        if from < to {
+            let mut chunk = vec![0; (to - from) as usize];
+            if let Some(full_path) = &file.full_path {
+                let mut reader = fs::File::open(full_path)
+                    .with_context(|| format!("reading {} failed", full_path.display()))?;
+                reader.seek(SeekFrom::Start(from - file_start))?;
+                reader
+                    .read_exact(&mut chunk)
+                    .with_context(|| format!("{} is too short", full_path.display()))?;
+            }
+            bytes.extend(chunk);
+        }
+        file_start = file_end;
+    }
+    ensure!(bytes.len() as u64 == len, "past the end of the data");
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tempfile::tempdir;
+    use tokio::time::sleep;
+
+    use super::{read_range, DataFile, Storage};
+
+    #[tokio::test]
+    async fn test_write_and_read() {
+        let dir = tempdir().unwrap();
+        let storage = Storage::open(dir.path().join("file"), 10, 4).await.unwrap();
+        assert_eq!(storage.get_no_pieces(), 3);
+        assert_eq!(storage.get_piece_len(1), 4);
+        assert_eq!(storage.get_piece_len(2), 2);
+        assert_eq!(storage.get_piece_len(3), 0);
+
+        storage.write_piece(2, &[8, 9]).await.unwrap();
+        storage.write_piece(0, &[0, 1, 2, 3]).await.unwrap();
+        assert!(storage.write_piece(1, &[4, 5]).await.is_err());
+
+        assert_eq!(storage.read_block(0, 1, 3).await.unwrap(), [1, 2, 3]);
+        assert_eq!(storage.read_block(2, 0, 2).await.unwrap(), [8, 9]);
+        assert!(storage.read_block(2, 1, 2).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+        let storage = Storage::open(&path, 10, 4).await.unwrap();
+        storage.write_piece(1, &[4, 5, 6, 7]).await.unwrap();
+        let modified = storage.get_modified().await.unwrap();
+        drop(storage);
+
+        // past the timestamp granularity, reopening leaves it alone
+        sleep(Duration::from_millis(20)).await;
+        let storage = Storage::open(&path, 10, 4).await.unwrap();
+        assert_eq!(storage.get_modified().await.unwrap(), modified);
+        assert_eq!(storage.read_piece(1).await.unwrap(), [4, 5, 6, 7]);
+        assert_eq!(storage.read_piece(2).await.unwrap(), [0, 0]);
+    }
+
+    #[test]
+    fn test_read_range() {
+        let dir = tempdir().unwrap();
+        std::fs::write(dir.path().join("a"), [1, 2, 3]).unwrap();
+        std::fs::write(dir.path().join("b"), [4, 5]).unwrap();
+        let file = |name: Option<&str>, length| DataFile {
+            full_path: name.map(|name| dir.path().join(name)),
+            path: vec![],
+            length,
+        };
+        let files = [file(Some("a"), 3), file(None, 2), file(Some("b"), 2)];
+        assert_eq!(read_range(&files, 1, 5).unwrap(), [2, 3, 0, 0, 4]);
+        assert!(read_range(&files, 6, 2).is_err());
+
+        // missing or short
+        let files = [file(Some("a"), 3), file(Some("c"), 2)];
+        assert_eq!(read_range(&files, 0, 3).unwrap(), [1, 2, 3]);
+        assert!(read_range(&files, 2, 2).is_err());
+        assert!(read_range(&[file(Some("b"), 3)], 0, 3).is_err());
+    }
+}
@@ -3,9 +3,10 @@ use std::{
     str::from_utf8_unchecked,
 };
 
+use anyhow::{bail, Context, Result};
 use reqwest::blocking::{Client, Request};
 
-use crate::{bencoding::Decoder, bytes_reader::BytesReader};
+use crate::bencoding::Value;
 
 pub struct QueryParams<'a> {
     pub info_hash: &'a [u8; 20],
@@ -33,18 +34,19 @@ fn build_request(client: &Client, tracker_url: &str, params: QueryParams) -> Req
         .unwrap()
 }
 
-pub fn get_peers(tracker_url: &str, query_params: QueryParams) -> Vec<SocketAddrV4> {
+pub fn get_peers(tracker_url: &str, query_params: QueryParams) -> Result<Vec<SocketAddrV4>> {
     let client = Client::new();
     let request = build_request(&client, tracker_url, query_params);
-    let bytes = client.execute(request).unwrap().bytes().unwrap();
-    let mut decoder = Decoder::new(BytesReader::new(&bytes));
-    decoder.start_dict();
-    decoder.find_key("peers");
-    decoder
-        .read_string_bytes()
-        .chunks(6)
-        .map(|peer| to_socket_addr(peer))
-        .collect()
+    let bytes = client.execute(request)?.error_for_status()?.bytes()?;
+    let response = Value::decode(&bytes).context("invalid tracker response")?;
+    if let Some(reason) = response.get("failure reason").and_then(Value::as_str) {
+        bail!("tracker failure: {}", reason);
+    }
+    let peers = response
+        .get("peers")
+        .and_then(Value::as_bytes)
+        .context("tracker response without peers")?;
+    Ok(peers.chunks_exact(6).map(to_socket_addr).collect())
 }
 
 fn to_socket_addr(bytes: &[u8]) -> SocketAddrV4 {
@@ -125,7 +127,7 @@ mod tests {
     fn test_get_peers() {
         let metainfo_path = "sample.torrent";
         let bytes = fs::read(metainfo_path).unwrap();
-        let metainfo = Metainfo::from_bytes(&bytes);
+        let metainfo = Metainfo::from_bytes(&bytes).unwrap();
         println!("{}", metainfo.announce);
         println!("{}", hex::encode(metainfo.get_info_hash()));
 
@@ -142,7 +144,7 @@ mod tests {
             compact: 1,
         };
 
-        let peers = get_peers(metainfo.announce, query_params);
-        assert!(peers.len() != 0);
+        let peers = get_peers(metainfo.announce, query_params).unwrap();
+        assert!(!peers.is_empty());
     }
 }
@@ -0,0 +1,124 @@
+use std::{collections::VecDeque, time::Duration};
+
+use tokio::time::Instant;
+
+use super::packet::MAX_PAYLOAD;
+
+// queuing delay we are willing to add to the path, in microseconds
+const TARGET: u32 = 100_000;
+// how much the window may grow per round trip
+const MAX_GROWTH: f64 = 3000.0;
+const MIN_WINDOW: f64 = 2.0 * MAX_PAYLOAD as f64;
+const INIT_WINDOW: f64 = 4.0 * MAX_PAYLOAD as f64;
+const MAX_WINDOW: f64 = (1 << 20) as f64;
+// the base delay is the lowest one of the last minutes, so route changes
+// and clock drift are picked up eventually
+const BASE_HISTORY: usize = 10;
+const BASE_INTERVAL: Duration = Duration::from_secs(60);
+
+// LEDBAT congestion control (RFC 6817): the window grows while the delay
+// our packets see stays close to the lowest one seen, and shrinks once
+// they start queuing up behind other traffic.
+pub struct Ledbat {
+    window: f64,
+    // per minute, the latest last
+    base_delays: VecDeque<u32>,
+    base_started: Instant,
+}
+
+impl Ledbat {
+    pub fn new() -> Self {
+        Self {
+            window: INIT_WINDOW,
+            base_delays: VecDeque::new(),
+            base_started: Instant::now(),
+        }
+    }
+
+    pub fn window(&self) -> usize {
+        self.window as usize
+    }
+
+    // The delay is the one-way delay of our packet as measured by the peer,
+    // which includes the difference of our clocks. Only changes matter, so
+    // delays are compared wrapping around, like sequence numbers.
+    pub fn on_ack(&mut self, bytes_acked: usize, delay: u32) {
+        let queuing = match delay {
+            // the peer had no packet to measure yet
+            0 => 0,
+            _ => {
+                self.add_delay(delay);
+                let base = (self.base_delays.iter().copied())
+                    .reduce(min_delay)
+                    .unwrap_or(delay);
+                delay.wrapping_sub(base).min(i32::MAX as u32)
+            }
+        };
+        let off_target = (TARGET as f64 - queuing as f64) / TARGET as f64;
+        let growth = off_target * MAX_GROWTH * bytes_acked as f64 / self.window;
+        self.window = (self.window + growth).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    pub fn on_loss(&mut self) {
+        self.window = (self.window / 2.0).max(MIN_WINDOW);
+    }
+
+    pub fn on_timeout(&mut self) {
+        self.window = MIN_WINDOW;
+    }
+
+    fn add_delay(&mut self, delay: u32) {
+        let now = Instant::now();
+        match self.base_delays.back_mut() {
+            Some(base) if now < self.base_started + BASE_INTERVAL => {
+                *base = min_delay(*base, delay)
+            }
+            _ => {
+                self.base_delays.push_back(delay);
+                self.base_started = now;
+                if self.base_delays.len() > BASE_HISTORY {
+                    self.base_delays.pop_front();
+                }
+            }
+        }
+    }
+}
+
+fn min_delay(a: u32, b: u32) -> u32 {
+    match (b.wrapping_sub(a) as i32) < 0 {
+        true => b,
+        false => a,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ledbat, INIT_WINDOW, MIN_WINDOW, TARGET};
+
+    #[tokio::test]
+    async fn test_ledbat() {
+        let mut ledbat = Ledbat::new();
+        let base = 1_000_000;
+        ledbat.on_ack(1000, base);
+        let grown = ledbat.window();
+        assert!(grown > INIT_WINDOW as usize);
+
+        // packets queue up beyond the target
+        ledbat.on_ack(1000, base + 2 * TARGET);
+        assert!(ledbat.window() < grown);
+
+        // the clocks are apart by just about the wrap around
+        let mut other = Ledbat::new();
+        other.on_ack(1000, u32::MAX - 10);
+        other.on_ack(1000, 10);
+        other.on_ack(1000, u32::MAX - 10);
+        assert!(other.window() > grown);
+
+        ledbat.on_loss();
+        assert!(ledbat.window() < grown / 2 + 1);
+        ledbat.on_timeout();
+        assert_eq!(ledbat.window(), MIN_WINDOW as usize);
+        ledbat.on_ack(1000, base + 2 * TARGET);
+        assert_eq!(ledbat.window(), MIN_WINDOW as usize);
+    }
+}
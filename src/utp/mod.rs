@@ -0,0 +1,260 @@
+// uTP (BEP 29): reliable, ordered streams over UDP, whose congestion
+// control (LEDBAT) backs off as soon as other traffic makes queues grow.
+//
+// One UDP socket carries all connections. It routes every packet to the
+// task of its connection by the sender and connection id.
+
+mod ledbat;
+mod packet;
+mod stream;
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use tokio::{
+    net::UdpSocket,
+    spawn,
+    sync::{
+        self,
+        mpsc::{self, unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Notify,
+    },
+};
+
+use crate::random::random_u64;
+use packet::{Kind, Packet};
+pub use stream::UtpStream;
+use stream::{drive, Connection};
+
+// incoming connections not accepted yet
+const BACKLOG: usize = 32;
+
+type Connections = HashMap<(SocketAddr, u16), UnboundedSender<Packet>>;
+
+pub struct UtpSocket {
+    socket: Arc<UdpSocket>,
+    connections: Mutex<Connections>,
+    incoming_sender: mpsc::Sender<(UtpStream, SocketAddr)>,
+    incoming: sync::Mutex<mpsc::Receiver<(UtpStream, SocketAddr)>>,
+}
+
+impl UtpSocket {
+    // The socket may be shared, e.g. with the DHT, as long as its packets
+    // are passed to handle.
+    pub fn new(socket: Arc<UdpSocket>) -> Self {
+        let (incoming_sender, incoming) = mpsc::channel(BACKLOG);
+        Self {
+            socket,
+            connections: Mutex::new(HashMap::new()),
+            incoming_sender,
+            incoming: sync::Mutex::new(incoming),
+        }
+    }
+
+    pub async fn connect(self: &Arc<Self>, addr: SocketAddr) -> Result<UtpStream> {
+        let (recv_id, packets) = {
+            let mut connections = self.connections.lock().unwrap();
+            let recv_id = loop {
+                let recv_id = random_u64() as u16;
+                if !connections.contains_key(&(addr, recv_id)) {
+                    break recv_id;
+                }
+            };
+            let (sender, packets) = unbounded_channel();
+            connections.insert((addr, recv_id), sender);
+            (recv_id, packets)
+        };
+        let stream = self.start(addr, recv_id, Connection::connect(recv_id), packets);
+        stream.connected().await.context("utp connect failed")?;
+        Ok(stream)
+    }
+
+    pub async fn accept(&self) -> Result<(UtpStream, SocketAddr)> {
+        let mut incoming = self.incoming.lock().await;
+        incoming.recv().await.context("utp socket closed")
+    }
+
+    fn start(
+        self: &Arc<Self>,
+        addr: SocketAddr,
+        recv_id: u16,
+        conn: Connection,
+        packets: UnboundedReceiver<Packet>,
+    ) -> UtpStream {
+        let conn = Arc::new(Mutex::new(conn));
+        let notify = Arc::new(Notify::new());
+        // sends the SYN, or the answer to it
+        notify.notify_one();
+        let utp = self.clone();
+        let stream = UtpStream::new(conn.clone(), notify.clone());
+        spawn(async move {
+            drive(conn, notify, packets, utp.socket.clone(), addr).await;
+            utp.connections.lock().unwrap().remove(&(addr, recv_id));
+        });
+        stream
+    }
+
+    pub async fn handle(self: &Arc<Self>, datagram: &[u8], from: SocketAddr) {
+        let Ok(packet) = Packet::decode(datagram) else {
+            return;
+        };
+        // a SYN names the id we will send with, we receive on the next one
+        let recv_id = match packet.kind {
+            Kind::Syn => packet.connection_id.wrapping_add(1),
+            _ => packet.connection_id,
+        };
+        let sender = (self.connections.lock().unwrap())
+            .get(&(from, recv_id))
+            .cloned();
+        if let Some(sender) = sender {
+            let _ = sender.send(packet);
+            return;
+        }
+
+        match packet.kind {
+            Kind::Syn => {
+                // nobody accepts, the peer will time out
+                let Ok(permit) = self.incoming_sender.try_reserve() else {
+                    return;
+                };
+                let (sender, packets) = unbounded_channel();
+                (self.connections.lock().unwrap()).insert((from, recv_id), sender);
+                let stream = self.start(from, recv_id, Connection::accept(&packet), packets);
+                permit.send((stream, from));
+            }
+            Kind::Reset => {}
+            _ => {
+                let reset = Packet::new(Kind::Reset, packet.connection_id, 0, packet.seq_nr);
+                let _ = self.socket.send_to(&reset.encode(), from).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+        spawn,
+    };
+
+    use crate::random::Rng;
+
+    use super::UtpSocket;
+
+    async fn bind() -> Arc<UtpSocket> {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let utp = Arc::new(UtpSocket::new(socket.clone()));
+        let handler = utp.clone();
+        spawn(async move {
+            let mut buf = vec![0; 2048];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                handler.handle(&buf[..len], from).await;
+            }
+        });
+        utp
+    }
+
+    // Forwards packets between the first one to send and the target,
+    // dropping some and holding back others until the next one passed.
+    async fn lossy_relay(target: SocketAddr, loss: f64, reorder: f64) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        spawn(async move {
+            let mut rng = Rng::seed(7);
+            let mut buf = vec![0; 2048];
+            let mut client = None;
+            let mut held: Option<(Vec<u8>, SocketAddr)> = None;
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let to = match from == target {
+                    true => client.unwrap(),
+                    false => {
+                        client = Some(from);
+                        target
+                    }
+                };
+                if rng.chance(loss) {
+                    continue;
+                }
+                if held.is_none() && rng.chance(reorder) {
+                    held = Some((buf[..len].to_vec(), to));
+                    continue;
+                }
+                socket.send_to(&buf[..len], to).await.unwrap();
+                if let Some((datagram, to)) = held.take() {
+                    socket.send_to(&datagram, to).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    async fn transfer(loss: f64, reorder: f64) {
+        let (a, b) = (bind().await, bind().await);
+        let b_addr = b.socket.local_addr().unwrap();
+        let relay = lossy_relay(b_addr, loss, reorder).await;
+        let data: Vec<u8> = (0..300_000).map(|idx| (idx % 251) as u8).collect();
+
+        let expected = data.clone();
+        let server = spawn(async move {
+            let (mut stream, _) = b.accept().await.unwrap();
+            let mut received = vec![0; expected.len()];
+            stream.read_exact(&mut received).await.unwrap();
+            assert!(received == expected);
+            stream.write_all(b"thanks").await.unwrap();
+            // the other side closes
+            let mut rest = vec![];
+            stream.read_to_end(&mut rest).await.unwrap();
+            assert!(rest.is_empty());
+        });
+
+        let mut stream = a.connect(relay).await.unwrap();
+        stream.write_all(&data).await.unwrap();
+        let mut reply = [0; 6];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"thanks");
+        stream.shutdown().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transfer() {
+        transfer(0.0, 0.0).await;
+    }
+
+    #[tokio::test]
+    async fn test_transfer_with_loss_and_reordering() {
+        transfer(0.05, 0.1).await;
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused() {
+        let a = bind().await;
+        // a uTP socket nobody accepts on answers data with a reset
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let handler = Arc::new(UtpSocket::new(Arc::new(socket)));
+        spawn(async move {
+            let mut buf = vec![0; 2048];
+            loop {
+                let (len, from) = handler.socket.recv_from(&mut buf).await.unwrap();
+                // pretend the SYN got through but its connection is gone
+                buf[0] &= 0x0f;
+                handler.handle(&buf[..len], from).await;
+            }
+        });
+        let Err(err) = a.connect(addr).await else {
+            panic!("connected");
+        };
+        assert_eq!(format!("{:#}", err), "utp connect failed: connection reset");
+    }
+}
@@ -0,0 +1,150 @@
+use anyhow::{bail, ensure, Context, Result};
+
+const VERSION: u8 = 1;
+const HEADER_LEN: usize = 20;
+const SELECTIVE_ACK: u8 = 1;
+// keeps packets below the usual path MTU
+pub const MAX_PAYLOAD: usize = 1380;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Data = 0,
+    Fin = 1,
+    State = 2,
+    Reset = 3,
+    Syn = 4,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Packet {
+    pub kind: Kind,
+    pub connection_id: u16,
+    // microseconds, on the sender's clock
+    pub timestamp: u32,
+    // the sender's view of our last one-way delay
+    pub timestamp_diff: u32,
+    pub wnd_size: u32,
+    pub seq_nr: u16,
+    pub ack_nr: u16,
+    // bit i stands for ack_nr + 2 + i
+    pub sack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    pub fn new(kind: Kind, connection_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            kind,
+            connection_id,
+            timestamp: 0,
+            timestamp_diff: 0,
+            wnd_size: 0,
+            seq_nr,
+            ack_nr,
+            sack: None,
+            payload: vec![],
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        bytes.push((self.kind as u8) << 4 | VERSION);
+        bytes.push(match self.sack {
+            Some(_) => SELECTIVE_ACK,
+            None => 0,
+        });
+        bytes.extend(self.connection_id.to_be_bytes());
+        bytes.extend(self.timestamp.to_be_bytes());
+        bytes.extend(self.timestamp_diff.to_be_bytes());
+        bytes.extend(self.wnd_size.to_be_bytes());
+        bytes.extend(self.seq_nr.to_be_bytes());
+        bytes.extend(self.ack_nr.to_be_bytes());
+        if let Some(sack) = &self.sack {
+            bytes.extend([0, sack.len() as u8]);
+            bytes.extend(sack);
+        }
+        bytes.extend(&self.payload);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        ensure!(bytes.len() >= HEADER_LEN, "utp packet too short");
+        ensure!(bytes[0] & 0x0f == VERSION, "unknown utp version");
+        let kind = match bytes[0] >> 4 {
+            0 => Kind::Data,
+            1 => Kind::Fin,
+            2 => Kind::State,
+            3 => Kind::Reset,
+            4 => Kind::Syn,
+            _ => bail!("unknown utp packet type"),
+        };
+        let u16_at = |pos: usize| u16::from_be_bytes([bytes[pos], bytes[pos + 1]]);
+        let u32_at = |pos: usize| u32::from_be_bytes(bytes[pos..pos + 4].try_into().unwrap());
+
+        // a chain of extensions, each naming the type of the next one
+        let mut extension = bytes[1];
+        let mut pos = HEADER_LEN;
+        let mut sack = None;
+        while extension != 0 {
+            ensure!(bytes.len() >= pos + 2, "utp extension too short");
+            let len = bytes[pos + 1] as usize;
+            let data = bytes
+                .get(pos + 2..pos + 2 + len)
+                .context("utp extension too short")?;
+            if extension == SELECTIVE_ACK {
+                ensure!(len >= 4 && len.is_multiple_of(4), "invalid selective ack");
+                sack = Some(data.to_vec());
+            }
+            extension = bytes[pos];
+            pos += 2 + len;
+        }
+
+        Ok(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_diff: u32_at(8),
+            wnd_size: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            sack,
+            payload: bytes[pos..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Kind, Packet};
+
+    #[test]
+    fn test_packet() {
+        let mut packet = Packet::new(Kind::State, 0x1234, 7, 5);
+        packet.timestamp = 1;
+        packet.timestamp_diff = 2;
+        packet.wnd_size = 3;
+        packet.sack = Some(vec![0b101, 0, 0, 0]);
+        let bytes = packet.encode();
+        assert_eq!(
+            bytes,
+            [
+                0x21, 1, 0x12, 0x34, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 7, 0, 5, 0, 4, 5, 0, 0,
+                0
+            ]
+        );
+        assert_eq!(Packet::decode(&bytes).unwrap(), packet);
+
+        let mut packet = Packet::new(Kind::Data, 1, 2, 3);
+        packet.payload = b"hello".to_vec();
+        assert_eq!(Packet::decode(&packet.encode()).unwrap(), packet);
+
+        // unknown extensions are skipped
+        let mut bytes = Packet::new(Kind::Fin, 1, 2, 3).encode();
+        bytes[1] = 9;
+        bytes.extend([0, 2, 0xff, 0xff]);
+        assert_eq!(Packet::decode(&bytes).unwrap().payload, b"");
+
+        assert!(Packet::decode(&bytes[..19]).is_err());
+        assert!(Packet::decode(b"d1:ad2:id20:abcdefghij0123456789e1:q4:pinge").is_err());
+    }
+}
@@ -0,0 +1,587 @@
+use std::{
+    cmp::min,
+    collections::{HashMap, VecDeque},
+    future::poll_fn,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::UdpSocket,
+    select,
+    sync::{mpsc::UnboundedReceiver, Notify},
+    time::{sleep_until, Instant},
+};
+
+use crate::random::random_u64;
+
+use super::{
+    ledbat::Ledbat,
+    packet::{Kind, Packet, MAX_PAYLOAD},
+};
+
+const RECV_BUF: usize = 1 << 20;
+const SEND_BUF: usize = 1 << 20;
+const INIT_TIMEOUT: Duration = Duration::from_secs(1);
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+// sends of a packet before the connection is given up on
+const MAX_TRANSMISSIONS: u32 = 6;
+const MAX_IN_FLIGHT: usize = 1024;
+// how far ahead of the next expected packet we buffer
+const MAX_AHEAD: u16 = 1024;
+// in bytes of the selective ack bitmask
+const SACK_LEN: usize = 4;
+// a packet counts as lost once this many later ones got through
+const DUP_ACKS: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+struct Sent {
+    packet: Packet,
+    // None while waiting to be (re)sent
+    sent_at: Option<Instant>,
+    transmissions: u32,
+    // by a selective ack, the cumulative one removes it
+    acked: bool,
+    fast_resent: bool,
+}
+
+impl Sent {
+    fn new(packet: Packet) -> Self {
+        Self {
+            packet,
+            sent_at: None,
+            transmissions: 0,
+            acked: false,
+            fast_resent: false,
+        }
+    }
+}
+
+// The state of one connection, shared between the stream and the task
+// that exchanges its packets.
+pub struct Connection {
+    state: State,
+    error: Option<io::ErrorKind>,
+    send_id: u16,
+    start: Instant,
+    // the next one we send
+    seq_nr: u16,
+    // the last one we received in order
+    ack_nr: u16,
+
+    send_buf: VecDeque<u8>,
+    in_flight: VecDeque<Sent>,
+    fin_queued: bool,
+    fin_sent: bool,
+    fin_acked: bool,
+    // the stream is gone, we only finish sending
+    dropped: bool,
+
+    recv_buf: VecDeque<u8>,
+    out_of_order: HashMap<u16, Vec<u8>>,
+    out_of_order_len: usize,
+    eof_seq: Option<u16>,
+    eof: bool,
+
+    reply_diff: u32,
+    peer_wnd: usize,
+    ledbat: Ledbat,
+    rtt: Option<(Duration, Duration)>,
+    rto: Duration,
+    timeout_at: Option<Instant>,
+    dup_acks: usize,
+    need_ack: bool,
+
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl Connection {
+    fn new(state: State, send_id: u16, seq_nr: u16, ack_nr: u16) -> Self {
+        Self {
+            state,
+            error: None,
+            send_id,
+            start: Instant::now(),
+            seq_nr,
+            ack_nr,
+            send_buf: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            fin_queued: false,
+            fin_sent: false,
+            fin_acked: false,
+            dropped: false,
+            recv_buf: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            out_of_order_len: 0,
+            eof_seq: None,
+            eof: false,
+            reply_diff: 0,
+            peer_wnd: 0,
+            ledbat: Ledbat::new(),
+            rtt: None,
+            rto: INIT_TIMEOUT,
+            timeout_at: None,
+            dup_acks: 0,
+            need_ack: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    // The SYN is sent and retransmitted like any other packet.
+    pub fn connect(recv_id: u16) -> Self {
+        let send_id = recv_id.wrapping_add(1);
+        let mut conn = Self::new(State::SynSent, send_id, 1, 0);
+        let syn = Packet::new(Kind::Syn, recv_id, 1, 0);
+        conn.in_flight.push_back(Sent::new(syn));
+        conn.seq_nr = 2;
+        conn
+    }
+
+    pub fn accept(syn: &Packet) -> Self {
+        let seq_nr = random_u64() as u16;
+        let mut conn = Self::new(State::Connected, syn.connection_id, seq_nr, syn.seq_nr);
+        conn.reply_diff = conn.micros(Instant::now()).wrapping_sub(syn.timestamp);
+        conn.peer_wnd = syn.wnd_size as usize;
+        conn.need_ack = true;
+        conn
+    }
+
+    fn micros(&self, now: Instant) -> u32 {
+        (now - self.start).as_micros() as u32
+    }
+
+    fn close(&mut self, error: Option<io::ErrorKind>) {
+        self.state = State::Closed;
+        self.error = self.error.or(error);
+        self.timeout_at = None;
+    }
+
+    fn is_done(&self) -> bool {
+        self.state == State::Closed || (self.fin_acked && (self.eof || self.dropped))
+    }
+
+    fn wake(&mut self) {
+        for waker in [self.read_waker.take(), self.write_waker.take()] {
+            waker.into_iter().for_each(Waker::wake);
+        }
+    }
+
+    fn get_error(&self) -> io::Error {
+        self.error.unwrap_or(io::ErrorKind::NotConnected).into()
+    }
+
+    pub fn on_packet(&mut self, packet: Packet) {
+        match (self.state, packet.kind) {
+            (State::Closed, _) => return,
+            (_, Kind::Reset) => return self.close(Some(io::ErrorKind::ConnectionReset)),
+            // our answer got lost
+            (_, Kind::Syn) => {
+                self.need_ack = true;
+                return;
+            }
+            (State::SynSent, Kind::State) => {
+                self.state = State::Connected;
+                self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            }
+            (State::SynSent, _) => return,
+            _ => {}
+        }
+        let now = Instant::now();
+        self.reply_diff = self.micros(now).wrapping_sub(packet.timestamp);
+        self.peer_wnd = packet.wnd_size as usize;
+        self.on_ack(&packet, now);
+        match packet.kind {
+            Kind::Data => self.on_data(packet.seq_nr, packet.payload),
+            Kind::Fin => {
+                self.eof_seq = Some(packet.seq_nr);
+                self.on_data(packet.seq_nr, vec![]);
+            }
+            _ => {}
+        }
+    }
+
+    fn on_ack(&mut self, packet: &Packet, now: Instant) {
+        let mut bytes_acked = 0;
+        let mut progress = false;
+        if let Some(first) = self.in_flight.front() {
+            let len = packet
+                .ack_nr
+                .wrapping_sub(first.packet.seq_nr)
+                .wrapping_add(1) as usize;
+            if len <= self.in_flight.len() {
+                let acked: Vec<_> = self.in_flight.drain(..len).collect();
+                for sent in acked {
+                    if !sent.acked {
+                        bytes_acked += sent.packet.payload.len();
+                    }
+                    // only unambiguous round trips count (Karn)
+                    if let (1, Some(sent_at)) = (sent.transmissions, sent.sent_at) {
+                        self.update_rtt(now - sent_at);
+                    }
+                    self.fin_acked |= sent.packet.kind == Kind::Fin;
+                }
+                progress = len > 0;
+            }
+        }
+
+        if let Some(sack) = &packet.sack {
+            for sent in self.in_flight.iter_mut().filter(|sent| !sent.acked) {
+                let bit = (sent.packet.seq_nr)
+                    .wrapping_sub(packet.ack_nr)
+                    .wrapping_sub(2) as usize;
+                if bit < sack.len() * 8 && sack[bit / 8] & (1 << (bit % 8)) != 0 {
+                    sent.acked = true;
+                    bytes_acked += sent.packet.payload.len();
+                }
+            }
+        }
+
+        let mut lost = false;
+        let mut later = 0;
+        for sent in self.in_flight.iter_mut().rev() {
+            if sent.acked {
+                later += 1;
+            } else if later >= DUP_ACKS && !sent.fast_resent && sent.sent_at.is_some() {
+                sent.fast_resent = true;
+                sent.sent_at = None;
+                lost = true;
+            }
+        }
+        // the same ack over and over means the packet after it is lost
+        if progress {
+            self.dup_acks = 0;
+        } else if packet.kind == Kind::State && bytes_acked == 0 {
+            if let Some(first) = self.in_flight.front_mut() {
+                if packet.ack_nr == first.packet.seq_nr.wrapping_sub(1) {
+                    self.dup_acks += 1;
+                    if self.dup_acks >= DUP_ACKS && !first.fast_resent && first.sent_at.is_some() {
+                        first.fast_resent = true;
+                        first.sent_at = None;
+                        lost = true;
+                    }
+                }
+            }
+        }
+
+        if lost {
+            self.ledbat.on_loss();
+        }
+        if bytes_acked > 0 {
+            self.ledbat.on_ack(bytes_acked, packet.timestamp_diff);
+        }
+        if progress {
+            self.timeout_at = None;
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        let (rtt, var) = match self.rtt {
+            None => (sample, sample / 2),
+            Some((rtt, var)) => {
+                let delta = rtt.abs_diff(sample);
+                ((rtt * 7 + sample) / 8, (var * 3 + delta) / 4)
+            }
+        };
+        self.rtt = Some((rtt, var));
+        self.rto = (rtt + var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    fn on_data(&mut self, seq_nr: u16, payload: Vec<u8>) {
+        self.need_ack = true;
+        let ahead = seq_nr.wrapping_sub(self.ack_nr);
+        if ahead == 0 || ahead > MAX_AHEAD || self.out_of_order.contains_key(&seq_nr) {
+            return;
+        }
+        if self.recv_buf.len() + self.out_of_order_len + payload.len() > RECV_BUF {
+            return;
+        }
+        self.out_of_order_len += payload.len();
+        self.out_of_order.insert(seq_nr, payload);
+        while let Some(payload) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = self.ack_nr.wrapping_add(1);
+            self.out_of_order_len -= payload.len();
+            self.recv_buf.extend(payload);
+        }
+        if self.eof_seq == Some(self.ack_nr) {
+            self.eof = true;
+        }
+    }
+
+    fn get_sack(&self) -> Option<Vec<u8>> {
+        if self.out_of_order.is_empty() {
+            return None;
+        }
+        let mut sack = vec![0; SACK_LEN];
+        for bit in 0..SACK_LEN * 8 {
+            let seq_nr = self.ack_nr.wrapping_add(2 + bit as u16);
+            if self.out_of_order.contains_key(&seq_nr) {
+                sack[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        Some(sack)
+    }
+
+    pub fn on_timeout(&mut self) {
+        let now = Instant::now();
+        if self.timeout_at.is_none_or(|timeout_at| now < timeout_at) {
+            return;
+        }
+        self.timeout_at = None;
+        let Some(first) = self.in_flight.iter().find(|sent| !sent.acked) else {
+            return;
+        };
+        if first.transmissions >= MAX_TRANSMISSIONS {
+            return self.close(Some(io::ErrorKind::TimedOut));
+        }
+        self.ledbat.on_timeout();
+        self.rto = (self.rto * 2).min(MAX_TIMEOUT);
+        for sent in self.in_flight.iter_mut().filter(|sent| !sent.acked) {
+            sent.sent_at = None;
+        }
+    }
+
+    // The packets to send now: retransmissions first, then new data as far
+    // as the window allows, then an ack if nothing else carries it.
+    pub fn flush(&mut self) -> Vec<Packet> {
+        let mut packets = vec![];
+        if self.state == State::Closed {
+            return packets;
+        }
+        let now = Instant::now();
+        let (timestamp, timestamp_diff, ack_nr) = (self.micros(now), self.reply_diff, self.ack_nr);
+        let wnd_size = (RECV_BUF - self.recv_buf.len() - self.out_of_order_len) as u32;
+        let stamp = |mut packet: Packet| {
+            packet.timestamp = timestamp;
+            packet.timestamp_diff = timestamp_diff;
+            packet.wnd_size = wnd_size;
+            packet.ack_nr = ack_nr;
+            packet
+        };
+
+        // a closed window still lets one packet through, to probe it
+        let window = self.ledbat.window().min(self.peer_wnd);
+        let mut flight: usize = (self.in_flight.iter())
+            .filter(|sent| sent.sent_at.is_some() && !sent.acked)
+            .map(|sent| sent.packet.payload.len())
+            .sum();
+        let fits = |flight: usize, len: usize| flight == 0 || flight + len <= window;
+
+        for sent in self.in_flight.iter_mut() {
+            if sent.acked || sent.sent_at.is_some() {
+                continue;
+            }
+            let len = sent.packet.payload.len();
+            if !fits(flight, len) {
+                break;
+            }
+            flight += len;
+            sent.sent_at = Some(now);
+            sent.transmissions += 1;
+            packets.push(stamp(sent.packet.clone()));
+        }
+
+        while self.state == State::Connected && !self.fin_sent {
+            let len = min(self.send_buf.len(), MAX_PAYLOAD);
+            let fin = len == 0 && self.fin_queued;
+            if (len == 0 && !fin) || !fits(flight, len) || self.in_flight.len() >= MAX_IN_FLIGHT {
+                break;
+            }
+            let kind = if fin { Kind::Fin } else { Kind::Data };
+            let mut packet = Packet::new(kind, self.send_id, self.seq_nr, ack_nr);
+            packet.payload = self.send_buf.drain(..len).collect();
+            self.seq_nr = self.seq_nr.wrapping_add(1);
+            self.fin_sent = fin;
+            flight += len;
+            packets.push(stamp(packet.clone()));
+            self.in_flight.push_back(Sent {
+                sent_at: Some(now),
+                transmissions: 1,
+                ..Sent::new(packet)
+            });
+        }
+
+        let sack = self.get_sack();
+        if self.need_ack && (packets.is_empty() || sack.is_some()) {
+            let mut ack = Packet::new(Kind::State, self.send_id, self.seq_nr, ack_nr);
+            ack.sack = sack;
+            packets.push(stamp(ack));
+        }
+        self.need_ack = false;
+
+        if self.in_flight.iter().all(|sent| sent.acked) {
+            self.timeout_at = None;
+        } else if self.timeout_at.is_none() {
+            self.timeout_at = Some(now + self.rto);
+        }
+        packets
+    }
+}
+
+// Runs the connection until it is closed: feeds it the packets the socket
+// routes to it and sends whatever it has to send.
+pub async fn drive(
+    conn: Arc<Mutex<Connection>>,
+    notify: Arc<Notify>,
+    mut packets: UnboundedReceiver<Packet>,
+    socket: Arc<UdpSocket>,
+    addr: SocketAddr,
+) {
+    loop {
+        let timeout_at = conn.lock().unwrap().timeout_at;
+        select! {
+            packet = packets.recv() => {
+                let mut conn = conn.lock().unwrap();
+                match packet {
+                    Some(packet) => conn.on_packet(packet),
+                    None => conn.close(Some(io::ErrorKind::ConnectionAborted)),
+                }
+            }
+            _ = notify.notified() => {}
+            _ = sleep_until(timeout_at.unwrap_or_else(Instant::now)), if timeout_at.is_some() => {
+                conn.lock().unwrap().on_timeout();
+            }
+        }
+
+        let (outgoing, done) = {
+            let mut conn = conn.lock().unwrap();
+            let outgoing = conn.flush();
+            let done = conn.is_done();
+            if done {
+                conn.close(None);
+            }
+            conn.wake();
+            (outgoing, done)
+        };
+        for packet in outgoing {
+            let _ = socket.send_to(&packet.encode(), addr).await;
+        }
+        if done {
+            break;
+        }
+    }
+}
+
+// A uTP connection, read and written like a TCP stream.
+pub struct UtpStream {
+    conn: Arc<Mutex<Connection>>,
+    notify: Arc<Notify>,
+}
+
+impl UtpStream {
+    pub fn new(conn: Arc<Mutex<Connection>>, notify: Arc<Notify>) -> Self {
+        Self { conn, notify }
+    }
+
+    pub async fn connected(&self) -> io::Result<()> {
+        poll_fn(|cx| {
+            let mut conn = self.conn.lock().unwrap();
+            match conn.state {
+                State::SynSent => {
+                    conn.write_waker = Some(cx.waker().clone());
+                    Poll::Pending
+                }
+                State::Connected => Poll::Ready(Ok(())),
+                State::Closed => Poll::Ready(Err(conn.get_error())),
+            }
+        })
+        .await
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut conn = self.conn.lock().unwrap();
+        if !conn.recv_buf.is_empty() {
+            let buffered = conn.recv_buf.len();
+            let len = min(buf.remaining(), buffered);
+            let bytes: Vec<_> = conn.recv_buf.drain(..len).collect();
+            buf.put_slice(&bytes);
+            // the peer may be waiting for the window to open again
+            if buffered >= RECV_BUF / 2 {
+                conn.need_ack = true;
+                self.notify.notify_one();
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if conn.error.is_some() {
+            return Poll::Ready(Err(conn.get_error()));
+        }
+        if conn.eof || conn.state == State::Closed {
+            return Poll::Ready(Ok(()));
+        }
+        conn.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.error.is_some() {
+            return Poll::Ready(Err(conn.get_error()));
+        }
+        if conn.state == State::Closed || conn.fin_queued {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        let len = min(SEND_BUF - conn.send_buf.len(), buf.len());
+        if len == 0 {
+            conn.write_waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        conn.send_buf.extend(&buf[..len]);
+        self.notify.notify_one();
+        Poll::Ready(Ok(len))
+    }
+
+    // Written data is sent as soon as the window allows anyway.
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut conn = self.conn.lock().unwrap();
+        if conn.error.is_some() {
+            return Poll::Ready(Err(conn.get_error()));
+        }
+        if conn.fin_acked || conn.state == State::Closed {
+            return Poll::Ready(Ok(()));
+        }
+        if !conn.fin_queued {
+            conn.fin_queued = true;
+            self.notify.notify_one();
+        }
+        conn.write_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        let mut conn = self.conn.lock().unwrap();
+        conn.dropped = true;
+        conn.fin_queued = true;
+        self.notify.notify_one();
+    }
+}